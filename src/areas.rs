@@ -0,0 +1,142 @@
+use bsp_reader::BspMap;
+use collision::CONTENTS_AREAPORTAL;
+
+/// Areas are the parts of the map that areaportal brushes seal off from each other, usually at
+/// doors. The game opens a portal while its door is open; here portals are only opened and
+/// closed by hand.
+pub struct Areas {
+    /// Area of every cluster, -1 for clusters without leafs.
+    cluster_areas: Vec<i32>,
+    /// The two areas of every areaportal and whether it's open.
+    portals: Vec<(i32, i32, bool)>,
+    /// Areas with the same number are connected through open portals.
+    flood: Vec<usize>,
+}
+
+impl Areas {
+    /// Finds the areas on both sides of every areaportal brush. The brush bounds aren't known,
+    /// so like the game does for the door entity, every area whose leafs touch the leafs of the
+    /// brush counts, and the first two are connected. Portals start closed like doors do.
+    pub fn new(map: &BspMap) -> Areas {
+        let area_count = map.leafs.iter().map(|l| l.area + 1).max().unwrap_or(0).max(0) as usize;
+        let cluster_count = map.leafs.iter().map(|l| l.cluster + 1).max().unwrap_or(0).max(0);
+        let mut cluster_areas = vec![-1; cluster_count as usize];
+        for leaf in &map.leafs {
+            if leaf.cluster >= 0 && leaf.area >= 0 {
+                cluster_areas[leaf.cluster as usize] = leaf.area;
+            }
+        }
+
+        let mut portals = Vec::new();
+        for (b, brush) in map.brushes.iter().enumerate() {
+            let contents = map.textures.get(brush.texture as usize).map_or(0, |t| t.contents);
+            if contents & CONTENTS_AREAPORTAL == 0 {
+                continue;
+            }
+
+            let mut bounds: Option<([i32; 3], [i32; 3])> = None;
+            for leaf in &map.leafs {
+                let brushes = &map.leafbrushes[leaf.leafbrush as usize..
+                                               (leaf.leafbrush + leaf.n_leafbrushes) as usize];
+                if !brushes.contains(&(b as i32)) {
+                    continue;
+                }
+                bounds = Some(match bounds {
+                    Some((mins, maxs)) => {
+                        ([mins[0].min(leaf.mins[0]),
+                          mins[1].min(leaf.mins[1]),
+                          mins[2].min(leaf.mins[2])],
+                         [maxs[0].max(leaf.maxs[0]),
+                          maxs[1].max(leaf.maxs[1]),
+                          maxs[2].max(leaf.maxs[2])])
+                    }
+                    None => (leaf.mins, leaf.maxs),
+                });
+            }
+            let (mins, maxs) = match bounds {
+                Some(bounds) => bounds,
+                None => continue,
+            };
+
+            let mut areas: Vec<i32> = map.leafs
+                                         .iter()
+                                         .filter(|l| l.area >= 0)
+                                         .filter(|l| {
+                                             (0..3).all(|a| {
+                                                 l.mins[a] <= maxs[a] + 1 &&
+                                                 l.maxs[a] >= mins[a] - 1
+                                             })
+                                         })
+                                         .map(|l| l.area)
+                                         .collect();
+            areas.sort();
+            areas.dedup();
+            if areas.len() >= 2 {
+                portals.push((areas[0], areas[1], false));
+            }
+        }
+
+        let mut areas = Areas {
+            cluster_areas: cluster_areas,
+            portals: portals,
+            flood: (0..area_count).collect(),
+        };
+        areas.flood_connections();
+        areas
+    }
+
+    pub fn area_count(&self) -> usize {
+        self.flood.len()
+    }
+
+    pub fn portal_count(&self) -> usize {
+        self.portals.len()
+    }
+
+    pub fn set_all_open(&mut self, open: bool) {
+        for portal in &mut self.portals {
+            portal.2 = open;
+        }
+        self.flood_connections();
+    }
+
+    /// Areas outside of the map (-1) are connected to everything.
+    pub fn are_areas_connected(&self, a: i32, b: i32) -> bool {
+        if a < 0 || b < 0 || a as usize >= self.flood.len() || b as usize >= self.flood.len() {
+            return true;
+        }
+        self.flood[a as usize] == self.flood[b as usize]
+    }
+
+    /// Whether `cluster` can be seen from `area` through the open portals.
+    pub fn is_cluster_visible(&self, area: i32, cluster: i32) -> bool {
+        cluster < 0 ||
+        self.are_areas_connected(area,
+                                 self.cluster_areas.get(cluster as usize).cloned().unwrap_or(-1))
+    }
+
+    fn flood_connections(&mut self) {
+        for (i, flood) in self.flood.iter_mut().enumerate() {
+            *flood = i;
+        }
+        // Merges until nothing changes, there are only a handful of areas
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &(a, b, open) in &self.portals {
+                let (a, b) = (a as usize, b as usize);
+                if !open || self.flood[a] == self.flood[b] {
+                    continue;
+                }
+                let from = self.flood[a].max(self.flood[b]);
+                let to = self.flood[a].min(self.flood[b]);
+                for flood in &mut self.flood {
+                    if *flood == from {
+                        *flood = to;
+                    }
+                }
+                changed = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::f32::consts::PI;
+use bsp_reader::BspMap;
+use entities;
+
+/// Frames rendered in each pass, the first frame of a pass isn't measured.
+const FRAMES: usize = 600;
+
+#[derive(Default)]
+struct PassTotals {
+    frames: usize,
+    seconds: f32,
+    draw_calls: usize,
+    triangles: usize,
+    overdraw: f32,
+}
+
+/// The feature a benchmark turns on for its first pass and off for the second.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Comparison {
+    Culling,
+    /// Drawing near batches first.
+    Sorting,
+}
+
+impl Comparison {
+    /// Capitalized and lowercase name for the report.
+    fn names(self) -> (&'static str, &'static str) {
+        match self {
+            Comparison::Culling => ("Culling", "culling"),
+            Comparison::Sorting => ("Sorting", "sorting"),
+        }
+    }
+}
+
+/// Flies the same loop through the map twice, first with the compared feature on and then off,
+/// and compares the frame times, draw calls and overdraw of both passes. The loop goes through
+/// the spawn points, or circles the center of the map if there are fewer than two.
+pub struct Benchmark {
+    comparison: Comparison,
+    /// Quake 3 positions the camera passes through, the last one leads back to the first.
+    points: Vec<[f32; 3]>,
+    frame: usize,
+    passes: [PassTotals; 2],
+}
+
+impl Benchmark {
+    pub fn new(map: &BspMap, comparison: Comparison) -> Benchmark {
+        let mut points: Vec<[f32; 3]> = entities::parse(&map.entities)
+                                            .iter()
+                                            .filter(|e| e.classname().starts_with("info_player_"))
+                                            .filter_map(|e| e.get_vec3("origin"))
+                                            .collect();
+        if points.len() < 2 {
+            let (mins, maxs) = map.bounds();
+            let center = map.center();
+            let radius = (maxs[0] - mins[0]).min(maxs[1] - mins[1]) / 4.0;
+            points = (0..16)
+                         .map(|i| {
+                             let angle = i as f32 / 16.0 * 2.0 * PI;
+                             [center[0] + angle.cos() * radius,
+                              center[1] + angle.sin() * radius,
+                              center[2]]
+                         })
+                         .collect();
+        }
+
+        Benchmark {
+            comparison: comparison,
+            points: points,
+            frame: 0,
+            passes: [PassTotals::default(), PassTotals::default()],
+        }
+    }
+
+    /// Whether `comparison` is on for the current frame. Everything else is on either way.
+    pub fn enabled(&self, comparison: Comparison) -> bool {
+        comparison != self.comparison || self.frame < FRAMES
+    }
+
+    /// Camera position and horizontal view direction in Quake 3 space for the current frame.
+    /// Both passes see exactly the same views.
+    pub fn camera(&self) -> ([f32; 3], [f32; 3]) {
+        let progress = (self.frame % FRAMES) as f32 / FRAMES as f32 * self.points.len() as f32;
+        let segment = progress.floor() as usize % self.points.len();
+        let t = progress - progress.floor();
+        let from = self.points[segment];
+        let to = self.points[(segment + 1) % self.points.len()];
+        let position = [from[0] + (to[0] - from[0]) * t,
+                        from[1] + (to[1] - from[1]) * t,
+                        from[2] + (to[2] - from[2]) * t];
+        (position, [to[0] - from[0], to[1] - from[1], 0.0])
+    }
+
+    /// Adds the measurements of the frame drawn at `camera` and moves on to the next. Overdraw
+    /// is the number of fragments that passed the depth test per pixel. Returns true once both
+    /// passes are done.
+    pub fn record(&mut self, dt: f32, draw_calls: usize, triangles: usize, overdraw: f32) -> bool {
+        if self.frame % FRAMES != 0 {
+            let pass = &mut self.passes[self.frame / FRAMES];
+            pass.frames += 1;
+            pass.seconds += dt;
+            pass.draw_calls += draw_calls;
+            pass.triangles += triangles;
+            pass.overdraw += overdraw;
+        }
+        self.frame += 1;
+        self.frame >= 2 * FRAMES
+    }
+
+    pub fn print_report(&self) {
+        let averages: Vec<(f32, f32, f32, f32)> =
+            self.passes
+                .iter()
+                .map(|p| {
+                    let frames = p.frames.max(1) as f32;
+                    (p.seconds / frames * 1000.0,
+                     p.draw_calls as f32 / frames,
+                     p.triangles as f32 / frames,
+                     p.overdraw / frames)
+                })
+                .collect();
+        let (title, name) = self.comparison.names();
+        println!("{} benchmark, {} frames per pass:", title, FRAMES - 1);
+        println!("             frame ms  draw calls   triangles  overdraw");
+        let names = [format!("{} on", name), format!("{} off", name)];
+        for (name, average) in names.iter().zip(&averages) {
+            println!("{:<12} {:>8.2}  {:>10.1}  {:>10.0}  {:>8.2}",
+                     name,
+                     average.0,
+                     average.1,
+                     average.2,
+                     average.3);
+        }
+        println!("difference   {:>+8.2}  {:>+10.1}  {:>+10.0}  {:>+8.2}",
+                 averages[1].0 - averages[0].0,
+                 averages[1].1 - averages[0].1,
+                 averages[1].2 - averages[0].2,
+                 averages[1].3 - averages[0].3);
+    }
+}
@@ -1,8 +1,139 @@
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
+use std::io;
 use std::io::Read;
-use std::io::Cursor;
 use std::str;
-use byteorder::{LittleEndian, ReadBytesExt};
+use std::str::Utf8Error;
+use byteorder::{ByteOrder, LittleEndian};
+
+pub const HEADER_SIZE: usize = 4 + 4 + 17 * 2 * 4;
+
+/// Lump names in the order of the directory.
+pub const LUMP_NAMES: [&'static str; 17] = ["entities", "textures", "planes", "nodes", "leafs",
+                                            "leaffaces", "leafbrushes", "models", "brushes",
+                                            "brushsides", "vertexes", "meshverts", "effects",
+                                            "faces", "lightmaps", "lightvols", "visdata"];
+
+// Size in bytes of one entry of the lumps that are lists of fixed size entries
+pub const TEXTURE_SIZE: i32 = 64 + 4 + 4;
+pub const PLANE_SIZE: i32 = 3 * 4 + 4;
+pub const NODE_SIZE: i32 = 4 + 2 * 4 + 3 * 4 + 3 * 4;
+pub const LEAF_SIZE: i32 = 12 * 4;
+pub const LEAFFACE_SIZE: i32 = 4;
+pub const LEAFBRUSH_SIZE: i32 = 4;
+pub const MODEL_SIZE: i32 = 10 * 4;
+pub const BRUSH_SIZE: i32 = 3 * 4;
+pub const BRUSHSIDE_SIZE: i32 = 2 * 4;
+/// Position, two texcoords and normal as floats, then the color as four bytes.
+pub const VERTEX_SIZE: i32 = 10 * 4 + 4;
+pub const MESHVERT_SIZE: i32 = 4;
+pub const EFFECT_SIZE: i32 = 64 + 2 * 4;
+pub const FACE_SIZE: i32 = 26 * 4;
+pub const LIGHTMAP_SIZE: i32 = 128 * 128 * 3;
+pub const LIGHTVOL_SIZE: i32 = 3 + 3 + 2;
+
+/// Entry sizes in the order of `LUMP_NAMES`. Entities and visdata aren't lists of entries.
+pub const LUMP_ENTRY_SIZES: [Option<i32>; 17] = [None,
+                                                 Some(TEXTURE_SIZE),
+                                                 Some(PLANE_SIZE),
+                                                 Some(NODE_SIZE),
+                                                 Some(LEAF_SIZE),
+                                                 Some(LEAFFACE_SIZE),
+                                                 Some(LEAFBRUSH_SIZE),
+                                                 Some(MODEL_SIZE),
+                                                 Some(BRUSH_SIZE),
+                                                 Some(BRUSHSIDE_SIZE),
+                                                 Some(VERTEX_SIZE),
+                                                 Some(MESHVERT_SIZE),
+                                                 Some(EFFECT_SIZE),
+                                                 Some(FACE_SIZE),
+                                                 Some(LIGHTMAP_SIZE),
+                                                 Some(LIGHTVOL_SIZE),
+                                                 None];
+
+#[derive(Debug)]
+pub enum BspError {
+    Io(io::Error),
+    BadMagic(String),
+    UnsupportedVersion(i32),
+    Truncated,
+    Utf8(Utf8Error),
+    BadLumpLength {
+        offset: i32,
+        length: i32,
+        entry_size: i32,
+    },
+    /// The visdata header doesn't describe the lump: `8 + n_vecs * sz_vecs` isn't its length.
+    BadVisdata {
+        n_vecs: i32,
+        sz_vecs: i32,
+        length: i32,
+    },
+}
+
+impl fmt::Display for BspError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BspError::Io(ref e) => write!(f, "{}", e),
+            BspError::BadMagic(ref magic) => write!(f, "bad magic {:?}, expected \"IBSP\"", magic),
+            BspError::UnsupportedVersion(v) => {
+                write!(f, "unsupported version {}, expected 46 or 47", v)
+            }
+            BspError::Truncated => write!(f, "file is truncated"),
+            BspError::Utf8(ref e) => write!(f, "invalid string: {}", e),
+            BspError::BadLumpLength { offset, length, entry_size } => {
+                write!(f,
+                       "lump at offset {} has length {}, which isn't a multiple of {}",
+                       offset,
+                       length,
+                       entry_size)
+            }
+            BspError::BadVisdata { n_vecs, sz_vecs, length } => {
+                write!(f,
+                       "visdata of {} clusters with {} bytes each doesn't fit its lump of {} \
+                        bytes",
+                       n_vecs,
+                       sz_vecs,
+                       length)
+            }
+        }
+    }
+}
+
+impl Error for BspError {
+    fn description(&self) -> &str {
+        match *self {
+            BspError::Io(ref e) => e.description(),
+            BspError::BadMagic(_) => "bad magic",
+            BspError::UnsupportedVersion(_) => "unsupported version",
+            BspError::Truncated => "file is truncated",
+            BspError::Utf8(ref e) => e.description(),
+            BspError::BadLumpLength { .. } => "bad lump length",
+            BspError::BadVisdata { .. } => "bad visdata",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            BspError::Io(ref e) => Some(e),
+            BspError::Utf8(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BspError {
+    fn from(e: io::Error) -> BspError {
+        BspError::Io(e)
+    }
+}
+
+impl From<Utf8Error> for BspError {
+    fn from(e: Utf8Error) -> BspError {
+        BspError::Utf8(e)
+    }
+}
 
 pub struct BSPReader {
     pub data: Vec<u8>,
@@ -43,6 +174,32 @@ pub struct Direntries {
     pub visdata: Direntry,
 }
 
+impl Direntries {
+    /// Looks up a lump by one of the names in `LUMP_NAMES`.
+    pub fn by_name(&self, name: &str) -> Option<&Direntry> {
+        match name {
+            "entities" => Some(&self.entities),
+            "textures" => Some(&self.textures),
+            "planes" => Some(&self.planes),
+            "nodes" => Some(&self.nodes),
+            "leafs" => Some(&self.leafs),
+            "leaffaces" => Some(&self.leaffaces),
+            "leafbrushes" => Some(&self.leafbrushes),
+            "models" => Some(&self.models),
+            "brushes" => Some(&self.brushes),
+            "brushsides" => Some(&self.brushsides),
+            "vertexes" => Some(&self.vertexes),
+            "meshverts" => Some(&self.meshverts),
+            "effects" => Some(&self.effects),
+            "faces" => Some(&self.faces),
+            "lightmaps" => Some(&self.lightmaps),
+            "lightvols" => Some(&self.lightvols),
+            "visdata" => Some(&self.visdata),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Texture {
     pub name: String,
@@ -111,7 +268,40 @@ pub struct Vertex {
 pub struct Effect {
     pub name: String,
     pub brush: i32,
-    pub unknown: i32,
+    /// Brush side through which the fog volume can be seen, or -1 if the volume is
+    /// visible from every side.
+    pub visible_side: i32,
+}
+
+/// The potentially visible set: one bit vector of `sz_vecs` bytes per cluster.
+pub struct Visdata {
+    pub n_vecs: i32,
+    pub sz_vecs: i32,
+    pub vecs: Vec<u8>,
+}
+
+impl Visdata {
+    /// Maps without visdata and leafs outside of any cluster see everything.
+    pub fn is_cluster_visible(&self, from: i32, to: i32) -> bool {
+        if self.vecs.is_empty() || from < 0 || to < 0 {
+            return true;
+        }
+        let index = (from * self.sz_vecs + to / 8) as usize;
+        self.vecs.get(index).map_or(true, |&byte| byte & (1 << (to & 7)) != 0)
+    }
+}
+
+pub struct Lightmap {
+    pub map: Vec<[u8; 3]>,
+}
+
+/// One cell of the light grid that lights models.
+#[derive(Debug, Clone, Copy)]
+pub struct Lightvol {
+    pub ambient: [u8; 3],
+    pub directional: [u8; 3],
+    /// Direction towards the light as longitude and latitude, 256 steps per full turn.
+    pub dir: [u8; 2],
 }
 
 #[derive(Debug)]
@@ -132,13 +322,98 @@ pub struct Face {
     pub size: [i32; 2],
 }
 
+pub struct BspMap {
+    pub header: Header,
+    pub entities: String,
+    pub textures: Vec<Texture>,
+    pub planes: Vec<Plane>,
+    pub nodes: Vec<Node>,
+    pub leafs: Vec<Leaf>,
+    pub leaffaces: Vec<i32>,
+    pub leafbrushes: Vec<i32>,
+    pub models: Vec<Model>,
+    pub brushes: Vec<Brush>,
+    pub brushsides: Vec<Brushside>,
+    pub vertexes: Vec<Vertex>,
+    pub meshverts: Vec<i32>,
+    pub effects: Vec<Effect>,
+    pub faces: Vec<Face>,
+    pub lightmaps: Vec<Lightmap>,
+    pub lightvols: Vec<Lightvol>,
+    pub visdata: Visdata,
+}
+
+impl BspMap {
+    /// Bounds over all vertexes. These are tighter than the bounds of `models[0]`.
+    pub fn bounds(&self) -> ([f32; 3], [f32; 3]) {
+        world_bounds(&self.vertexes)
+    }
+
+    pub fn center(&self) -> [f32; 3] {
+        let (mins, maxs) = self.bounds();
+        [(mins[0] + maxs[0]) / 2.0, (mins[1] + maxs[1]) / 2.0, (mins[2] + maxs[2]) / 2.0]
+    }
+
+    /// Describes why the visdata can't be used for culling, or returns `None` if it can. Maps
+    /// compiled without vis have an empty lump, other tools write vectors that don't cover all
+    /// clusters of the leafs.
+    pub fn vis_problem(&self) -> Option<String> {
+        let visdata = &self.visdata;
+        if visdata.vecs.is_empty() {
+            return Some("the map has no visdata, it was probably compiled without -vis".to_owned());
+        }
+        let clusters = self.leafs.iter().map(|l| l.cluster + 1).max().unwrap_or(0);
+        if clusters > visdata.n_vecs {
+            return Some(format!("the visdata has {} clusters, but the leafs use {}",
+                                visdata.n_vecs,
+                                clusters));
+        }
+        if visdata.sz_vecs * 8 < visdata.n_vecs {
+            return Some(format!("the visdata vectors of {} bytes can't hold {} clusters",
+                                visdata.sz_vecs,
+                                visdata.n_vecs));
+        }
+        None
+    }
+}
+
+/// Returns `(mins, maxs)` of the vertex positions, or zero bounds if there are no vertexes.
+pub fn world_bounds(vertexes: &[Vertex]) -> ([f32; 3], [f32; 3]) {
+    if vertexes.is_empty() {
+        return ([0.0; 3], [0.0; 3]);
+    }
+
+    let mut mins = vertexes[0].position;
+    let mut maxs = vertexes[0].position;
+    for vertex in vertexes {
+        for i in 0..3 {
+            mins[i] = mins[i].min(vertex.position[i]);
+            maxs[i] = maxs[i].max(vertex.position[i]);
+        }
+    }
+
+    (mins, maxs)
+}
+
 impl BSPReader {
-    pub fn new(path: &str) -> BSPReader {
-        let mut f = File::open(path).unwrap();
+    /// Opens a BSP file, or reads it from stdin if `path` is `-`.
+    pub fn open(path: &str) -> io::Result<BSPReader> {
+        if path == "-" {
+            BSPReader::from_reader(io::stdin())
+        } else {
+            BSPReader::from_reader(try!(File::open(path)))
+        }
+    }
+
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<BSPReader> {
         let mut buf = Vec::new();
-        f.read_to_end(&mut buf);
+        try!(reader.read_to_end(&mut buf));
+        Ok(BSPReader::from_bytes(buf))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> BSPReader {
         BSPReader {
-            data: buf,
+            data: data,
             marker: 0,
         }
     }
@@ -148,211 +423,703 @@ impl BSPReader {
         self
     }
 
-    pub fn read_ubyte(&mut self) -> u8 {
-        let data = self.data[self.marker];
-        self.marker += 1;
-        data
+    pub fn read_bytes(&mut self, length: usize) -> Result<&[u8], BspError> {
+        if self.marker + length > self.data.len() {
+            return Err(BspError::Truncated);
+        }
+        let bytes = &self.data[self.marker..self.marker + length];
+        self.marker += length;
+        Ok(bytes)
     }
 
-    pub fn read_int(&mut self) -> i32 {
-        let bytes = [self.read_ubyte(), self.read_ubyte(), self.read_ubyte(), self.read_ubyte()];
-        Cursor::new(&bytes[..]).read_i32::<LittleEndian>().unwrap()
+    pub fn read_ubyte(&mut self) -> Result<u8, BspError> {
+        Ok(try!(self.read_bytes(1))[0])
     }
 
-    pub fn read_string(&mut self, length: usize) -> String {
-        let bytes = &(self.data)[self.marker..self.marker + length];
-        self.marker += length;
-        str::from_utf8(bytes).unwrap().to_owned()
+    pub fn read_int(&mut self) -> Result<i32, BspError> {
+        Ok(LittleEndian::read_i32(try!(self.read_bytes(4))))
     }
 
-    pub fn read_float(&mut self) -> f32 {
-        let bytes = [self.read_ubyte(), self.read_ubyte(), self.read_ubyte(), self.read_ubyte()];
-        Cursor::new(&bytes[..]).read_f32::<LittleEndian>().unwrap()
+    /// Reads `length` bytes of UTF-8 as is, which is what the entity lump needs.
+    pub fn read_string(&mut self, length: usize) -> Result<String, BspError> {
+        Ok(try!(str::from_utf8(try!(self.read_bytes(length)))).to_owned())
     }
 
-    pub fn read_direntry(&mut self) -> Direntry {
-        Direntry {
-            offset: self.read_int(),
-            length: self.read_int(),
-        }
+    /// Reads a fixed length, null padded name field like the ones in the texture and effect lumps.
+    /// Names are null terminated and not always valid UTF-8, so anything after the first null is
+    /// dropped and invalid bytes are replaced instead of failing the whole map.
+    pub fn read_cstring(&mut self, length: usize) -> Result<String, BspError> {
+        let bytes = try!(self.read_bytes(length));
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    pub fn read_float(&mut self) -> Result<f32, BspError> {
+        Ok(LittleEndian::read_f32(try!(self.read_bytes(4))))
+    }
+
+    pub fn read_direntry(&mut self) -> Result<Direntry, BspError> {
+        Ok(Direntry {
+            offset: try!(self.read_int()),
+            length: try!(self.read_int()),
+        })
+    }
+
+    pub fn read_direntries(&mut self) -> Result<Direntries, BspError> {
+        Ok(Direntries {
+            entities: try!(self.read_direntry()),
+            textures: try!(self.read_direntry()),
+            planes: try!(self.read_direntry()),
+            nodes: try!(self.read_direntry()),
+            leafs: try!(self.read_direntry()),
+            leaffaces: try!(self.read_direntry()),
+            leafbrushes: try!(self.read_direntry()),
+            models: try!(self.read_direntry()),
+            brushes: try!(self.read_direntry()),
+            brushsides: try!(self.read_direntry()),
+            vertexes: try!(self.read_direntry()),
+            meshverts: try!(self.read_direntry()),
+            effects: try!(self.read_direntry()),
+            faces: try!(self.read_direntry()),
+            lightmaps: try!(self.read_direntry()),
+            lightvols: try!(self.read_direntry()),
+            visdata: try!(self.read_direntry()),
+        })
     }
 
-    pub fn read_direntries(&mut self) -> Direntries {
-        Direntries {
-            entities: self.read_direntry(),
-            textures: self.read_direntry(),
-            planes: self.read_direntry(),
-            nodes: self.read_direntry(),
-            leafs: self.read_direntry(),
-            leaffaces: self.read_direntry(),
-            leafbrushes: self.read_direntry(),
-            models: self.read_direntry(),
-            brushes: self.read_direntry(),
-            brushsides: self.read_direntry(),
-            vertexes: self.read_direntry(),
-            meshverts: self.read_direntry(),
-            effects: self.read_direntry(),
-            faces: self.read_direntry(),
-            lightmaps: self.read_direntry(),
-            lightvols: self.read_direntry(),
-            visdata: self.read_direntry(),
+    /// Returns the unparsed bytes of a lump.
+    pub fn read_raw_lump(&mut self, direntry: &Direntry) -> Result<&[u8], BspError> {
+        if direntry.length == 0 {
+            return Ok(&[]);
         }
+        try!(self.jump_to_lump(direntry));
+        self.read_bytes(direntry.length as usize)
+    }
+
+    pub fn read_header(&mut self) -> Result<Header, BspError> {
+        let magic = String::from_utf8_lossy(try!(self.read_bytes(4))).into_owned();
+        Ok(Header {
+            magic: magic,
+            version: try!(self.read_int()),
+            direntries: try!(self.read_direntries()),
+        })
     }
 
-    pub fn read_header(&mut self) -> Header {
-        Header {
-            magic: self.read_string(4),
-            version: self.read_int(),
-            direntries: self.read_direntries(),
+    fn jump_to_lump(&mut self, direntry: &Direntry) -> Result<(), BspError> {
+        if direntry.offset < 0 || direntry.length < 0 ||
+           direntry.offset as usize + direntry.length as usize > self.data.len() {
+            return Err(BspError::Truncated);
         }
+        self.jump(direntry.offset as usize);
+        Ok(())
     }
 
-    pub fn read_entities(&mut self, direntries: &Direntries) -> String {
-        self.jump(direntries.entities.offset as usize)
-            .read_string(direntries.entities.length as usize)
+    pub fn read_entities(&mut self, direntries: &Direntries) -> Result<String, BspError> {
+        if direntries.entities.length == 0 {
+            return Ok(String::new());
+        }
+        try!(self.jump_to_lump(&direntries.entities));
+        self.read_string(direntries.entities.length as usize)
     }
 
-    pub fn read_list<T, F>(&mut self, direntry: &Direntry, entry_size: i32, read: F) -> Vec<T>
-        where F: Fn(&mut BSPReader) -> T
+    pub fn read_list<T, F>(&mut self,
+                           direntry: &Direntry,
+                           entry_size: i32,
+                           read: F)
+                           -> Result<Vec<T>, BspError>
+        where F: Fn(&mut BSPReader) -> Result<T, BspError>
     {
-        self.jump(direntry.offset as usize);
+        // Optional lumps like effects are often left empty, sometimes with a bogus offset
+        if direntry.length == 0 {
+            return Ok(Vec::new());
+        }
+        try!(self.jump_to_lump(direntry));
+        if direntry.length % entry_size != 0 {
+            return Err(BspError::BadLumpLength {
+                offset: direntry.offset,
+                length: direntry.length,
+                entry_size: entry_size,
+            });
+        }
         let mut list = Vec::new();
         let entries = direntry.length / entry_size;
         for _ in 0..entries {
-            list.push(read(self));
+            list.push(try!(read(self)));
         }
-        list
+        Ok(list)
     }
 
-    pub fn read_textures(&mut self, direntries: &Direntries) -> Vec<Texture> {
-        self.read_list(&direntries.textures, 64 + 4 + 4, |r| {
-            Texture {
-                name: r.read_string(64).replace("\u{0}", "").to_owned(),
-                flags: r.read_int(),
-                contents: r.read_int(),
-            }
+    pub fn read_textures(&mut self, direntries: &Direntries) -> Result<Vec<Texture>, BspError> {
+        self.read_list(&direntries.textures, TEXTURE_SIZE, |r| {
+            Ok(Texture {
+                name: try!(r.read_cstring(64)),
+                flags: try!(r.read_int()),
+                contents: try!(r.read_int()),
+            })
         })
     }
 
-    pub fn read_planes(&mut self, direntries: &Direntries) -> Vec<Plane> {
-        self.read_list(&direntries.planes, 3 * 4 + 4, |r| {
-            Plane {
-                normal: [r.read_float(), r.read_float(), r.read_float()],
-                dist: r.read_float(),
-            }
+    pub fn read_planes(&mut self, direntries: &Direntries) -> Result<Vec<Plane>, BspError> {
+        self.read_list(&direntries.planes, PLANE_SIZE, |r| {
+            Ok(Plane {
+                normal: [try!(r.read_float()), try!(r.read_float()), try!(r.read_float())],
+                dist: try!(r.read_float()),
+            })
         })
     }
 
-    pub fn read_nodes(&mut self, direntries: &Direntries) -> Vec<Node> {
-        self.read_list(&direntries.nodes, 4 + 2 * 4 + 3 * 4 + 3 * 4, |r| {
-            Node {
-                plane: r.read_int(),
-                children: [r.read_int(), r.read_int()],
-                mins: [r.read_int(), r.read_int(), r.read_int()],
-                maxs: [r.read_int(), r.read_int(), r.read_int()],
-            }
+    pub fn read_nodes(&mut self, direntries: &Direntries) -> Result<Vec<Node>, BspError> {
+        self.read_list(&direntries.nodes, NODE_SIZE, |r| {
+            Ok(Node {
+                plane: try!(r.read_int()),
+                children: [try!(r.read_int()), try!(r.read_int())],
+                mins: [try!(r.read_int()), try!(r.read_int()), try!(r.read_int())],
+                maxs: [try!(r.read_int()), try!(r.read_int()), try!(r.read_int())],
+            })
         })
     }
 
-    pub fn read_leafs(&mut self, direntries: &Direntries) -> Vec<Leaf> {
-        self.read_list(&direntries.leafs, 12 * 4, |r| {
-            Leaf {
-                cluster: r.read_int(),
-                area: r.read_int(),
-                mins: [r.read_int(), r.read_int(), r.read_int()],
-                maxs: [r.read_int(), r.read_int(), r.read_int()],
-                leafface: r.read_int(),
-                n_leaffaces: r.read_int(),
-                leafbrush: r.read_int(),
-                n_leafbrushes: r.read_int(),
-            }
+    pub fn read_leafs(&mut self, direntries: &Direntries) -> Result<Vec<Leaf>, BspError> {
+        self.read_list(&direntries.leafs, LEAF_SIZE, |r| {
+            Ok(Leaf {
+                cluster: try!(r.read_int()),
+                area: try!(r.read_int()),
+                mins: [try!(r.read_int()), try!(r.read_int()), try!(r.read_int())],
+                maxs: [try!(r.read_int()), try!(r.read_int()), try!(r.read_int())],
+                leafface: try!(r.read_int()),
+                n_leaffaces: try!(r.read_int()),
+                leafbrush: try!(r.read_int()),
+                n_leafbrushes: try!(r.read_int()),
+            })
         })
     }
 
-    pub fn read_leaffaces(&mut self, direntries: &Direntries) -> Vec<i32> {
-        self.read_list(&direntries.leaffaces, 4, |r| r.read_int())
+    pub fn read_leaffaces(&mut self, direntries: &Direntries) -> Result<Vec<i32>, BspError> {
+        self.read_list(&direntries.leaffaces, LEAFFACE_SIZE, |r| r.read_int())
     }
 
-    pub fn read_leafbrushes(&mut self, direntries: &Direntries) -> Vec<i32> {
-        self.read_list(&direntries.leafbrushes, 4, |r| r.read_int())
+    pub fn read_leafbrushes(&mut self, direntries: &Direntries) -> Result<Vec<i32>, BspError> {
+        self.read_list(&direntries.leafbrushes, LEAFBRUSH_SIZE, |r| r.read_int())
     }
 
-    pub fn read_models(&mut self, direntries: &Direntries) -> Vec<Model> {
-        self.read_list(&direntries.models, 10 * 4, |r| {
-            Model {
-                mins: [r.read_float(), r.read_float(), r.read_float()],
-                maxs: [r.read_float(), r.read_float(), r.read_float()],
-                face: r.read_int(),
-                n_faces: r.read_int(),
-                brush: r.read_int(),
-                n_brushes: r.read_int(),
-            }
+    pub fn read_models(&mut self, direntries: &Direntries) -> Result<Vec<Model>, BspError> {
+        self.read_list(&direntries.models, MODEL_SIZE, |r| {
+            Ok(Model {
+                mins: [try!(r.read_float()), try!(r.read_float()), try!(r.read_float())],
+                maxs: [try!(r.read_float()), try!(r.read_float()), try!(r.read_float())],
+                face: try!(r.read_int()),
+                n_faces: try!(r.read_int()),
+                brush: try!(r.read_int()),
+                n_brushes: try!(r.read_int()),
+            })
         })
     }
 
-    pub fn read_brushes(&mut self, direntries: &Direntries) -> Vec<Brush> {
-        self.read_list(&direntries.brushes, 3 * 4, |r| {
-            Brush {
-                brushside: r.read_int(),
-                n_brushsides: r.read_int(),
-                texture: r.read_int(),
-            }
+    pub fn read_brushes(&mut self, direntries: &Direntries) -> Result<Vec<Brush>, BspError> {
+        self.read_list(&direntries.brushes, BRUSH_SIZE, |r| {
+            Ok(Brush {
+                brushside: try!(r.read_int()),
+                n_brushsides: try!(r.read_int()),
+                texture: try!(r.read_int()),
+            })
         })
     }
 
-    pub fn read_brushsides(&mut self, direntries: &Direntries) -> Vec<Brushside> {
-        self.read_list(&direntries.brushsides, 2 * 4, |r| {
-            Brushside {
-                plane: r.read_int(),
-                texture: r.read_int(),
-            }
+    pub fn read_brushsides(&mut self, direntries: &Direntries) -> Result<Vec<Brushside>, BspError> {
+        self.read_list(&direntries.brushsides, BRUSHSIDE_SIZE, |r| {
+            Ok(Brushside {
+                plane: try!(r.read_int()),
+                texture: try!(r.read_int()),
+            })
         })
     }
 
-    pub fn read_vertexes(&mut self, direntries: &Direntries) -> Vec<Vertex> {
-        self.read_list(&direntries.vertexes, 14 * 4, |r| {
-            Vertex {
-                position: [r.read_float(), r.read_float(), r.read_float()],
-                texcoord: [[r.read_float(), r.read_float()], [r.read_float(), r.read_float()]],
-                normal: [r.read_float(), r.read_float(), r.read_float()],
-                color: [r.read_ubyte(), r.read_ubyte(), r.read_ubyte(), r.read_ubyte()],
-            }
+    pub fn read_vertexes(&mut self, direntries: &Direntries) -> Result<Vec<Vertex>, BspError> {
+        self.read_list(&direntries.vertexes, VERTEX_SIZE, |r| {
+            Ok(Vertex {
+                position: [try!(r.read_float()), try!(r.read_float()), try!(r.read_float())],
+                texcoord: [[try!(r.read_float()), try!(r.read_float())],
+                           [try!(r.read_float()), try!(r.read_float())]],
+                normal: [try!(r.read_float()), try!(r.read_float()), try!(r.read_float())],
+                color: [try!(r.read_ubyte()),
+                        try!(r.read_ubyte()),
+                        try!(r.read_ubyte()),
+                        try!(r.read_ubyte())],
+            })
         })
     }
 
-    pub fn read_meshverts(&mut self, direntries: &Direntries) -> Vec<i32> {
-        self.read_list(&direntries.meshverts, 4, |r| r.read_int())
+    pub fn read_meshverts(&mut self, direntries: &Direntries) -> Result<Vec<i32>, BspError> {
+        self.read_list(&direntries.meshverts, MESHVERT_SIZE, |r| r.read_int())
     }
 
-    pub fn read_effects(&mut self, direntries: &Direntries) -> Vec<Effect> {
-        self.read_list(&direntries.effects, 64 + 2 * 4, |r| {
-            Effect {
-                name: r.read_string(64),
-                brush: r.read_int(),
-                unknown: r.read_int(),
-            }
+    pub fn read_effects(&mut self, direntries: &Direntries) -> Result<Vec<Effect>, BspError> {
+        self.read_list(&direntries.effects, EFFECT_SIZE, |r| {
+            Ok(Effect {
+                name: try!(r.read_cstring(64)),
+                brush: try!(r.read_int()),
+                visible_side: try!(r.read_int()),
+            })
         })
     }
 
-    pub fn read_faces(&mut self, direntries: &Direntries) -> Vec<Face> {
-        self.read_list(&direntries.faces, 26 * 4, |r| {
-            Face {
-                texture: r.read_int(),
-                effect: r.read_int(),
-                f_type: r.read_int(),
-                vertex: r.read_int(),
-                n_vertexes: r.read_int(),
-                meshvert: r.read_int(),
-                n_meshverts: r.read_int(),
-                lm_index: r.read_int(),
-                lm_start: [r.read_int(), r.read_int()],
-                lm_size: [r.read_int(), r.read_int()],
-                lm_origin: [r.read_float(), r.read_float(), r.read_float()],
-                lm_vecs: [[r.read_float(), r.read_float(), r.read_float()],
-                          [r.read_float(), r.read_float(), r.read_float()]],
-                normal: [r.read_float(), r.read_float(), r.read_float()],
-                size: [r.read_int(), r.read_int()],
-            }
+    pub fn read_faces(&mut self, direntries: &Direntries) -> Result<Vec<Face>, BspError> {
+        self.read_list(&direntries.faces, FACE_SIZE, |r| {
+            Ok(Face {
+                texture: try!(r.read_int()),
+                effect: try!(r.read_int()),
+                f_type: try!(r.read_int()),
+                vertex: try!(r.read_int()),
+                n_vertexes: try!(r.read_int()),
+                meshvert: try!(r.read_int()),
+                n_meshverts: try!(r.read_int()),
+                lm_index: try!(r.read_int()),
+                lm_start: [try!(r.read_int()), try!(r.read_int())],
+                lm_size: [try!(r.read_int()), try!(r.read_int())],
+                lm_origin: [try!(r.read_float()), try!(r.read_float()), try!(r.read_float())],
+                lm_vecs: [[try!(r.read_float()), try!(r.read_float()), try!(r.read_float())],
+                          [try!(r.read_float()), try!(r.read_float()), try!(r.read_float())]],
+                normal: [try!(r.read_float()), try!(r.read_float()), try!(r.read_float())],
+                size: [try!(r.read_int()), try!(r.read_int())],
+            })
+        })
+    }
+
+    pub fn read_lightmaps(&mut self, direntries: &Direntries) -> Result<Vec<Lightmap>, BspError> {
+        self.read_list(&direntries.lightmaps, LIGHTMAP_SIZE, |r| {
+            let bytes = try!(r.read_bytes(LIGHTMAP_SIZE as usize));
+            Ok(Lightmap { map: bytes.chunks(3).map(|t| [t[0], t[1], t[2]]).collect() })
+        })
+    }
+
+    pub fn read_lightvols(&mut self, direntries: &Direntries) -> Result<Vec<Lightvol>, BspError> {
+        self.read_list(&direntries.lightvols, LIGHTVOL_SIZE, |r| {
+            let bytes = try!(r.read_bytes(LIGHTVOL_SIZE as usize));
+            Ok(Lightvol {
+                ambient: [bytes[0], bytes[1], bytes[2]],
+                directional: [bytes[3], bytes[4], bytes[5]],
+                dir: [bytes[6], bytes[7]],
+            })
         })
     }
+
+    pub fn read_visdata(&mut self, direntries: &Direntries) -> Result<Visdata, BspError> {
+        let direntry = &direntries.visdata;
+        if direntry.length == 0 {
+            return Ok(Visdata {
+                n_vecs: 0,
+                sz_vecs: 0,
+                vecs: Vec::new(),
+            });
+        }
+
+        try!(self.jump_to_lump(direntry));
+        let n_vecs = try!(self.read_int());
+        let sz_vecs = try!(self.read_int());
+        // Some tools write a header that doesn't match the vectors that follow it. Reading past
+        // the lump or only part of it would make clusters see garbage, so these maps are
+        // rejected instead.
+        if n_vecs < 0 || sz_vecs < 0 ||
+           8 + n_vecs as i64 * sz_vecs as i64 != direntry.length as i64 {
+            return Err(BspError::BadVisdata {
+                n_vecs: n_vecs,
+                sz_vecs: sz_vecs,
+                length: direntry.length,
+            });
+        }
+        Ok(Visdata {
+            n_vecs: n_vecs,
+            sz_vecs: sz_vecs,
+            vecs: try!(self.read_bytes((n_vecs * sz_vecs) as usize)).to_vec(),
+        })
+    }
+
+    pub fn read_map(&mut self) -> Result<BspMap, BspError> {
+        let header = try!(self.jump(0).read_header());
+        Ok(BspMap {
+            entities: try!(self.read_entities(&header.direntries)),
+            textures: try!(self.read_textures(&header.direntries)),
+            planes: try!(self.read_planes(&header.direntries)),
+            nodes: try!(self.read_nodes(&header.direntries)),
+            leafs: try!(self.read_leafs(&header.direntries)),
+            leaffaces: try!(self.read_leaffaces(&header.direntries)),
+            leafbrushes: try!(self.read_leafbrushes(&header.direntries)),
+            models: try!(self.read_models(&header.direntries)),
+            brushes: try!(self.read_brushes(&header.direntries)),
+            brushsides: try!(self.read_brushsides(&header.direntries)),
+            vertexes: try!(self.read_vertexes(&header.direntries)),
+            meshverts: try!(self.read_meshverts(&header.direntries)),
+            effects: try!(self.read_effects(&header.direntries)),
+            faces: try!(self.read_faces(&header.direntries)),
+            lightmaps: try!(self.read_lightmaps(&header.direntries)),
+            lightvols: try!(self.read_lightvols(&header.direntries)),
+            visdata: try!(self.read_visdata(&header.direntries)),
+            header: header,
+        })
+    }
+}
+
+/// Returns the effect (usually a fog volume) a face is in, if any.
+pub fn effect_for_face<'a>(face: &Face, effects: &'a [Effect]) -> Option<&'a Effect> {
+    if face.effect < 0 {
+        None
+    } else {
+        effects.get(face.effect as usize)
+    }
+}
+
+pub fn validate_header(header: &Header) -> Result<(), BspError> {
+    if header.magic != "IBSP" {
+        return Err(BspError::BadMagic(header.magic.clone()));
+    }
+    if header.version != 46 && header.version != 47 {
+        return Err(BspError::UnsupportedVersion(header.version));
+    }
+    Ok(())
+}
+
+/// A directory entry that doesn't describe a lump inside the file.
+#[derive(Debug)]
+pub struct DirentryError {
+    pub lump: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for DirentryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} lump: {}", self.lump, self.message)
+    }
+}
+
+/// Checks that every non empty lump lies between the header and the end of a file of
+/// `file_size` bytes and holds a whole number of entries.
+pub fn validate_direntries(direntries: &Direntries, file_size: usize) -> Vec<DirentryError> {
+    let mut errors = Vec::new();
+    for (&lump, &entry_size) in LUMP_NAMES.iter().zip(LUMP_ENTRY_SIZES.iter()) {
+        let direntry = direntries.by_name(lump).unwrap();
+        let message = if direntry.offset < 0 || direntry.length < 0 {
+            format!("negative offset {} or length {}", direntry.offset, direntry.length)
+        } else if direntry.length == 0 {
+            continue;
+        } else if (direntry.offset as usize) < HEADER_SIZE {
+            format!("offset {} overlaps the header", direntry.offset)
+        } else if direntry.offset as usize + direntry.length as usize > file_size {
+            format!("{} bytes at offset {} run past the end of the file ({} bytes)",
+                    direntry.length,
+                    direntry.offset,
+                    file_size)
+        } else if entry_size.map_or(false, |size| direntry.length % size != 0) {
+            format!("length {} isn't a multiple of the entry size {}",
+                    direntry.length,
+                    entry_size.unwrap())
+        } else {
+            continue;
+        };
+        errors.push(DirentryError {
+            lump: lump,
+            message: message,
+        });
+    }
+    errors
+}
+
+/// An index in one lump that points outside of the lump it refers to.
+#[derive(Debug)]
+pub struct RefError {
+    pub lump: &'static str,
+    pub index: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RefError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: {}", self.lump, self.index, self.message)
+    }
+}
+
+/// Index of the last of `count` entries starting at `first`, wide enough not to overflow.
+fn last(first: i32, count: i32) -> i64 {
+    first as i64 + count as i64 - 1
+}
+
+/// Checks that every cross reference between lumps stays in bounds, so the renderer can index
+/// with them without panicking. Returns all violations instead of stopping at the first one.
+pub fn validate_references(map: &BspMap) -> Vec<RefError> {
+    let mut errors = Vec::new();
+    {
+        // Values are i64 so the sums of i32 fields can't overflow
+        let mut check = |lump: &'static str, index: usize, what: &str, value: i64, count: usize| {
+            if value < 0 || value >= count as i64 {
+                errors.push(RefError {
+                    lump: lump,
+                    index: index,
+                    message: format!("{} {} is out of range ({} entries)", what, value, count),
+                });
+            }
+        };
+
+        for (i, face) in map.faces.iter().enumerate() {
+            check("face", i, "texture", face.texture as i64, map.textures.len());
+            if face.effect != -1 {
+                check("face", i, "effect", face.effect as i64, map.effects.len());
+            }
+            // External lightmaps leave the lump empty
+            if face.lm_index >= 0 && !map.lightmaps.is_empty() {
+                check("face", i, "lightmap", face.lm_index as i64, map.lightmaps.len());
+            }
+            if face.n_vertexes > 0 {
+                check("face", i, "first vertex", face.vertex as i64, map.vertexes.len());
+                check("face",
+                      i,
+                      "last vertex",
+                      last(face.vertex, face.n_vertexes),
+                      map.vertexes.len());
+            }
+            if face.n_meshverts > 0 {
+                check("face", i, "first meshvert", face.meshvert as i64, map.meshverts.len());
+                check("face",
+                      i,
+                      "last meshvert",
+                      last(face.meshvert, face.n_meshverts),
+                      map.meshverts.len());
+                let start = face.meshvert.max(0) as usize;
+                let end = (face.meshvert as i64 + face.n_meshverts as i64).max(0) as usize;
+                let end = end.min(map.meshverts.len()).max(start);
+                for &meshvert in map.meshverts.get(start..end).unwrap_or(&[]) {
+                    check("face",
+                          i,
+                          "meshvert vertex",
+                          face.vertex as i64 + meshvert as i64,
+                          map.vertexes.len());
+                }
+            }
+        }
+
+        for (i, leaf) in map.leafs.iter().enumerate() {
+            if leaf.n_leaffaces > 0 {
+                check("leaf", i, "first leafface", leaf.leafface as i64, map.leaffaces.len());
+                check("leaf",
+                      i,
+                      "last leafface",
+                      last(leaf.leafface, leaf.n_leaffaces),
+                      map.leaffaces.len());
+            }
+            if leaf.n_leafbrushes > 0 {
+                check("leaf", i, "first leafbrush", leaf.leafbrush as i64, map.leafbrushes.len());
+                check("leaf",
+                      i,
+                      "last leafbrush",
+                      last(leaf.leafbrush, leaf.n_leafbrushes),
+                      map.leafbrushes.len());
+            }
+        }
+        for (i, &face) in map.leaffaces.iter().enumerate() {
+            check("leafface", i, "face", face as i64, map.faces.len());
+        }
+        for (i, &brush) in map.leafbrushes.iter().enumerate() {
+            check("leafbrush", i, "brush", brush as i64, map.brushes.len());
+        }
+
+        for (i, node) in map.nodes.iter().enumerate() {
+            check("node", i, "plane", node.plane as i64, map.planes.len());
+            for &child in node.children.iter() {
+                if child >= 0 {
+                    check("node", i, "child node", child as i64, map.nodes.len());
+                } else {
+                    check("node", i, "child leaf", -(child + 1) as i64, map.leafs.len());
+                }
+            }
+        }
+
+        for (i, model) in map.models.iter().enumerate() {
+            if model.n_faces > 0 {
+                check("model", i, "first face", model.face as i64, map.faces.len());
+                check("model", i, "last face", last(model.face, model.n_faces), map.faces.len());
+            }
+            if model.n_brushes > 0 {
+                check("model", i, "first brush", model.brush as i64, map.brushes.len());
+                check("model",
+                      i,
+                      "last brush",
+                      last(model.brush, model.n_brushes),
+                      map.brushes.len());
+            }
+        }
+
+        for (i, brush) in map.brushes.iter().enumerate() {
+            check("brush", i, "texture", brush.texture as i64, map.textures.len());
+            if brush.n_brushsides > 0 {
+                check("brush", i, "first brushside", brush.brushside as i64, map.brushsides.len());
+                check("brush",
+                      i,
+                      "last brushside",
+                      last(brush.brushside, brush.n_brushsides),
+                      map.brushsides.len());
+            }
+        }
+        for (i, side) in map.brushsides.iter().enumerate() {
+            check("brushside", i, "plane", side.plane as i64, map.planes.len());
+            check("brushside", i, "texture", side.texture as i64, map.textures.len());
+        }
+
+        for (i, effect) in map.effects.iter().enumerate() {
+            check("effect", i, "brush", effect.brush as i64, map.brushes.len());
+        }
+    }
+    errors
+}
+
+/// Reads a whole map from `path` (`-` for stdin) after checking that it has a valid header.
+pub fn load(path: &str) -> Result<BspMap, BspError> {
+    load_reader(try!(BSPReader::open(path)))
+}
+
+/// Reads a whole map from a BSP file in memory, like `load`.
+pub fn load_bytes(data: Vec<u8>) -> Result<BspMap, BspError> {
+    load_reader(BSPReader::from_bytes(data))
+}
+
+fn load_reader(mut reader: BSPReader) -> Result<BspMap, BspError> {
+    if reader.data.len() < HEADER_SIZE {
+        return Err(BspError::Truncated);
+    }
+    try!(validate_header(&try!(reader.read_header())));
+    reader.read_map()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    #[test]
+    fn lump_entry_sizes_match_the_format() {
+        // Spelled out field by field from the Quake 3 BSP format, f32 and i32 are 4 bytes
+        let expected = [("entities", None),
+                        ("textures", Some(64 + 4 + 4)), // name, flags, contents
+                        ("planes", Some(16)), // normal, dist
+                        ("nodes", Some(36)), // plane, children, mins, maxs
+                        ("leafs", Some(48)), // cluster, area, mins, maxs, 2 ranges
+                        ("leaffaces", Some(4)),
+                        ("leafbrushes", Some(4)),
+                        ("models", Some(40)), // mins, maxs, face and brush ranges
+                        ("brushes", Some(12)), // brushside range, texture
+                        ("brushsides", Some(8)), // plane, texture
+                        ("vertexes", Some(44)), // 3 + 2 + 2 + 3 f32, 4 u8 color
+                        ("meshverts", Some(4)),
+                        ("effects", Some(72)), // name, brush, visible_side
+                        ("faces", Some(104)), // 12 i32, 12 f32, 2 i32 for the patch size
+                        ("lightmaps", Some(49152)), // 128 * 128 rgb
+                        ("lightvols", Some(8)), // ambient, directional, dir
+                        ("visdata", None)];
+        for (i, &(name, size)) in expected.iter().enumerate() {
+            assert_eq!(LUMP_NAMES[i], name);
+            assert_eq!(LUMP_ENTRY_SIZES[i], size, "entry size of {}", name);
+        }
+    }
+
+    #[test]
+    fn read_cstring_stops_at_the_first_null() {
+        let mut reader = BSPReader::from_bytes(b"textures/base\0junk\0\0\0".to_vec());
+        assert_eq!(reader.read_cstring(21).unwrap(), "textures/base");
+        assert_eq!(reader.marker, 21);
+    }
+
+    #[test]
+    fn read_cstring_reads_the_whole_field_without_a_null() {
+        let mut reader = BSPReader::from_bytes(b"noise".to_vec());
+        assert_eq!(reader.read_cstring(5).unwrap(), "noise");
+    }
+
+    #[test]
+    fn read_cstring_replaces_invalid_bytes() {
+        let mut reader = BSPReader::from_bytes(vec![b'a', 0xe9, b'b', 0, 0xff]);
+        assert_eq!(reader.read_cstring(5).unwrap(), "a\u{fffd}b");
+    }
+
+    #[test]
+    fn read_cstring_fails_past_the_end() {
+        let mut reader = BSPReader::from_bytes(b"abc".to_vec());
+        match reader.read_cstring(4) {
+            Err(BspError::Truncated) => {}
+            other => panic!("expected Truncated, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_list_ignores_the_offset_of_empty_lumps() {
+        let mut reader = BSPReader::from_bytes(vec![0; 8]);
+        for &offset in &[0, 1000, -1] {
+            let direntry = Direntry {
+                offset: offset,
+                length: 0,
+            };
+            let list = reader.read_list(&direntry, 4, |_| -> Result<u8, BspError> {
+                                 panic!("read an entry of an empty lump")
+                             })
+                             .unwrap();
+            assert!(list.is_empty());
+        }
+    }
+
+    #[test]
+    fn read_list_reads_every_entry() {
+        let mut reader = BSPReader::from_bytes(vec![0, 0, 1, 2, 3, 4]);
+        let direntry = Direntry {
+            offset: 2,
+            length: 4,
+        };
+        let list = reader.read_list(&direntry, 2, |r| {
+                             Ok((try!(r.read_ubyte()), try!(r.read_ubyte())))
+                         })
+                         .unwrap();
+        assert_eq!(list, vec![(1, 2), (3, 4)]);
+    }
+
+    #[test]
+    fn read_list_rejects_partial_entries() {
+        let mut reader = BSPReader::from_bytes(vec![0; 8]);
+        let direntry = Direntry {
+            offset: 0,
+            length: 6,
+        };
+        match reader.read_list(&direntry, 4, |r| r.read_int()) {
+            Err(BspError::BadLumpLength { length: 6, entry_size: 4, .. }) => {}
+            other => panic!("expected BadLumpLength, got {:?}", other),
+        }
+    }
+
+    fn default_map() -> BspMap {
+        load_bytes(include_bytes!("../assets/default.bsp").to_vec()).unwrap()
+    }
+
+    #[test]
+    fn read_visdata_rejects_a_header_that_doesnt_match_the_lump() {
+        let mut data = include_bytes!("../assets/default.bsp").to_vec();
+        // The visdata direntry is the last of the header, n_vecs is the first field of the lump
+        let offset = LittleEndian::read_i32(&data[8 + 16 * 8..]) as usize;
+        let length = LittleEndian::read_i32(&data[8 + 16 * 8 + 4..]);
+        let n_vecs = LittleEndian::read_i32(&data[offset..]);
+        let sz_vecs = LittleEndian::read_i32(&data[offset + 4..]);
+        assert_eq!(8 + n_vecs * sz_vecs, length);
+
+        LittleEndian::write_i32(&mut data[offset..], n_vecs + 1);
+        match load_bytes(data) {
+            Err(BspError::BadVisdata { n_vecs: bad, sz_vecs: size, length: lump }) => {
+                assert_eq!((bad, size, lump), (n_vecs + 1, sz_vecs, length));
+            }
+            Err(other) => panic!("expected BadVisdata, got {}", other),
+            Ok(_) => panic!("loaded visdata that doesn't fit its lump"),
+        }
+    }
+
+    #[test]
+    fn validate_references_reports_ranges_that_overflow_i32() {
+        let mut map = default_map();
+        assert!(validate_references(&map).is_empty());
+        map.faces[0].vertex = ::std::i32::MAX;
+        map.faces[0].n_vertexes = 2;
+        let errors = validate_references(&map);
+        assert!(errors.iter().any(|e| e.lump == "face" && e.message.starts_with("last vertex")));
+    }
 }
@@ -0,0 +1,53 @@
+use cgmath::Point3;
+
+/// Camera positions recorded over time. Playing a path back only moves the camera, where it
+/// looks stays under the control of the mouse, so a recorded dolly move can be aimed by hand.
+pub struct CameraPath {
+    /// Seconds since the recording started and the camera position at that time.
+    points: Vec<(f32, Point3<f32>)>,
+}
+
+impl CameraPath {
+    pub fn new() -> CameraPath {
+        CameraPath { points: Vec::new() }
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Length of the recording in seconds.
+    pub fn duration(&self) -> f32 {
+        self.points.last().map_or(0.0, |p| p.0)
+    }
+
+    /// Appends `position` at `time`, which has to be later than the previous point.
+    pub fn record(&mut self, time: f32, position: Point3<f32>) {
+        if self.points.last().map_or(true, |p| time > p.0) {
+            self.points.push((time, position));
+        }
+    }
+
+    /// Interpolates the position at `time` linearly, or returns `None` once the recording ends.
+    pub fn position_at(&self, time: f32) -> Option<Point3<f32>> {
+        if time > self.duration() {
+            return None;
+        }
+        let next = match self.points.iter().position(|p| p.0 >= time) {
+            Some(next) => next,
+            None => return None,
+        };
+        if next == 0 {
+            return Some(self.points[0].1);
+        }
+
+        let (t0, p0) = self.points[next - 1];
+        let (t1, p1) = self.points[next];
+        let factor = (time - t0) / (t1 - t0);
+        Some(p0 + (p1 - p0) * factor)
+    }
+}
@@ -0,0 +1,484 @@
+use bsp_reader::{BspMap, Brush};
+use geometry::dot;
+
+pub const CONTENTS_SOLID: i32 = 1;
+pub const CONTENTS_LAVA: i32 = 8;
+pub const CONTENTS_SLIME: i32 = 0x10;
+pub const CONTENTS_WATER: i32 = 0x20;
+pub const CONTENTS_FOG: i32 = 0x40;
+pub const CONTENTS_AREAPORTAL: i32 = 0x8000;
+pub const CONTENTS_PLAYERCLIP: i32 = 0x10000;
+pub const CONTENTS_TRIGGER: i32 = 0x40000000;
+
+/// Names of the content flags, used to describe `point_contents` results.
+const CONTENTS_NAMES: [(i32, &'static str); 8] = [(CONTENTS_SOLID, "solid"),
+                                                  (CONTENTS_LAVA, "lava"),
+                                                  (CONTENTS_SLIME, "slime"),
+                                                  (CONTENTS_WATER, "water"),
+                                                  (CONTENTS_FOG, "fog"),
+                                                  (CONTENTS_AREAPORTAL, "areaportal"),
+                                                  (CONTENTS_PLAYERCLIP, "playerclip"),
+                                                  (CONTENTS_TRIGGER, "trigger")];
+
+const EPSILON: f32 = 1.0 / 32.0;
+/// Overlapping brushes `push_out_of_solid` moves out of before giving up.
+const MAX_PUSHES: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub start: [f32; 3],
+    pub end: [f32; 3],
+    pub fraction: f32,
+    pub normal: [f32; 3],
+    pub brush: Option<usize>,
+    pub start_solid: bool,
+    pub all_solid: bool,
+}
+
+impl Trace {
+    pub fn hit(&self) -> bool {
+        self.fraction < 1.0
+    }
+}
+
+struct TraceWork<'a> {
+    map: &'a BspMap,
+    radius: f32,
+    contents: i32,
+    trace: Trace,
+}
+
+pub fn trace_ray(map: &BspMap, start: [f32; 3], end: [f32; 3]) -> Trace {
+    trace_sphere(map, start, end, 0.0)
+}
+
+/// Sweeps a sphere from `start` to `end` through the world brushes that contain any of
+/// `CONTENTS_SOLID`. `end` of the result is the position where the sweep stopped.
+pub fn trace_sphere(map: &BspMap, start: [f32; 3], end: [f32; 3], radius: f32) -> Trace {
+    let mut work = TraceWork {
+        map: map,
+        radius: radius,
+        contents: CONTENTS_SOLID,
+        trace: Trace {
+            start: start,
+            end: end,
+            fraction: 1.0,
+            normal: [0.0, 0.0, 0.0],
+            brush: None,
+            start_solid: false,
+            all_solid: false,
+        },
+    };
+
+    if !map.nodes.is_empty() {
+        work.check_node(0, 0.0, 1.0, start, end);
+    }
+
+    let mut trace = work.trace;
+    trace.end = lerp(start, end, trace.fraction);
+    trace
+}
+
+/// Returns the index of the leaf containing `point`.
+pub fn find_leaf(map: &BspMap, point: [f32; 3]) -> usize {
+    let mut index = 0;
+    while index >= 0 && !map.nodes.is_empty() {
+        let node = &map.nodes[index as usize];
+        let plane = &map.planes[node.plane as usize];
+        index = if dot(point, plane.normal) - plane.dist >= 0.0 {
+            node.children[0]
+        } else {
+            node.children[1]
+        };
+    }
+    if index < 0 {
+        (-(index + 1)) as usize
+    } else {
+        0
+    }
+}
+
+/// Returns the indices of all leafs whose bounds intersect the box from `mins` to `maxs`.
+pub fn leaves_in_box(map: &BspMap, mins: [f32; 3], maxs: [f32; 3]) -> Vec<usize> {
+    let mut leaves = Vec::new();
+    if map.nodes.is_empty() {
+        return leaves;
+    }
+
+    let mut stack = vec![0];
+    while let Some(index) = stack.pop() {
+        if index < 0 {
+            let leaf = (-(index + 1)) as usize;
+            if let Some(l) = map.leafs.get(leaf) {
+                if (0..3).all(|a| l.mins[a] as f32 <= maxs[a] && l.maxs[a] as f32 >= mins[a]) {
+                    leaves.push(leaf);
+                }
+            }
+            continue;
+        }
+
+        let node = &map.nodes[index as usize];
+        let plane = &map.planes[node.plane as usize];
+        // Distance of the box corners closest to and furthest along the plane normal
+        let mut near = 0.0;
+        let mut far = 0.0;
+        for a in 0..3 {
+            if plane.normal[a] >= 0.0 {
+                near += plane.normal[a] * mins[a];
+                far += plane.normal[a] * maxs[a];
+            } else {
+                near += plane.normal[a] * maxs[a];
+                far += plane.normal[a] * mins[a];
+            }
+        }
+        if far - plane.dist >= 0.0 {
+            stack.push(node.children[0]);
+        }
+        if near - plane.dist < 0.0 {
+            stack.push(node.children[1]);
+        }
+    }
+    leaves
+}
+
+/// Returns the indices of all leafs ordered from near to far as seen from `viewpoint`. At every
+/// node the child on the viewpoint's side of the plane is walked first, so no leaf comes after
+/// one that could be hidden behind it. Each leaf appears once, in the order of the tree rather
+/// than by its distance.
+pub fn leaves_front_to_back(map: &BspMap, viewpoint: [f32; 3]) -> Vec<usize> {
+    if map.nodes.is_empty() {
+        return (0..map.leafs.len()).collect();
+    }
+
+    let mut leaves = Vec::new();
+    let mut stack = vec![0];
+    while let Some(index) = stack.pop() {
+        if index < 0 {
+            leaves.push((-(index + 1)) as usize);
+            continue;
+        }
+
+        let node = &map.nodes[index as usize];
+        let plane = &map.planes[node.plane as usize];
+        // The far child goes on the stack first so the near one is walked completely before it
+        if dot(viewpoint, plane.normal) - plane.dist >= 0.0 {
+            stack.push(node.children[1]);
+            stack.push(node.children[0]);
+        } else {
+            stack.push(node.children[0]);
+            stack.push(node.children[1]);
+        }
+    }
+    leaves
+}
+
+/// Returns the contents flags of all brushes in the leaf of `point` that contain it, 0 for
+/// empty space.
+pub fn point_contents(map: &BspMap, point: [f32; 3]) -> i32 {
+    let leaf = match map.leafs.get(find_leaf(map, point)) {
+        Some(leaf) => leaf,
+        None => return 0,
+    };
+
+    let mut contents = 0;
+    for i in 0..leaf.n_leafbrushes {
+        let brush = &map.brushes[map.leafbrushes[(leaf.leafbrush + i) as usize] as usize];
+        let inside = brush.n_brushsides > 0 &&
+                     (0..brush.n_brushsides).all(|s| {
+            let side = &map.brushsides[(brush.brushside + s) as usize];
+            let plane = &map.planes[side.plane as usize];
+            dot(point, plane.normal) - plane.dist <= 0.0
+        });
+        if inside {
+            contents |= map.textures[brush.texture as usize].contents;
+        }
+    }
+    contents
+}
+
+/// Moves a sphere of `radius` at `point` out of the solid world brushes it's inside of, each
+/// time through the side of the brush that is closest. Returns `None` if it's still stuck after
+/// `MAX_PUSHES` brushes.
+pub fn push_out_of_solid(map: &BspMap, point: [f32; 3], radius: f32) -> Option<[f32; 3]> {
+    let brushes = match map.models.get(0) {
+        Some(world) => &map.brushes[world.brush as usize..(world.brush + world.n_brushes) as usize],
+        None => return Some(point),
+    };
+
+    let mut point = point;
+    for _ in 0..MAX_PUSHES {
+        let mut push = None;
+        for brush in brushes {
+            let contents = map.textures[brush.texture as usize].contents;
+            if brush.n_brushsides == 0 || contents & CONTENTS_SOLID == 0 {
+                continue;
+            }
+
+            // The side with the shallowest depth, if the point is behind all of them
+            let mut nearest: Option<([f32; 3], f32)> = None;
+            let mut inside = true;
+            for i in 0..brush.n_brushsides {
+                let side = &map.brushsides[(brush.brushside + i) as usize];
+                let plane = &map.planes[side.plane as usize];
+                let depth = plane.dist + radius - dot(point, plane.normal);
+                if depth < 0.0 {
+                    inside = false;
+                    break;
+                }
+                if nearest.map_or(true, |n| depth < n.1) {
+                    nearest = Some((plane.normal, depth));
+                }
+            }
+            if inside {
+                push = nearest;
+                break;
+            }
+        }
+
+        match push {
+            Some((normal, depth)) => {
+                let distance = depth + EPSILON;
+                point = [point[0] + normal[0] * distance,
+                         point[1] + normal[1] * distance,
+                         point[2] + normal[2] * distance];
+            }
+            None => return Some(point),
+        }
+    }
+    None
+}
+
+/// Lists the known flags in `contents`, like "solid, water".
+pub fn describe_contents(contents: i32) -> String {
+    if contents == 0 {
+        return "empty".to_owned();
+    }
+    let names: Vec<&str> = CONTENTS_NAMES.iter()
+                                         .filter(|&&(flag, _)| contents & flag != 0)
+                                         .map(|&(_, name)| name)
+                                         .collect();
+    if names.is_empty() {
+        format!("{:#x}", contents)
+    } else {
+        names.join(", ")
+    }
+}
+
+fn lerp(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+}
+
+impl<'a> TraceWork<'a> {
+    fn check_node(&mut self,
+                  node_index: i32,
+                  start_fraction: f32,
+                  end_fraction: f32,
+                  start: [f32; 3],
+                  end: [f32; 3]) {
+        if self.trace.fraction <= start_fraction {
+            return;
+        }
+
+        let map = self.map;
+
+        if node_index < 0 {
+            let leaf = &map.leafs[(-(node_index + 1)) as usize];
+            for i in 0..leaf.n_leafbrushes {
+                let brush_index = map.leafbrushes[(leaf.leafbrush + i) as usize] as usize;
+                let brush = &map.brushes[brush_index];
+                let contents = map.textures[brush.texture as usize].contents;
+                if brush.n_brushsides > 0 && contents & self.contents != 0 {
+                    self.check_brush(brush, brush_index);
+                }
+            }
+            return;
+        }
+
+        let node = &map.nodes[node_index as usize];
+        let plane = &map.planes[node.plane as usize];
+        let start_distance = dot(start, plane.normal) - plane.dist;
+        let end_distance = dot(end, plane.normal) - plane.dist;
+        let offset = self.radius;
+
+        if start_distance >= offset && end_distance >= offset {
+            self.check_node(node.children[0], start_fraction, end_fraction, start, end);
+        } else if start_distance < -offset && end_distance < -offset {
+            self.check_node(node.children[1], start_fraction, end_fraction, start, end);
+        } else {
+            let (side, fraction1, fraction2) = if start_distance < end_distance {
+                let inverse = 1.0 / (start_distance - end_distance);
+                (1,
+                 (start_distance - offset + EPSILON) * inverse,
+                 (start_distance + offset + EPSILON) * inverse)
+            } else if start_distance > end_distance {
+                let inverse = 1.0 / (start_distance - end_distance);
+                (0,
+                 (start_distance + offset + EPSILON) * inverse,
+                 (start_distance - offset - EPSILON) * inverse)
+            } else {
+                (0, 1.0, 0.0)
+            };
+            let fraction1 = fraction1.max(0.0).min(1.0);
+            let fraction2 = fraction2.max(0.0).min(1.0);
+
+            let middle_fraction = start_fraction + (end_fraction - start_fraction) * fraction1;
+            let middle = lerp(start, end, fraction1);
+            self.check_node(node.children[side], start_fraction, middle_fraction, start, middle);
+
+            let middle_fraction = start_fraction + (end_fraction - start_fraction) * fraction2;
+            let middle = lerp(start, end, fraction2);
+            self.check_node(node.children[1 - side], middle_fraction, end_fraction, middle, end);
+        }
+    }
+
+    fn check_brush(&mut self, brush: &Brush, brush_index: usize) {
+        let mut start_fraction = -1.0;
+        let mut end_fraction = 1.0;
+        let mut starts_out = false;
+        let mut ends_out = false;
+        let mut hit_normal = [0.0, 0.0, 0.0];
+        let map = self.map;
+
+        for i in 0..brush.n_brushsides {
+            let side = &map.brushsides[(brush.brushside + i) as usize];
+            let plane = &map.planes[side.plane as usize];
+
+            let start_distance = dot(self.trace.start, plane.normal) - (plane.dist + self.radius);
+            let end_distance = dot(self.trace.end, plane.normal) - (plane.dist + self.radius);
+
+            if start_distance > 0.0 {
+                starts_out = true;
+            }
+            if end_distance > 0.0 {
+                ends_out = true;
+            }
+
+            if start_distance > 0.0 && end_distance > 0.0 {
+                return;
+            }
+            if start_distance <= 0.0 && end_distance <= 0.0 {
+                continue;
+            }
+
+            if start_distance > end_distance {
+                let fraction = (start_distance - EPSILON) / (start_distance - end_distance);
+                if fraction > start_fraction {
+                    start_fraction = fraction;
+                    hit_normal = plane.normal;
+                }
+            } else {
+                let fraction = (start_distance + EPSILON) / (start_distance - end_distance);
+                if fraction < end_fraction {
+                    end_fraction = fraction;
+                }
+            }
+        }
+
+        if !starts_out {
+            self.trace.start_solid = true;
+            if !ends_out {
+                self.trace.all_solid = true;
+            }
+            self.trace.fraction = 0.0;
+            self.trace.brush = Some(brush_index);
+            return;
+        }
+
+        if start_fraction < end_fraction && start_fraction > -1.0 &&
+           start_fraction < self.trace.fraction {
+            self.trace.fraction = start_fraction.max(0.0);
+            self.trace.normal = hit_normal;
+            self.trace.brush = Some(brush_index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsp_reader::{self, Leaf, Node, Plane};
+
+    /// The room of the default map, its walls are 16 units thick around -256 to 256 on x and y.
+    fn default_map() -> BspMap {
+        bsp_reader::load_bytes(include_bytes!("../assets/default.bsp").to_vec()).unwrap()
+    }
+
+    #[test]
+    fn push_out_of_solid_moves_a_point_in_a_wall_back_into_the_room() {
+        let map = default_map();
+        assert!(point_contents(&map, [260.0, 0.0, 64.0]) & CONTENTS_SOLID != 0);
+
+        // The room side of the wall is nearer than the outside
+        let pushed = push_out_of_solid(&map, [260.0, 0.0, 64.0], 16.0).unwrap();
+        assert!(pushed[0] < 240.0 && pushed[0] > 239.0, "pushed to {:?}", pushed);
+        assert_eq!((pushed[1], pushed[2]), (0.0, 64.0));
+        assert_eq!(point_contents(&map, pushed) & CONTENTS_SOLID, 0);
+    }
+
+    #[test]
+    fn push_out_of_solid_keeps_points_clear_of_the_walls() {
+        let map = default_map();
+        assert_eq!(push_out_of_solid(&map, [0.0, 0.0, 64.0], 16.0), Some([0.0, 0.0, 64.0]));
+    }
+
+    #[test]
+    fn point_contents_reads_the_contents_of_the_brushes_around_the_point() {
+        let mut map = default_map();
+        assert_eq!(point_contents(&map, [0.0, 0.0, 64.0]), 0);
+        assert_eq!(point_contents(&map, [260.0, 0.0, 64.0]), CONTENTS_SOLID);
+
+        // Turning the walls into water
+        for texture in &mut map.textures {
+            texture.contents = CONTENTS_WATER;
+        }
+        assert_eq!(point_contents(&map, [260.0, 0.0, 64.0]), CONTENTS_WATER);
+        assert_eq!(point_contents(&map, [0.0, 0.0, 64.0]), 0);
+    }
+
+    /// The default map split in two by the plane x = 0. Leaf 0 is in front of it, leaf 1 behind.
+    fn split_map() -> BspMap {
+        let mut map = default_map();
+        map.planes.push(Plane {
+            normal: [1.0, 0.0, 0.0],
+            dist: 0.0,
+        });
+        let node = Node {
+            plane: map.planes.len() as i32 - 1,
+            children: [-1, -2],
+            mins: [-272, -272, -80],
+            maxs: [272, 272, 208],
+        };
+        map.nodes = vec![node];
+        let leaf = |cluster: i32, min_x: i32, max_x: i32| {
+            Leaf {
+                cluster: cluster,
+                area: 0,
+                mins: [min_x, -272, -80],
+                maxs: [max_x, 272, 208],
+                leafface: 0,
+                n_leaffaces: 0,
+                leafbrush: 0,
+                n_leafbrushes: 0,
+            }
+        };
+        map.leafs = vec![leaf(0, 0, 272), leaf(1, -272, 0)];
+        map
+    }
+
+    #[test]
+    fn leaves_front_to_back_starts_with_the_leaf_of_the_viewpoint() {
+        let map = split_map();
+        for &viewpoint in &[[100.0, 0.0, 0.0], [-100.0, 50.0, 0.0]] {
+            let order = leaves_front_to_back(&map, viewpoint);
+            assert_eq!(order[0], find_leaf(&map, viewpoint));
+            assert_eq!(order.len(), 2);
+        }
+    }
+
+    #[test]
+    fn leaves_front_to_back_flips_when_the_viewpoint_crosses_the_plane() {
+        let map = split_map();
+        assert_eq!(leaves_front_to_back(&map, [1.0, 0.0, 0.0]), vec![0, 1]);
+        assert_eq!(leaves_front_to_back(&map, [-1.0, 0.0, 0.0]), vec![1, 0]);
+    }
+}
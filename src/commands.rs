@@ -0,0 +1,669 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use bsp_reader::{self, BSPReader, BspMap};
+use collision;
+use coords::UpAxis;
+use entities;
+use export;
+use geometry;
+use pk3::Pk3;
+
+/// Runs a headless command if one is given on the command line and returns its exit code.
+pub fn run(args: &[String]) -> Option<i32> {
+    if args.get(0).map_or(false, |a| a == "convert") {
+        return Some(convert(&args[1..]));
+    }
+    if let Some(path) = flag_value(args, "--list-entities") {
+        return Some(list_entities(path, flag_value(args, "--classname"), has_flag(args, "--all")));
+    }
+
+    if let Some(i) = args.iter().position(|a| a == "--hexdump") {
+        return Some(match (args.get(i + 1), args.get(i + 2)) {
+            (Some(lump), Some(path)) => {
+                let mut length = None;
+                if let Some(value) = flag_value(args, "--length") {
+                    match value.parse() {
+                        Ok(l) => length = Some(l),
+                        Err(_) => println!("Warning: --length expects a number of bytes"),
+                    }
+                }
+                hexdump(lump, path, length)
+            }
+            _ => {
+                println!("Usage: --hexdump <lump> <path> [--length <bytes>]");
+                1
+            }
+        });
+    }
+    if let Some(i) = args.iter().position(|a| a == "--dump-triangle") {
+        return Some(match (args.get(i + 1).and_then(|n| n.parse().ok()), args.get(i + 2)) {
+            (Some(index), Some(path)) => dump_triangle(path, index),
+            _ => {
+                println!("Usage: --dump-triangle <index> <path>");
+                1
+            }
+        });
+    }
+    if let Some(path) = flag_value(args, "--info") {
+        return Some(info(path, has_flag(args, "--json")));
+    }
+    if let Some(path) = flag_value(args, "--validate") {
+        return Some(validate(path));
+    }
+    if let Some(path) = flag_value(args, "--check-spawns") {
+        return Some(check_spawns(path));
+    }
+    if let Some(path) = flag_value(args, "--vis-stats") {
+        return Some(vis_stats(path));
+    }
+    if let Some(path) = flag_value(args, "--faces-csv") {
+        return Some(faces_csv(path));
+    }
+
+    None
+}
+
+fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().any(|a| a == flag)
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter().position(|a| a == flag).and_then(|i| args.get(i + 1)).map(|v| &v[..])
+}
+
+/// Returns the arguments that are neither flags nor the values of `value_flags`.
+fn positional<'a>(args: &'a [String], value_flags: &[&str]) -> Vec<&'a str> {
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if value_flags.contains(&&arg[..]) {
+            args.next();
+        } else if !arg.starts_with("--") {
+            positional.push(&arg[..]);
+        }
+    }
+    positional
+}
+
+fn load(path: &str) -> Option<BspMap> {
+    match bsp_reader::load(path) {
+        Ok(map) => Some(map),
+        Err(error) => {
+            println!("Couldn't load {}: {}", path, error);
+            None
+        }
+    }
+}
+
+/// Loads the map and checks its references, printing every broken one. Collision and geometry
+/// index the lumps with them directly, so broken maps would make them panic.
+fn load_valid(path: &str) -> Option<BspMap> {
+    let map = match load(path) {
+        Some(map) => map,
+        None => return None,
+    };
+    let errors = bsp_reader::validate_references(&map);
+    if errors.is_empty() {
+        return Some(map);
+    }
+    for error in &errors {
+        println!("{}", error);
+    }
+    println!("{}: {} broken references", path, errors.len());
+    None
+}
+
+fn list_entities(path: &str, classname: Option<&str>, all_keys: bool) -> i32 {
+    let map = match load(path) {
+        Some(map) => map,
+        None => return 1,
+    };
+    let entities = entities::parse(&map.entities);
+
+    for entity in &entities {
+        if classname.map_or(false, |c| c != entity.classname()) {
+            continue;
+        }
+
+        let mut line = format!("{}\t{}", entity.classname(), entity.get("origin").unwrap_or("-"));
+        if all_keys {
+            for &(ref key, ref value) in &entity.properties {
+                if key != "classname" && key != "origin" {
+                    line.push_str(&format!("\t{}={}", key, value));
+                }
+            }
+        }
+        println!("{}", line);
+    }
+
+    0
+}
+
+/// Prints the vertexes, face and texture of triangle `index`. Triangles are counted through the
+/// polygon and mesh faces in the order of the face lump, with their winding fixed like the
+/// renderer does. Patches aren't counted since their triangles depend on the tessellation.
+fn dump_triangle(path: &str, index: usize) -> i32 {
+    let map = match load_valid(path) {
+        Some(map) => map,
+        None => return 1,
+    };
+
+    let mut first = 0;
+    for (face_index, face) in map.faces.iter().enumerate() {
+        let indices = match geometry::resolve_face(&map, face, true) {
+            Some((indices, _)) => indices,
+            None => continue,
+        };
+        let count = indices.len() / 3;
+        if index >= first + count {
+            first += count;
+            continue;
+        }
+
+        let texture = map.textures.get(face.texture as usize).map_or("-", |t| &t.name[..]);
+        println!("Triangle {}: face {} (type {}), texture {}",
+                 index,
+                 face_index,
+                 face.f_type,
+                 texture);
+        for &vertex_index in &indices[(index - first) * 3..(index - first) * 3 + 3] {
+            let v = &map.vertexes[vertex_index as usize];
+            println!("  vertex {}: position {:?}, normal {:?}, texcoord {:?}, lightmap {:?}, \
+                      color {:?}",
+                     vertex_index,
+                     v.position,
+                     v.normal,
+                     v.texcoord[0],
+                     v.texcoord[1],
+                     v.color);
+        }
+        return 0;
+    }
+
+    println!("There are only {} triangles in polygon and mesh faces", first);
+    1
+}
+
+/// Writes a CSV row per face to stdout, so it can be redirected into a file. Centroids are
+/// empty for faces without vertexes and the texture is empty if its index is out of range.
+fn faces_csv(path: &str) -> i32 {
+    let map = match load(path) {
+        Some(map) => map,
+        None => return 1,
+    };
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let result = write_faces_csv(&map, &mut out).and_then(|_| out.flush());
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            println!("Couldn't write the CSV: {}", error);
+            1
+        }
+    }
+}
+
+fn write_faces_csv<W: Write>(map: &BspMap, out: &mut W) -> io::Result<()> {
+    try!(writeln!(out,
+                  "face,texture,type,vertexes,meshverts,lm_index,centroid_x,centroid_y,\
+                   centroid_z"));
+    for (i, (face, centroid)) in map.faces.iter().zip(geometry::face_centroids(map)).enumerate() {
+        let texture = map.textures.get(face.texture as usize).map_or("", |t| &t.name[..]);
+        let centroid = match centroid {
+            Some(c) => format!("{},{},{}", c[0], c[1], c[2]),
+            None => ",,".to_owned(),
+        };
+        try!(writeln!(out,
+                      "{},{},{},{},{},{},{}",
+                      i,
+                      csv_field(texture),
+                      face.f_type,
+                      face.n_vertexes,
+                      face.n_meshverts,
+                      face.lm_index,
+                      centroid));
+    }
+    Ok(())
+}
+
+/// Quotes `s` if it contains a separator, quote or line break, doubling quotes inside it.
+fn csv_field(s: &str) -> String {
+    if s.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Spawns deeper than this above the ground count as hanging in the void.
+const MAX_SPAWN_HEIGHT: f32 = 4096.0;
+
+/// Checks that every info_player_* spawn is in open space and has ground below it.
+fn check_spawns(path: &str) -> i32 {
+    let map = match load_valid(path) {
+        Some(map) => map,
+        None => return 1,
+    };
+
+    let mut spawns = 0;
+    let mut invalid = 0;
+    for entity in entities::parse(&map.entities) {
+        if !entity.classname().starts_with("info_player_") {
+            continue;
+        }
+        spawns += 1;
+        let origin = match entity.get_vec3("origin") {
+            Some(origin) => origin,
+            None => {
+                println!("{}: no origin", entity.classname());
+                invalid += 1;
+                continue;
+            }
+        };
+
+        let below = [origin[0], origin[1], origin[2] - MAX_SPAWN_HEIGHT];
+        let status = if collision::point_contents(&map, origin) & collision::CONTENTS_SOLID != 0 {
+            "in solid"
+        } else if !collision::trace_ray(&map, origin, below).hit() {
+            "no ground below"
+        } else {
+            "ok"
+        };
+        if status != "ok" {
+            invalid += 1;
+        }
+        println!("{} at {} {} {}: {}",
+                 entity.classname(),
+                 origin[0],
+                 origin[1],
+                 origin[2],
+                 status);
+    }
+
+    println!("{} spawns, {} invalid", spawns, invalid);
+    if invalid == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+fn vis_stats(path: &str) -> i32 {
+    let map = match load(path) {
+        Some(map) => map,
+        None => return 1,
+    };
+    let visdata = &map.visdata;
+
+    if let Some(problem) = map.vis_problem() {
+        println!("{}: {}, every cluster sees every other one", path, problem);
+        return 0;
+    }
+
+    let clusters = visdata.n_vecs;
+    let mut total = 0;
+    let mut max = (0, 0);
+    for from in 0..clusters {
+        let visible = (0..clusters).filter(|&to| visdata.is_cluster_visible(from, to)).count();
+        total += visible;
+        if visible > max.0 {
+            max = (visible, from);
+        }
+    }
+    let average = total as f32 / clusters as f32;
+
+    println!("Clusters: {}", clusters);
+    println!("PVS size: {} bytes ({} bytes per cluster)",
+             visdata.vecs.len(),
+             visdata.sz_vecs);
+    println!("Visible clusters: {:.1} on average ({:.1}%)",
+             average,
+             average / clusters as f32 * 100.0);
+    println!("Visible clusters: {} at most, from cluster {}", max.0, max.1);
+
+    0
+}
+
+fn validate(path: &str) -> i32 {
+    let map = match load(path) {
+        Some(map) => map,
+        None => return 1,
+    };
+
+    let errors = bsp_reader::validate_references(&map);
+    for error in &errors {
+        println!("{}", error);
+    }
+    if errors.is_empty() {
+        println!("{}: all references are valid", path);
+        0
+    } else {
+        println!("{}: {} broken references", path, errors.len());
+        1
+    }
+}
+
+/// Prints the header and every problem the validators find. The exit code is 0 only if the map
+/// is clean, so this can lint maps in a build.
+fn info(path: &str, json: bool) -> i32 {
+    let mut reader = match BSPReader::open(path) {
+        Ok(reader) => reader,
+        Err(error) => {
+            println!("Couldn't load {}: {}", path, error);
+            return 1;
+        }
+    };
+    let header = match reader.read_header() {
+        Ok(header) => header,
+        Err(error) => {
+            println!("Couldn't load {}: {}", path, error);
+            return 1;
+        }
+    };
+
+    let mut problems: Vec<String> = Vec::new();
+    if let Err(error) = bsp_reader::validate_header(&header) {
+        problems.push(error.to_string());
+    }
+    let direntry_errors = bsp_reader::validate_direntries(&header.direntries, reader.data.len());
+    problems.extend(direntry_errors.iter().map(|e| e.to_string()));
+    // References can only be checked if every lump could be read
+    if problems.is_empty() {
+        match reader.read_map() {
+            Ok(map) => {
+                let errors = bsp_reader::validate_references(&map);
+                problems.extend(errors.iter().map(|e| e.to_string()));
+            }
+            Err(error) => problems.push(error.to_string()),
+        }
+    }
+
+    if json {
+        let mut lumps = Vec::new();
+        for &name in bsp_reader::LUMP_NAMES.iter() {
+            let direntry = header.direntries.by_name(name).unwrap();
+            lumps.push(format!("{{\"name\": \"{}\", \"offset\": {}, \"length\": {}}}",
+                               name,
+                               direntry.offset,
+                               direntry.length));
+        }
+        let problem_strings: Vec<String> = problems.iter().map(|p| json_string(p)).collect();
+        println!("{{\"path\": {}, \"magic\": {}, \"version\": {}, \"lumps\": [{}], \
+                  \"problems\": [{}], \"valid\": {}}}",
+                 json_string(path),
+                 json_string(&header.magic),
+                 header.version,
+                 lumps.join(", "),
+                 problem_strings.join(", "),
+                 problems.is_empty());
+    } else {
+        println!("{}: {} version {}", path, header.magic, header.version);
+        for &name in bsp_reader::LUMP_NAMES.iter() {
+            let direntry = header.direntries.by_name(name).unwrap();
+            println!("  {:<12} offset {:>9}, length {:>9}",
+                     name,
+                     direntry.offset,
+                     direntry.length);
+        }
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        if problems.is_empty() {
+            println!("No problems found");
+        } else {
+            println!("{} problems found", problems.len());
+        }
+    }
+
+    if problems.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn hexdump(lump: &str, path: &str, length: Option<usize>) -> i32 {
+    let mut reader = match BSPReader::open(path) {
+        Ok(reader) => reader,
+        Err(error) => {
+            println!("Couldn't load {}: {}", path, error);
+            return 1;
+        }
+    };
+    let header = match reader.read_header() {
+        Ok(header) => header,
+        Err(error) => {
+            println!("Couldn't load {}: {}", path, error);
+            return 1;
+        }
+    };
+    let direntry = match header.direntries.by_name(lump) {
+        Some(direntry) => direntry,
+        None => {
+            println!("Unknown lump {}, expected one of {}",
+                     lump,
+                     bsp_reader::LUMP_NAMES.join(", "));
+            return 1;
+        }
+    };
+    let bytes = match reader.read_raw_lump(direntry) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            println!("Couldn't read lump {}: {}", lump, error);
+            return 1;
+        }
+    };
+
+    let shown = &bytes[..length.unwrap_or(bytes.len()).min(bytes.len())];
+    for (i, line) in shown.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line.iter()
+                                .map(|&b| if b >= 0x20 && b < 0x7f { b as char } else { '.' })
+                                .collect();
+        println!("{:08x}  {:<47}  {}", direntry.offset as usize + i * 16, hex.join(" "), ascii);
+    }
+    if shown.len() < bytes.len() {
+        println!("... {} more bytes", bytes.len() - shown.len());
+    }
+
+    0
+}
+
+fn convert(args: &[String]) -> i32 {
+    let format = flag_value(args, "--format").unwrap_or("obj");
+    if format != "obj" {
+        println!("Unsupported format {}, only obj is supported", format);
+        return 1;
+    }
+    let mut tessellation = 5;
+    if let Some(value) = flag_value(args, "--tessellation") {
+        match value.parse() {
+            Ok(level) if level > 0 => tessellation = level,
+            _ => println!("Warning: --tessellation expects a positive number"),
+        }
+    }
+    let group_planes = args.iter().any(|a| a == "--group-planes");
+    let up = match flag_value(args, "--up").map(UpAxis::from_name) {
+        Some(Some(up)) => up,
+        Some(None) => {
+            println!("--up expects y or z");
+            return 1;
+        }
+        None => UpAxis::Y,
+    };
+
+    let paths = positional(args, &["--format", "--tessellation", "--up"]);
+    if paths.len() != 2 {
+        println!("Usage: convert [--format obj] [--tessellation <level>] [--group-planes] \
+                  [--up y|z] <input dir> <output dir>");
+        return 1;
+    }
+    let input = Path::new(paths[0]);
+    let output = Path::new(paths[1]);
+
+    let mut files = Vec::new();
+    let mut archives = Vec::new();
+    if let Err(error) = find_maps(input, &mut files, &mut archives) {
+        println!("Couldn't read {}: {}", input.display(), error);
+        return 1;
+    }
+    files.sort();
+    archives.sort();
+
+    let mut converted = 0;
+    let mut failed = 0;
+    {
+        // Prints where a map went, or why it couldn't be converted
+        let mut report = |name: String, result: Result<PathBuf, String>| {
+            match result {
+                Ok(target) => {
+                    println!("{} -> {}", name, target.display());
+                    converted += 1;
+                }
+                Err(error) => {
+                    println!("Couldn't convert {}: {}", name, error);
+                    failed += 1;
+                }
+            }
+        };
+        for file in &files {
+            let target = output.join(file.strip_prefix(input).unwrap_or(file))
+                               .with_extension(format);
+            let result = bsp_reader::load(&file.to_string_lossy())
+                             .map_err(|e| e.to_string())
+                             .and_then(|map| {
+                                 convert_map(&map, &target, tessellation, group_planes, up)
+                             });
+            report(file.display().to_string(), result.map(|_| target));
+        }
+        // Maps in an archive go to a directory named after it, archives often share map names
+        for path in &archives {
+            let mut archive = match Pk3::open(path) {
+                Ok(archive) => archive,
+                Err(error) => {
+                    report(path.display().to_string(), Err(error.to_string()));
+                    continue;
+                }
+            };
+            let directory = output.join(path.strip_prefix(input).unwrap_or(path))
+                                  .with_extension("");
+            for entry in archive.maps() {
+                let target = directory.join(&entry.name).with_extension(format);
+                let result = archive.read(&entry)
+                                    .map_err(|e| e.to_string())
+                                    .and_then(|data| {
+                                        bsp_reader::load_bytes(data).map_err(|e| e.to_string())
+                                    })
+                                    .and_then(|map| {
+                                        convert_map(&map, &target, tessellation, group_planes, up)
+                                    });
+                report(format!("{}:{}", path.display(), entry.name),
+                       result.map(|_| target));
+            }
+        }
+    }
+
+    println!("Converted {} maps, {} failed", converted, failed);
+    if failed == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+fn convert_map(map: &BspMap,
+               target: &Path,
+               tessellation: usize,
+               group_planes: bool,
+               up: UpAxis)
+               -> Result<(), String> {
+    let errors = bsp_reader::validate_references(map);
+    if !errors.is_empty() {
+        return Err(format!("{} broken references, the first is {}", errors.len(), errors[0]));
+    }
+
+    if let Some(directory) = target.parent() {
+        try!(fs::create_dir_all(directory).map_err(|e| e.to_string()));
+    }
+    let mut file = BufWriter::new(try!(File::create(target).map_err(|e| e.to_string())));
+    export::write_obj(map, &mut file, tessellation, group_planes, up).map_err(|e| e.to_string())
+}
+
+/// Collects the .bsp files and .pk3 archives in `directory` and its subdirectories.
+fn find_maps(directory: &Path,
+             files: &mut Vec<PathBuf>,
+             archives: &mut Vec<PathBuf>)
+             -> io::Result<()> {
+    for entry in try!(fs::read_dir(directory)) {
+        let path = try!(entry).path();
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        if path.is_dir() {
+            try!(find_maps(&path, files, archives));
+        } else if extension.as_ref().map_or(false, |e| e == "bsp") {
+            files.push(path);
+        } else if extension.as_ref().map_or(false, |e| e == "pk3") {
+            archives.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn find_maps_collects_maps_and_archives_in_subdirectories() {
+        let directory = env::temp_dir().join("guac_find_maps_test");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(directory.join("baseq3/maps")).unwrap();
+        for file in &["top.bsp", "baseq3/pak0.pk3", "baseq3/PAK1.PK3", "baseq3/maps/q3dm1.BSP",
+                      "baseq3/maps/q3dm1.aas", "notes.txt"] {
+            File::create(directory.join(file)).unwrap();
+        }
+
+        let mut files = Vec::new();
+        let mut archives = Vec::new();
+        find_maps(&directory, &mut files, &mut archives).unwrap();
+        files.sort();
+        archives.sort();
+        let relative = |paths: &[PathBuf]| -> Vec<PathBuf> {
+            paths.iter().map(|p| p.strip_prefix(&directory).unwrap().to_path_buf()).collect()
+        };
+        assert_eq!(relative(&files),
+                   vec![Path::new("baseq3/maps/q3dm1.BSP"), Path::new("top.bsp")]);
+        assert_eq!(relative(&archives),
+                   vec![Path::new("baseq3/PAK1.PK3"), Path::new("baseq3/pak0.pk3")]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn find_maps_fails_for_a_missing_directory() {
+        let missing = env::temp_dir().join("guac_find_maps_test_missing");
+        let mut files = Vec::new();
+        let mut archives = Vec::new();
+        assert!(find_maps(&missing, &mut files, &mut archives).is_err());
+    }
+}
@@ -0,0 +1,959 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use glium::glutin::VirtualKeyCode;
+use toml;
+
+pub struct CameraConfig {
+    pub fov: f32,
+    pub speed: f32,
+    /// Speed factors while the slow or sprint key is held. Slow also scales the sensitivity.
+    pub slow_multiplier: f32,
+    pub sprint_multiplier: f32,
+    /// Degrees per pixel of mouse movement, horizontally and vertically.
+    pub sensitivity_x: f32,
+    pub sensitivity_y: f32,
+    /// Moving the mouse up looks down.
+    pub invert_y: bool,
+    /// Accelerate and slow down the camera instead of moving at full speed while a key is held.
+    pub smooth_movement: bool,
+    /// Quake 3 defaults for noclip, in units per second per second relative to the speed.
+    pub acceleration: f32,
+    pub friction: f32,
+    /// Time constant in seconds of the view following the camera, 0 follows it right away.
+    pub smoothing: f32,
+    pub near: f32,
+    pub far: f32,
+    /// Initial camera position in Quake 3 coordinates.
+    pub start: Option<[f32; 3]>,
+    /// Initial pitch and yaw in degrees.
+    pub start_angles: Option<(f32, f32)>,
+}
+
+pub struct Controls {
+    pub forward: VirtualKeyCode,
+    pub back: VirtualKeyCode,
+    pub left: VirtualKeyCode,
+    pub right: VirtualKeyCode,
+    pub render_mode: VirtualKeyCode,
+    pub next_texture: VirtualKeyCode,
+    pub previous_texture: VirtualKeyCode,
+    pub trace: VirtualKeyCode,
+    pub crosshair: VirtualKeyCode,
+    pub normals: VirtualKeyCode,
+    pub print_position: VirtualKeyCode,
+    pub next_model: VirtualKeyCode,
+    pub previous_model: VirtualKeyCode,
+    pub console: VirtualKeyCode,
+    pub freeze_culling: VirtualKeyCode,
+    pub jump: VirtualKeyCode,
+    pub viewmodel: VirtualKeyCode,
+    pub minimap: VirtualKeyCode,
+    pub gizmo: VirtualKeyCode,
+    pub volumes: VirtualKeyCode,
+    pub labels: VirtualKeyCode,
+    pub record_path: VirtualKeyCode,
+    pub play_path: VirtualKeyCode,
+    pub leaf_bounds: VirtualKeyCode,
+    pub slow: VirtualKeyCode,
+    pub sprint: VirtualKeyCode,
+    pub inspect: VirtualKeyCode,
+    pub reload_shaders: VirtualKeyCode,
+    pub depth_write: VirtualKeyCode,
+    pub depth_test: VirtualKeyCode,
+    pub profiler: VirtualKeyCode,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CrosshairStyle {
+    Cross,
+    Dot,
+}
+
+pub struct RenderConfig {
+    pub width: u32,
+    pub height: u32,
+    pub vsync: bool,
+    pub linear_vertex_colors: bool,
+    pub fix_winding: bool,
+    /// Don't draw the back of faces, except for shaders whose script sets `cull none`.
+    pub backface_culling: bool,
+    /// Triangles with a smaller area in square units are dropped when loading, 0 keeps all.
+    pub min_triangle_area: f32,
+    pub data_path: String,
+    pub only_texture: Option<String>,
+    /// Directory to read the shaders from instead of using the ones built into the binary.
+    pub shader_path: Option<String>,
+    pub crosshair: bool,
+    pub crosshair_style: CrosshairStyle,
+    pub viewmodel: bool,
+    /// How far the viewmodel bobs while moving at full speed, in units.
+    pub viewmodel_bob: f32,
+    /// Show a top down map in the upper right corner, `minimap_size` pixels wide and high.
+    pub minimap: bool,
+    pub minimap_size: u32,
+    /// MSAA samples for the window, 0 disables it. Blitting the dynamic resolution framebuffer
+    /// into a multisampled window isn't allowed, so dynamic resolution turns MSAA off.
+    pub samples: u16,
+    /// Sort the opaque draws front to back every frame.
+    pub sort_draws: bool,
+    /// Draw the world into the depth buffer first and shade it in a second pass.
+    pub depth_prepass: bool,
+    /// Split batches by cluster and hide clusters whose bounds fail a GPU occlusion query.
+    pub occlusion_queries: bool,
+    /// Hide clusters in areas that closed areaportals seal off from the camera.
+    pub area_portals: bool,
+    /// Batches whose bounding sphere covers fewer pixels than this aren't drawn, 0 draws all.
+    pub min_screen_size: f32,
+    /// Color of the void, overrides _color on the worldspawn.
+    pub clear_color: Option<[f32; 3]>,
+    /// Overrides the fog of the map, density 0 disables it.
+    pub fog_color: Option<[f32; 3]>,
+    pub fog_density: Option<f32>,
+    /// Fog brushes take their color from the fogparms of their shader, which aren't read from
+    /// the scripts, so surfaces in fog volumes all use this color.
+    pub fog_volume_color: [f32; 3],
+    pub fog_volume_density: f32,
+    /// Draw faces with water contents last, tinted, see-through and with their texture warped
+    /// over time. Off draws them like any other face.
+    pub water: bool,
+    pub water_tint: [f32; 3],
+    /// Opacity of water surfaces between 0 and 1.
+    pub water_opacity: f32,
+    /// Texture coordinates of water move by up to this much, like tcMod turb.
+    pub water_warp: f32,
+    /// Warp cycles per second.
+    pub water_warp_speed: f32,
+    /// Lightmap texcoords have v = 0 at the first row of the lightmap, like Quake 3 textures. The
+    /// lightmaps are uploaded first row first and OpenGL samples that row at v = 0, so no flip is
+    /// needed. This is only for lightmaps from tools that store their rows bottom up.
+    pub flip_lightmap_v: bool,
+    /// Multiply lit surfaces by their vertex color as well. The lightmaps aren't overbright
+    /// scaled, so the product is darker than in the game, where the lightmap is doubled first.
+    pub modulate_vertex_colors: bool,
+    pub dynamic_resolution: bool,
+    pub min_scale: f32,
+    pub max_scale: f32,
+    /// Frame time in milliseconds that dynamic resolution tries to stay under.
+    pub target_frame_time: f32,
+}
+
+/// Player movement constants in Quake 3 units, defaulting to the values of the original game.
+pub struct PhysicsConfig {
+    pub player_mins: [f32; 3],
+    pub player_maxs: [f32; 3],
+    pub step_height: f32,
+    pub slide_iterations: u32,
+    pub overclip: f32,
+    pub gravity: f32,
+    pub jump_velocity: f32,
+    /// Walking speed the movement keys accelerate towards.
+    pub max_speed: f32,
+    /// Acceleration on the ground and in the air, relative to `max_speed` per second. A high
+    /// air_accelerate gives CPMA like air control.
+    pub accelerate: f32,
+    pub air_accelerate: f32,
+    pub friction: f32,
+    /// Below this speed friction acts as if the player moved this fast.
+    pub stop_speed: f32,
+}
+
+pub struct Config {
+    pub map: String,
+    pub camera: CameraConfig,
+    pub controls: Controls,
+    pub render: RenderConfig,
+    pub physics: PhysicsConfig,
+    /// Fly a loop through the map with and without culling, print the difference and exit. If no
+    /// culling method is configured, occlusion queries and areaportals are benchmarked.
+    pub cull_benchmark: bool,
+    /// Fly the same loop with and without front to back sorting, print the difference and exit.
+    pub sort_benchmark: bool,
+}
+
+impl Default for CameraConfig {
+    fn default() -> CameraConfig {
+        CameraConfig {
+            fov: 45.0,
+            speed: 500.0,
+            slow_multiplier: 0.1,
+            sprint_multiplier: 2.0,
+            sensitivity_x: 0.6,
+            sensitivity_y: 0.6,
+            invert_y: false,
+            smooth_movement: false,
+            acceleration: 10.0,
+            friction: 9.0,
+            smoothing: 0.0,
+            near: 1.0,
+            far: 10000.0,
+            start: None,
+            start_angles: None,
+        }
+    }
+}
+
+impl CameraConfig {
+    /// Checks the values that go into the projection matrix.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.near <= 0.0 || self.far <= self.near {
+            return Err("near and far must satisfy 0 < near < far".to_owned());
+        }
+        if self.fov <= 0.0 || self.fov >= 180.0 {
+            return Err("fov must be between 0 and 180 degrees".to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Default for Controls {
+    fn default() -> Controls {
+        Controls {
+            forward: VirtualKeyCode::W,
+            back: VirtualKeyCode::S,
+            left: VirtualKeyCode::A,
+            right: VirtualKeyCode::D,
+            render_mode: VirtualKeyCode::M,
+            next_texture: VirtualKeyCode::RBracket,
+            previous_texture: VirtualKeyCode::LBracket,
+            trace: VirtualKeyCode::T,
+            crosshair: VirtualKeyCode::C,
+            normals: VirtualKeyCode::N,
+            print_position: VirtualKeyCode::P,
+            next_model: VirtualKeyCode::Period,
+            previous_model: VirtualKeyCode::Comma,
+            console: VirtualKeyCode::Grave,
+            freeze_culling: VirtualKeyCode::F,
+            jump: VirtualKeyCode::Space,
+            viewmodel: VirtualKeyCode::V,
+            minimap: VirtualKeyCode::Tab,
+            gizmo: VirtualKeyCode::O,
+            volumes: VirtualKeyCode::L,
+            labels: VirtualKeyCode::K,
+            record_path: VirtualKeyCode::R,
+            play_path: VirtualKeyCode::G,
+            leaf_bounds: VirtualKeyCode::B,
+            inspect: VirtualKeyCode::I,
+            slow: VirtualKeyCode::LControl,
+            sprint: VirtualKeyCode::LShift,
+            reload_shaders: VirtualKeyCode::F6,
+            depth_write: VirtualKeyCode::F7,
+            depth_test: VirtualKeyCode::F8,
+            profiler: VirtualKeyCode::F9,
+        }
+    }
+}
+
+impl Default for RenderConfig {
+    fn default() -> RenderConfig {
+        RenderConfig {
+            width: 1280,
+            height: 720,
+            vsync: false,
+            linear_vertex_colors: true,
+            fix_winding: true,
+            backface_culling: false,
+            min_triangle_area: 0.001,
+            data_path: "data".to_owned(),
+            only_texture: None,
+            shader_path: None,
+            crosshair: true,
+            crosshair_style: CrosshairStyle::Cross,
+            viewmodel: false,
+            viewmodel_bob: 1.5,
+            minimap: false,
+            minimap_size: 256,
+            samples: 0,
+            sort_draws: true,
+            depth_prepass: false,
+            occlusion_queries: false,
+            area_portals: false,
+            min_screen_size: 0.0,
+            clear_color: None,
+            fog_color: None,
+            fog_density: None,
+            fog_volume_color: [0.5, 0.4, 0.3],
+            fog_volume_density: 0.002,
+            water: true,
+            water_tint: [0.6, 0.8, 1.0],
+            water_opacity: 0.6,
+            water_warp: 0.03,
+            water_warp_speed: 0.5,
+            flip_lightmap_v: false,
+            modulate_vertex_colors: false,
+            dynamic_resolution: false,
+            min_scale: 0.5,
+            max_scale: 1.0,
+            target_frame_time: 16.7,
+        }
+    }
+}
+
+impl RenderConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_scale <= 0.0 || self.min_scale > self.max_scale || self.max_scale > 1.0 {
+            return Err("scales must satisfy 0 < min_scale <= max_scale <= 1".to_owned());
+        }
+        if self.target_frame_time <= 0.0 {
+            return Err("target_frame_time must be positive".to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> PhysicsConfig {
+        PhysicsConfig {
+            player_mins: [-15.0, -15.0, -24.0],
+            player_maxs: [15.0, 15.0, 32.0],
+            step_height: 18.0,
+            slide_iterations: 4,
+            overclip: 1.001,
+            gravity: 800.0,
+            jump_velocity: 270.0,
+            max_speed: 320.0,
+            accelerate: 10.0,
+            air_accelerate: 1.0,
+            friction: 6.0,
+            stop_speed: 100.0,
+        }
+    }
+}
+
+impl PhysicsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for axis in 0..3 {
+            if self.player_mins[axis] >= self.player_maxs[axis] {
+                return Err(format!("player_mins[{}] must be less than player_maxs[{}]", axis, axis));
+            }
+        }
+        if self.step_height < 0.0 || self.step_height >= self.player_maxs[2] - self.player_mins[2] {
+            return Err("step_height must be between 0 and the player height".to_owned());
+        }
+        if self.slide_iterations == 0 {
+            return Err("slide_iterations must be at least 1".to_owned());
+        }
+        if self.overclip < 1.0 {
+            return Err("overclip must be at least 1.0".to_owned());
+        }
+        if self.gravity < 0.0 || self.jump_velocity < 0.0 {
+            return Err("gravity and jump_velocity can't be negative".to_owned());
+        }
+        if self.max_speed <= 0.0 || self.accelerate < 0.0 || self.air_accelerate < 0.0 ||
+           self.friction < 0.0 || self.stop_speed < 0.0 {
+            return Err("max_speed must be positive and movement constants can't be negative"
+                           .to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            map: "data/maps/q3dm0.bsp".to_owned(),
+            camera: CameraConfig::default(),
+            controls: Controls::default(),
+            render: RenderConfig::default(),
+            physics: PhysicsConfig::default(),
+            cull_benchmark: false,
+            sort_benchmark: false,
+        }
+    }
+}
+
+impl Config {
+    /// Loads `guac.toml` (or the file given with `--config`) and applies the remaining command
+    /// line flags on top of it.
+    pub fn from_args(args: &[String]) -> Config {
+        let mut config = Config::default();
+        match args.iter().position(|a| a == "--config") {
+            Some(i) if i + 1 < args.len() => {
+                let path = Path::new(&args[i + 1]);
+                if !path.exists() {
+                    println!("Warning: {} not found, using defaults", path.display());
+                }
+                config.load(path);
+            }
+            _ => config.load(&default_path()),
+        }
+        // Like fs_basepath in the game, for finding maps and textures without a config file
+        if let Ok(base_path) = env::var("GUAC_BASEPATH") {
+            config.render.data_path = base_path;
+        }
+        config.apply_args(args);
+        // Checked after both the file and the arguments, either one can set each value
+        if let Err(error) = config.camera.validate() {
+            println!("Warning: invalid camera settings ({}), using defaults", error);
+            let defaults = CameraConfig::default();
+            config.camera.fov = defaults.fov;
+            config.camera.near = defaults.near;
+            config.camera.far = defaults.far;
+        }
+        config.map = resolve_map(&config.map, &config.render.data_path);
+        // Every culling method but the PVS is off by default, which would leave little to compare
+        if config.cull_benchmark && !config.render.occlusion_queries &&
+           !config.render.area_portals && config.render.min_screen_size <= 0.0 {
+            println!("Notice: no culling method is on, benchmarking occlusion queries and \
+                      areaportals");
+            config.render.occlusion_queries = true;
+            config.render.area_portals = true;
+        }
+        if config.sort_benchmark {
+            if config.cull_benchmark {
+                println!("Warning: only one benchmark can run at a time, running the culling one");
+            }
+            config.render.sort_draws = true;
+        }
+        config.check_samples();
+        if config.render.samples > 0 && config.render.dynamic_resolution {
+            println!("Warning: MSAA doesn't work together with dynamic resolution, disabling it");
+            config.render.samples = 0;
+        }
+        config
+    }
+
+    pub fn load(&mut self, path: &Path) {
+        let mut source = String::new();
+        match File::open(path).and_then(|mut f| f.read_to_string(&mut source)) {
+            Ok(_) => {}
+            Err(_) => return,
+        }
+
+        let mut parser = toml::Parser::new(&source);
+        let table = match parser.parse() {
+            Some(table) => toml::Value::Table(table),
+            None => {
+                for error in &parser.errors {
+                    println!("Warning: {}: {}", path.display(), error);
+                }
+                println!("Warning: ignoring {}, using defaults", path.display());
+                return;
+            }
+        };
+
+        let float = |key: &str, value: &mut f32| {
+            match table.lookup(key) {
+                Some(&toml::Value::Float(f)) => *value = f as f32,
+                Some(&toml::Value::Integer(i)) => *value = i as f32,
+                Some(_) => println!("Warning: {} should be a number", key),
+                None => {}
+            }
+        };
+        float("camera.fov", &mut self.camera.fov);
+        float("camera.speed", &mut self.camera.speed);
+        float("camera.slow_multiplier", &mut self.camera.slow_multiplier);
+        float("camera.sprint_multiplier", &mut self.camera.sprint_multiplier);
+        // sensitivity sets both axes, the per axis keys override it
+        float("camera.sensitivity", &mut self.camera.sensitivity_x);
+        float("camera.sensitivity", &mut self.camera.sensitivity_y);
+        float("camera.sensitivity_x", &mut self.camera.sensitivity_x);
+        float("camera.sensitivity_y", &mut self.camera.sensitivity_y);
+        float("camera.acceleration", &mut self.camera.acceleration);
+        float("camera.friction", &mut self.camera.friction);
+        float("camera.smoothing", &mut self.camera.smoothing);
+        float("camera.near", &mut self.camera.near);
+        float("camera.far", &mut self.camera.far);
+        float("render.viewmodel_bob", &mut self.render.viewmodel_bob);
+        float("render.min_triangle_area", &mut self.render.min_triangle_area);
+        float("render.min_screen_size", &mut self.render.min_screen_size);
+        float("render.min_scale", &mut self.render.min_scale);
+        float("render.max_scale", &mut self.render.max_scale);
+        float("render.target_frame_time", &mut self.render.target_frame_time);
+        float("physics.step_height", &mut self.physics.step_height);
+        float("physics.overclip", &mut self.physics.overclip);
+        float("physics.gravity", &mut self.physics.gravity);
+        float("physics.jump_velocity", &mut self.physics.jump_velocity);
+        float("physics.max_speed", &mut self.physics.max_speed);
+        float("physics.accelerate", &mut self.physics.accelerate);
+        float("physics.air_accelerate", &mut self.physics.air_accelerate);
+        float("physics.friction", &mut self.physics.friction);
+        float("physics.stop_speed", &mut self.physics.stop_speed);
+
+        let vector = |key: &str, value: &mut [f32; 3]| {
+            match table.lookup(key).and_then(|v| v.as_slice()) {
+                Some(array) => {
+                    let numbers: Vec<f32> = array.iter()
+                                                 .filter_map(|v| {
+                                                     v.as_float()
+                                                      .or(v.as_integer().map(|i| i as f64))
+                                                 })
+                                                 .map(|f| f as f32)
+                                                 .collect();
+                    if numbers.len() == 3 {
+                        *value = [numbers[0], numbers[1], numbers[2]];
+                    } else {
+                        println!("Warning: {} should be an array of 3 numbers", key);
+                    }
+                }
+                None => {}
+            }
+        };
+        if table.lookup("camera.start").is_some() {
+            let mut start = [0.0; 3];
+            vector("camera.start", &mut start);
+            self.camera.start = Some(start);
+        }
+        if table.lookup("render.clear_color").is_some() {
+            let mut color = [0.0; 3];
+            vector("render.clear_color", &mut color);
+            self.render.clear_color = Some(color);
+        }
+        if table.lookup("render.fog_color").is_some() {
+            let mut color = [0.0; 3];
+            vector("render.fog_color", &mut color);
+            self.render.fog_color = Some(color);
+        }
+        if table.lookup("render.fog_density").is_some() {
+            let mut density = 0.0;
+            float("render.fog_density", &mut density);
+            self.render.fog_density = Some(density);
+        }
+        vector("render.fog_volume_color", &mut self.render.fog_volume_color);
+        float("render.fog_volume_density", &mut self.render.fog_volume_density);
+        if let Some(water) = table.lookup("render.water").and_then(|v| v.as_bool()) {
+            self.render.water = water;
+        }
+        vector("render.water_tint", &mut self.render.water_tint);
+        float("render.water_opacity", &mut self.render.water_opacity);
+        float("render.water_warp", &mut self.render.water_warp);
+        float("render.water_warp_speed", &mut self.render.water_warp_speed);
+        vector("physics.player_mins", &mut self.physics.player_mins);
+        vector("physics.player_maxs", &mut self.physics.player_maxs);
+
+        let key = |name: &str, value: &mut VirtualKeyCode| {
+            match table.lookup(name).and_then(|v| v.as_str()) {
+                Some(s) => {
+                    match key_from_name(s) {
+                        Some(k) => *value = k,
+                        None => println!("Warning: unknown key \"{}\" for {}", s, name),
+                    }
+                }
+                None => {}
+            }
+        };
+        key("controls.forward", &mut self.controls.forward);
+        key("controls.back", &mut self.controls.back);
+        key("controls.left", &mut self.controls.left);
+        key("controls.right", &mut self.controls.right);
+        key("controls.render_mode", &mut self.controls.render_mode);
+        key("controls.next_texture", &mut self.controls.next_texture);
+        key("controls.previous_texture", &mut self.controls.previous_texture);
+        key("controls.trace", &mut self.controls.trace);
+        key("controls.crosshair", &mut self.controls.crosshair);
+        key("controls.normals", &mut self.controls.normals);
+        key("controls.print_position", &mut self.controls.print_position);
+        key("controls.next_model", &mut self.controls.next_model);
+        key("controls.previous_model", &mut self.controls.previous_model);
+        key("controls.console", &mut self.controls.console);
+        key("controls.freeze_culling", &mut self.controls.freeze_culling);
+        key("controls.jump", &mut self.controls.jump);
+        key("controls.viewmodel", &mut self.controls.viewmodel);
+        key("controls.minimap", &mut self.controls.minimap);
+        key("controls.gizmo", &mut self.controls.gizmo);
+        key("controls.volumes", &mut self.controls.volumes);
+        key("controls.labels", &mut self.controls.labels);
+        key("controls.record_path", &mut self.controls.record_path);
+        key("controls.play_path", &mut self.controls.play_path);
+        key("controls.leaf_bounds", &mut self.controls.leaf_bounds);
+        key("controls.inspect", &mut self.controls.inspect);
+        key("controls.slow", &mut self.controls.slow);
+        key("controls.sprint", &mut self.controls.sprint);
+        key("controls.reload_shaders", &mut self.controls.reload_shaders);
+        key("controls.depth_write", &mut self.controls.depth_write);
+        key("controls.depth_test", &mut self.controls.depth_test);
+        key("controls.profiler", &mut self.controls.profiler);
+
+        if let Some(invert) = table.lookup("camera.invert_y").and_then(|v| v.as_bool()) {
+            self.camera.invert_y = invert;
+        }
+        if let Some(smooth) = table.lookup("camera.smooth_movement").and_then(|v| v.as_bool()) {
+            self.camera.smooth_movement = smooth;
+        }
+        if let Some(width) = table.lookup("render.width").and_then(|v| v.as_integer()) {
+            if width > 0 {
+                self.render.width = width as u32;
+            } else {
+                println!("Warning: render.width must be positive");
+            }
+        }
+        if let Some(height) = table.lookup("render.height").and_then(|v| v.as_integer()) {
+            if height > 0 {
+                self.render.height = height as u32;
+            } else {
+                println!("Warning: render.height must be positive");
+            }
+        }
+        if let Some(minimap) = table.lookup("render.minimap").and_then(|v| v.as_bool()) {
+            self.render.minimap = minimap;
+        }
+        if let Some(size) = table.lookup("render.minimap_size").and_then(|v| v.as_integer()) {
+            if size > 0 {
+                self.render.minimap_size = size as u32;
+            } else {
+                println!("Warning: render.minimap_size must be positive");
+            }
+        }
+        if let Some(vsync) = table.lookup("render.vsync").and_then(|v| v.as_bool()) {
+            self.render.vsync = vsync;
+        }
+        if let Some(linear) = table.lookup("render.linear_vertex_colors").and_then(|v| v.as_bool()) {
+            self.render.linear_vertex_colors = linear;
+        }
+        if let Some(fix) = table.lookup("render.fix_winding").and_then(|v| v.as_bool()) {
+            self.render.fix_winding = fix;
+        }
+        if let Some(culling) = table.lookup("render.backface_culling").and_then(|v| v.as_bool()) {
+            self.render.backface_culling = culling;
+        }
+        if let Some(crosshair) = table.lookup("render.crosshair").and_then(|v| v.as_bool()) {
+            self.render.crosshair = crosshair;
+        }
+        if let Some(viewmodel) = table.lookup("render.viewmodel").and_then(|v| v.as_bool()) {
+            self.render.viewmodel = viewmodel;
+        }
+        if let Some(flip) = table.lookup("render.flip_lightmap_v").and_then(|v| v.as_bool()) {
+            self.render.flip_lightmap_v = flip;
+        }
+        if let Some(modulate) = table.lookup("render.modulate_vertex_colors")
+                                     .and_then(|v| v.as_bool()) {
+            self.render.modulate_vertex_colors = modulate;
+        }
+        if let Some(portals) = table.lookup("render.area_portals").and_then(|v| v.as_bool()) {
+            self.render.area_portals = portals;
+        }
+        if let Some(prepass) = table.lookup("render.depth_prepass").and_then(|v| v.as_bool()) {
+            self.render.depth_prepass = prepass;
+        }
+        if let Some(sort) = table.lookup("render.sort_draws").and_then(|v| v.as_bool()) {
+            self.render.sort_draws = sort;
+        }
+        if let Some(occlusion) = table.lookup("render.occlusion_queries").and_then(|v| v.as_bool()) {
+            self.render.occlusion_queries = occlusion;
+        }
+        if let Some(samples) = table.lookup("render.samples").and_then(|v| v.as_integer()) {
+            self.render.samples = samples as u16;
+        }
+        if let Some(dynamic) = table.lookup("render.dynamic_resolution").and_then(|v| v.as_bool()) {
+            self.render.dynamic_resolution = dynamic;
+        }
+        match table.lookup("render.crosshair_style").and_then(|v| v.as_str()) {
+            Some("cross") => self.render.crosshair_style = CrosshairStyle::Cross,
+            Some("dot") => self.render.crosshair_style = CrosshairStyle::Dot,
+            Some(style) => println!("Warning: unknown crosshair style \"{}\"", style),
+            None => {}
+        }
+        if let Some(data_path) = table.lookup("render.data_path").and_then(|v| v.as_str()) {
+            self.render.data_path = data_path.to_owned();
+        }
+        if let Some(shader_path) = table.lookup("render.shader_path").and_then(|v| v.as_str()) {
+            self.render.shader_path = Some(shader_path.to_owned());
+        }
+        if let Some(iterations) = table.lookup("physics.slide_iterations")
+                                      .and_then(|v| v.as_integer()) {
+            // Checked before the conversion, a negative count would wrap around to billions
+            if iterations > 0 {
+                self.physics.slide_iterations = iterations as u32;
+            } else {
+                println!("Warning: physics.slide_iterations must be at least 1, ignoring {}",
+                         iterations);
+            }
+        }
+        if let Some(map) = table.lookup("map").and_then(|v| v.as_str()) {
+            self.map = map.to_owned();
+        }
+
+        self.check_samples();
+        if self.camera.slow_multiplier <= 0.0 || self.camera.sprint_multiplier <= 0.0 {
+            println!("Warning: speed multipliers must be positive, using defaults");
+            let defaults = CameraConfig::default();
+            self.camera.slow_multiplier = defaults.slow_multiplier;
+            self.camera.sprint_multiplier = defaults.sprint_multiplier;
+        }
+        self.render.clear_color = self.render
+                                      .clear_color
+                                      .and_then(|c| check_color("clear_color", c));
+        if self.render.water_opacity < 0.0 || self.render.water_opacity > 1.0 {
+            println!("Warning: water_opacity must be between 0 and 1, using the default");
+            self.render.water_opacity = RenderConfig::default().water_opacity;
+        }
+        if let Err(error) = self.render.validate() {
+            println!("Warning: invalid dynamic resolution settings ({}), using defaults", error);
+            let defaults = RenderConfig::default();
+            self.render.min_scale = defaults.min_scale;
+            self.render.max_scale = defaults.max_scale;
+            self.render.target_frame_time = defaults.target_frame_time;
+        }
+        if let Err(error) = self.physics.validate() {
+            println!("Warning: invalid physics settings ({}), using defaults", error);
+            self.physics = PhysicsConfig::default();
+        }
+    }
+
+    fn check_samples(&mut self) {
+        match self.render.samples {
+            0 | 2 | 4 | 8 => {}
+            samples => {
+                println!("Warning: MSAA samples must be 0, 2, 4 or 8, not {}, disabling it", samples);
+                self.render.samples = 0;
+            }
+        }
+    }
+
+    pub fn apply_args(&mut self, args: &[String]) {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "--config" => {
+                    args.next();
+                }
+                "--fov" => parse_arg(arg, args.next(), &mut self.camera.fov),
+                "--speed" => parse_arg(arg, args.next(), &mut self.camera.speed),
+                "--sensitivity" => {
+                    parse_arg(arg, args.next(), &mut self.camera.sensitivity_x);
+                    self.camera.sensitivity_y = self.camera.sensitivity_x;
+                }
+                "--invert-y" => self.camera.invert_y = true,
+                "--smooth-movement" => self.camera.smooth_movement = true,
+                "--instant-movement" => self.camera.smooth_movement = false,
+                "--smoothing" => parse_arg(arg, args.next(), &mut self.camera.smoothing),
+                "--near" => parse_arg(arg, args.next(), &mut self.camera.near),
+                "--far" => parse_arg(arg, args.next(), &mut self.camera.far),
+                "--start" => {
+                    match args.next().and_then(|v| parse_start(v)) {
+                        Some((start, angles)) => {
+                            self.camera.start = Some(start);
+                            self.camera.start_angles = angles;
+                        }
+                        None => println!("Warning: --start expects x,y,z or x,y,z,pitch,yaw"),
+                    }
+                }
+                "--width" => parse_size(arg, args.next(), &mut self.render.width),
+                "--height" => parse_size(arg, args.next(), &mut self.render.height),
+                "--vsync" => self.render.vsync = true,
+                "--no-vsync" => self.render.vsync = false,
+                "--no-fix-winding" => self.render.fix_winding = false,
+                "--backface-culling" => self.render.backface_culling = true,
+                "--min-triangle-area" => {
+                    parse_arg(arg, args.next(), &mut self.render.min_triangle_area)
+                }
+                "--no-crosshair" => self.render.crosshair = false,
+                "--viewmodel" => self.render.viewmodel = true,
+                "--minimap" => self.render.minimap = true,
+                "--cull-benchmark" => self.cull_benchmark = true,
+                "--sort-benchmark" => self.sort_benchmark = true,
+                "--dynamic-resolution" => self.render.dynamic_resolution = true,
+                "--flip-lightmap-v" => self.render.flip_lightmap_v = true,
+                "--modulate-vertex-colors" => self.render.modulate_vertex_colors = true,
+                "--depth-prepass" => self.render.depth_prepass = true,
+                "--no-sort" => self.render.sort_draws = false,
+                "--no-water" => self.render.water = false,
+                "--clear-color" => {
+                    let color: Vec<f32> = args.next()
+                                              .map_or(Vec::new(), |v| {
+                                                  v.split(',')
+                                                   .filter_map(|c| c.trim().parse().ok())
+                                                   .collect()
+                                              });
+                    if color.len() == 3 {
+                        self.render.clear_color = check_color("--clear-color",
+                                                              [color[0], color[1], color[2]]);
+                    } else {
+                        println!("Warning: --clear-color expects r,g,b");
+                    }
+                }
+                "--fog" => {
+                    let mut density = 0.0;
+                    parse_arg(arg, args.next(), &mut density);
+                    self.render.fog_density = Some(density);
+                }
+                "--occlusion" => self.render.occlusion_queries = true,
+                "--area-portals" => self.render.area_portals = true,
+                "--min-screen-size" => parse_arg(arg, args.next(), &mut self.render.min_screen_size),
+                "--msaa" => parse_arg(arg, args.next(), &mut self.render.samples),
+                "--data" => parse_arg(arg, args.next(), &mut self.render.data_path),
+                "--only-texture" => self.render.only_texture = args.next().cloned(),
+                "--shaders" => self.render.shader_path = args.next().cloned(),
+                _ if arg.starts_with("--") => println!("Warning: unknown flag {}", arg),
+                _ => self.map = arg.clone(),
+            }
+        }
+    }
+}
+
+/// The guac.toml next to the executable, or the one in the working directory if there is none,
+/// which is where it is when running with `cargo run` from the repository.
+fn default_path() -> PathBuf {
+    match env::current_exe() {
+        Ok(exe) if exe.with_file_name("guac.toml").exists() => exe.with_file_name("guac.toml"),
+        _ => PathBuf::from("guac.toml"),
+    }
+}
+
+/// Turns a map name like `q3dm1` into `<data_path>/maps/q3dm1.bsp`, where the game would look
+/// for it. Anything with a path separator or a .bsp extension is a path already, and `-` reads
+/// the map from stdin. Maps inside pk3 archives aren't looked up, only `convert` reads those.
+fn resolve_map(map: &str, data_path: &str) -> String {
+    if map == "-" || map.contains('/') || map.contains('\\') ||
+       map.to_lowercase().ends_with(".bsp") {
+        return map.to_owned();
+    }
+
+    let path = format!("{}/maps/{}.bsp", data_path, map);
+    let has_archives = || {
+        fs::read_dir(data_path).map_or(false, |entries| {
+            entries.filter_map(|e| e.ok())
+                   .any(|e| e.path().extension().map_or(false, |e| e == "pk3"))
+        })
+    };
+    if !Path::new(&path).exists() && has_archives() {
+        println!("Warning: {} not found, maps in pk3 archives have to be extracted to {}/maps \
+                  first",
+                 path,
+                 data_path);
+    }
+    path
+}
+
+/// Returns `color` if all its components are between 0 and 1, otherwise warns about `name` and
+/// returns `None`.
+pub fn check_color(name: &str, color: [f32; 3]) -> Option<[f32; 3]> {
+    if color.iter().any(|&c| c < 0.0 || c > 1.0) {
+        println!("Warning: {} components must be between 0 and 1, ignoring it", name);
+        None
+    } else {
+        Some(color)
+    }
+}
+
+fn parse_arg<T: FromStr>(flag: &str, value: Option<&String>, target: &mut T) {
+    match value.map(|v| v.parse()) {
+        Some(Ok(v)) => *target = v,
+        _ => println!("Warning: {} expects a value", flag),
+    }
+}
+
+/// Like `parse_arg`, but a size of 0 is rejected too.
+fn parse_size(flag: &str, value: Option<&String>, target: &mut u32) {
+    match value.map(|v| v.parse()) {
+        Some(Ok(0)) => println!("Warning: {} must be positive", flag),
+        Some(Ok(v)) => *target = v,
+        _ => println!("Warning: {} expects a value", flag),
+    }
+}
+
+/// Parses `x,y,z` or `x,y,z,pitch,yaw` as written by the position printout.
+fn parse_start(value: &str) -> Option<([f32; 3], Option<(f32, f32)>)> {
+    let numbers: Vec<f32> = value.split(',').filter_map(|n| n.trim().parse().ok()).collect();
+    if numbers.len() != value.split(',').count() {
+        return None;
+    }
+    match numbers.len() {
+        3 => Some(([numbers[0], numbers[1], numbers[2]], None)),
+        5 => Some(([numbers[0], numbers[1], numbers[2]], Some((numbers[3], numbers[4])))),
+        _ => None,
+    }
+}
+
+pub fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+    use glium::glutin::VirtualKeyCode::*;
+
+    let key = match &name.to_uppercase()[..] {
+        "A" => A, "B" => B, "C" => C, "D" => D, "E" => E, "F" => F, "G" => G,
+        "H" => H, "I" => I, "J" => J, "K" => K, "L" => L, "M" => M, "N" => N,
+        "O" => O, "P" => P, "Q" => Q, "R" => R, "S" => S, "T" => T, "U" => U,
+        "V" => V, "W" => W, "X" => X, "Y" => Y, "Z" => Z,
+        "0" => Key0, "1" => Key1, "2" => Key2, "3" => Key3, "4" => Key4,
+        "5" => Key5, "6" => Key6, "7" => Key7, "8" => Key8, "9" => Key9,
+        "F1" => F1, "F2" => F2, "F3" => F3, "F4" => F4, "F5" => F5, "F6" => F6,
+        "F7" => F7, "F8" => F8, "F9" => F9, "F10" => F10, "F11" => F11, "F12" => F12,
+        "UP" => Up, "DOWN" => Down, "LEFT" => Left, "RIGHT" => Right,
+        "SPACE" => Space, "TAB" => Tab, "RETURN" | "ENTER" => Return, "BACKSPACE" => Back,
+        "LSHIFT" | "SHIFT" => LShift, "RSHIFT" => RShift,
+        "LCONTROL" | "CTRL" => LControl, "RCONTROL" => RControl,
+        "LALT" | "ALT" => LAlt, "RALT" => RAlt,
+        "GRAVE" => Grave, "LBRACKET" => LBracket, "RBRACKET" => RBracket,
+        "MINUS" => Minus, "EQUALS" => Equals, "COMMA" => Comma, "PERIOD" => Period,
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory in the temp directory, removed again by `remove_dir`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn remove_dir(directory: &Path) {
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn resolve_map_passes_paths_and_stdin_through() {
+        for map in &["-", "maps/q3dm1", "..\\q3dm1", "q3dm1.bsp", "Q3DM1.BSP"] {
+            assert_eq!(resolve_map(map, "data"), map.to_string());
+        }
+    }
+
+    #[test]
+    fn resolve_map_looks_for_names_in_the_maps_directory() {
+        assert_eq!(resolve_map("q3dm1", "data"), "data/maps/q3dm1.bsp");
+        assert_eq!(resolve_map("q3dm1", "/games/quake3/baseq3"),
+                   "/games/quake3/baseq3/maps/q3dm1.bsp");
+    }
+
+    #[test]
+    fn resolve_map_gives_the_maps_path_even_if_the_map_is_in_an_archive() {
+        let directory = temp_dir("guac_resolve_map_test");
+        File::create(directory.join("pak0.pk3")).unwrap();
+        let data_path = directory.to_string_lossy().into_owned();
+        assert_eq!(resolve_map("q3dm1", &data_path),
+                   format!("{}/maps/q3dm1.bsp", data_path));
+        remove_dir(&directory);
+    }
+
+    #[test]
+    fn parse_size_rejects_zero_and_keeps_the_old_size() {
+        let mut width = 1280;
+        parse_size("--width", Some(&"0".to_owned()), &mut width);
+        assert_eq!(width, 1280);
+        parse_size("--width", Some(&"-5".to_owned()), &mut width);
+        assert_eq!(width, 1280);
+        parse_size("--width", Some(&"640".to_owned()), &mut width);
+        assert_eq!(width, 640);
+    }
+
+    #[test]
+    fn camera_validate_checks_the_projection() {
+        assert_eq!(CameraConfig::default().validate(), Ok(()));
+        let invalid = [(0.0, 100.0, 90.0),
+                       (10.0, 10.0, 90.0),
+                       (1.0, 100.0, 0.0),
+                       (1.0, 100.0, 180.0)];
+        for &(near, far, fov) in &invalid {
+            let camera = CameraConfig { near: near, far: far, fov: fov, ..CameraConfig::default() };
+            assert!(camera.validate().is_err(), "near {}, far {}, fov {}", near, far, fov);
+        }
+    }
+
+    #[test]
+    fn invalid_camera_arguments_fall_back_to_the_defaults() {
+        let args: Vec<String> = ["--config", "missing.toml", "--near", "0", "--width", "0"]
+                                    .iter()
+                                    .map(|a| a.to_string())
+                                    .collect();
+        let config = Config::from_args(&args);
+        let defaults = Config::default();
+        assert_eq!(config.camera.near, defaults.camera.near);
+        assert_eq!(config.render.width, defaults.render.width);
+    }
+}
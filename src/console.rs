@@ -0,0 +1,257 @@
+use screenshot::ScreenshotSize;
+
+/// Lines of output kept for the overlay, older ones are only on stdout.
+pub const OUTPUT_LINES: usize = 8;
+
+/// Name and usage of every console command, printed by `help`.
+const COMMANDS: &'static [(&'static str, &'static str)] = &[("help", "help"),
+                                                             ("wireframe", "wireframe <0|1>"),
+                                                             ("fov", "fov <degrees>"),
+                                                             ("goto", "goto <x> <y> <z>"),
+                                                             ("goto_entity",
+                                                              "goto_entity <targetname or \
+                                                               classname>"),
+                                                             ("rendermode", "rendermode <name>"),
+                                                             ("model", "model <index>"),
+                                                             ("noclip", "noclip"),
+                                                             ("portals", "portals <0|1>"),
+                                                             ("screenshot",
+                                                              "screenshot <width> <height>|<scale>x \
+                                                               [file.png]")];
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Wireframe(bool),
+    Fov(f32),
+    /// Position in Quake 3 coordinates.
+    Goto([f32; 3]),
+    /// Targetname or classname, repeating it moves on to the next match.
+    GotoEntity(String),
+    RenderMode(String),
+    Model(usize),
+    Noclip,
+    /// Opens or closes every areaportal.
+    Portals(bool),
+    /// Size and file of an offscreen screenshot, the file is made up if there's none.
+    Screenshot(ScreenshotSize, Option<String>),
+}
+
+/// A one line console. While it's open, typed characters go to the input line instead of the
+/// key bindings, and the input and recent output are drawn over the view.
+pub struct Console {
+    pub open: bool,
+    pub input: String,
+    /// The last `OUTPUT_LINES` printed lines, oldest first.
+    pub output: Vec<String>,
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console {
+            open: false,
+            input: String::new(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Prints `line` to stdout and keeps it for the overlay.
+    pub fn print(&mut self, line: String) {
+        println!("{}", line);
+        if self.output.len() == OUTPUT_LINES {
+            self.output.remove(0);
+        }
+        self.output.push(line);
+    }
+
+    pub fn print_help(&mut self) {
+        self.print("Commands:".to_owned());
+        for &(_, usage) in COMMANDS {
+            self.print(format!("  {}", usage));
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        self.input.clear();
+    }
+
+    pub fn type_char(&mut self, c: char) {
+        // The toggle key also arrives as a character
+        if !c.is_control() && c != '`' && c != '~' {
+            self.input.push(c);
+        }
+    }
+
+    pub fn backspace(&mut self) {
+        self.input.pop();
+    }
+
+    /// Parses and clears the input line. Empty lines give `None`.
+    pub fn submit(&mut self) -> Option<Result<Command, String>> {
+        let line = self.input.trim().to_owned();
+        self.input.clear();
+        if line.is_empty() {
+            None
+        } else {
+            self.print(format!("] {}", line));
+            Some(parse(&line))
+        }
+    }
+
+    /// The input line with a prompt and a cursor, as it's drawn.
+    pub fn prompt(&self) -> String {
+        format!("] {}_", self.input)
+    }
+}
+
+pub fn parse(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let name = match words.first() {
+        Some(word) => word.to_lowercase(),
+        None => return Err("No command, try help".to_owned()),
+    };
+    let args = &words[1..];
+    let usage = match COMMANDS.iter().find(|c| c.0 == name) {
+        Some(&(_, usage)) => usage,
+        None => return Err(format!("Unknown command {}, try help", name)),
+    };
+    let bad_usage = || format!("Usage: {}", usage);
+
+    match &name[..] {
+        "help" => Ok(Command::Help),
+        "wireframe" => {
+            match (args.len(), args.get(0).map(|a| *a)) {
+                (0, _) | (1, Some("1")) => Ok(Command::Wireframe(true)),
+                (1, Some("0")) => Ok(Command::Wireframe(false)),
+                _ => Err(bad_usage()),
+            }
+        }
+        "fov" => {
+            match args.get(0).and_then(|a| a.parse().ok()) {
+                Some(fov) if args.len() == 1 && fov > 0.0 && fov < 180.0 => Ok(Command::Fov(fov)),
+                _ => Err(bad_usage()),
+            }
+        }
+        "goto" => {
+            let numbers: Vec<f32> = args.iter().filter_map(|a| a.parse().ok()).collect();
+            if args.len() == 3 && numbers.len() == 3 {
+                Ok(Command::Goto([numbers[0], numbers[1], numbers[2]]))
+            } else {
+                Err(bad_usage())
+            }
+        }
+        "goto_entity" => {
+            if args.len() == 1 {
+                Ok(Command::GotoEntity(args[0].to_owned()))
+            } else {
+                Err(bad_usage())
+            }
+        }
+        "rendermode" => {
+            if args.len() == 1 {
+                Ok(Command::RenderMode(args[0].to_lowercase()))
+            } else {
+                Err(bad_usage())
+            }
+        }
+        "model" => {
+            match args.get(0).and_then(|a| a.parse().ok()) {
+                Some(index) if args.len() == 1 => Ok(Command::Model(index)),
+                _ => Err(bad_usage()),
+            }
+        }
+        "noclip" => Ok(Command::Noclip),
+        "portals" => {
+            match (args.len(), args.get(0).map(|a| *a)) {
+                (1, Some("1")) => Ok(Command::Portals(true)),
+                (1, Some("0")) => Ok(Command::Portals(false)),
+                _ => Err(bad_usage()),
+            }
+        }
+        "screenshot" => {
+            let scale = args.get(0).and_then(|a| {
+                if a.ends_with('x') {
+                    a[..a.len() - 1].parse().ok()
+                } else {
+                    None
+                }
+            });
+            let size_args = if scale.is_some() { 1 } else { 2 };
+            let size = match scale {
+                Some(scale) if scale > 0.0 => ScreenshotSize::Scale(scale),
+                Some(_) => return Err(bad_usage()),
+                None => {
+                    let pixels: Vec<u32> = args.iter()
+                                               .take(2)
+                                               .filter_map(|a| a.parse().ok())
+                                               .collect();
+                    if pixels.len() != 2 {
+                        return Err(bad_usage());
+                    }
+                    ScreenshotSize::Pixels(pixels[0], pixels[1])
+                }
+            };
+            if args.len() > size_args + 1 {
+                return Err(bad_usage());
+            }
+            Ok(Command::Screenshot(size, args.get(size_args).map(|p| (*p).to_owned())))
+        }
+        _ => Err(bad_usage()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screenshot::ScreenshotSize;
+
+    #[test]
+    fn parse_rejects_empty_lines() {
+        assert!(parse("").is_err());
+        assert!(parse("   ").is_err());
+    }
+
+    #[test]
+    fn parse_ignores_the_case_of_the_command() {
+        assert_eq!(parse("NoClip"), Ok(Command::Noclip));
+        assert!(parse("nonsense").is_err());
+    }
+
+    #[test]
+    fn parse_fov_only_accepts_angles_between_0_and_180() {
+        assert_eq!(parse("fov 90"), Ok(Command::Fov(90.0)));
+        for line in &["fov", "fov 0", "fov 180", "fov -10", "fov wide", "fov 90 100"] {
+            assert!(parse(line).is_err(), "{} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn parse_goto_needs_exactly_three_numbers() {
+        assert_eq!(parse("goto 1 -2 3.5"), Ok(Command::Goto([1.0, -2.0, 3.5])));
+        for line in &["goto", "goto 1 2", "goto 1 2 3 4", "goto 1 2 z"] {
+            assert!(parse(line).is_err(), "{} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn parse_screenshot_takes_a_scale_or_a_size() {
+        assert_eq!(parse("screenshot 2x"),
+                   Ok(Command::Screenshot(ScreenshotSize::Scale(2.0), None)));
+        assert_eq!(parse("screenshot 0.5x small.png"),
+                   Ok(Command::Screenshot(ScreenshotSize::Scale(0.5),
+                                          Some("small.png".to_owned()))));
+        assert_eq!(parse("screenshot 1920 1080"),
+                   Ok(Command::Screenshot(ScreenshotSize::Pixels(1920, 1080), None)));
+        assert_eq!(parse("screenshot 1920 1080 big.png"),
+                   Ok(Command::Screenshot(ScreenshotSize::Pixels(1920, 1080),
+                                          Some("big.png".to_owned()))));
+        for line in &["screenshot",
+                      "screenshot 0x",
+                      "screenshot 1920",
+                      "screenshot 2x a.png b.png",
+                      "screenshot 1920 1080 a.png b.png"] {
+            assert!(parse(line).is_err(), "{} was accepted", line);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use cgmath::{Matrix3, Matrix4, Point3, Rad, Vector3, deg};
+
+/// Up axis of exported geometry, chosen with `convert --up`. Rendering stays y up: render space
+/// is only ever seen through the camera, whose look_at up vector, pitch and yaw and movement are
+/// all built around y up. Drawing z up would take a second version of all of them and show the
+/// exact same image, so the convention only matters for files other tools read.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UpAxis {
+    /// Rotated into render space, what most tools expect.
+    Y,
+    /// Quake 3 space unchanged, for tools that are z up themselves.
+    Z,
+}
+
+impl UpAxis {
+    pub fn from_name(name: &str) -> Option<UpAxis> {
+        match &name.to_lowercase()[..] {
+            "y" => Some(UpAxis::Y),
+            "z" => Some(UpAxis::Z),
+            _ => None,
+        }
+    }
+
+    /// Converts a point from Quake 3 space to the space with this up axis.
+    pub fn point(self, point: Point3<f32>) -> Point3<f32> {
+        match self {
+            UpAxis::Y => q3_to_render_point(point),
+            UpAxis::Z => point,
+        }
+    }
+
+    pub fn vector(self, vector: Vector3<f32>) -> Vector3<f32> {
+        match self {
+            UpAxis::Y => q3_to_render_vector(vector),
+            UpAxis::Z => vector,
+        }
+    }
+}
+
+/// Quake 3 is z up while the renderer is y up like OpenGL, so the world is drawn rotated by -90°
+/// around the x axis: `(x, y, z)` in Quake 3 is `(x, z, -y)` in render space. The BSP data,
+/// collision, entities and everything printed or read from the user stay in Quake 3 space, the
+/// camera and the matrices handed to shaders are in render space. Conversions between the two go
+/// through these functions, so both sides agree on the rotation.
+pub fn q3_to_render() -> Matrix4<f32> {
+    Matrix4::from(Matrix3::from_angle_x(Rad::from(deg(-90.0f32))))
+}
+
+pub fn q3_to_render_point(point: Point3<f32>) -> Point3<f32> {
+    Point3::new(point.x, point.z, -point.y)
+}
+
+pub fn q3_to_render_vector(vector: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(vector.x, vector.z, -vector.y)
+}
+
+pub fn render_to_q3_point(point: Point3<f32>) -> Point3<f32> {
+    Point3::new(point.x, -point.z, point.y)
+}
+
+pub fn render_to_q3_vector(vector: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(vector.x, -vector.z, vector.y)
+}
@@ -0,0 +1,188 @@
+pub struct Entity {
+    pub properties: Vec<(String, String)>,
+}
+
+impl Entity {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.properties.iter().find(|p| p.0 == key).map(|p| &p.1[..])
+    }
+
+    pub fn classname(&self) -> &str {
+        self.get("classname").unwrap_or("")
+    }
+
+    pub fn get_f32(&self, key: &str) -> Option<f32> {
+        self.get(key).and_then(parse_f32)
+    }
+
+    pub fn get_vec2(&self, key: &str) -> Option<[f32; 2]> {
+        self.get(key).and_then(parse_vec2)
+    }
+
+    pub fn get_vec3(&self, key: &str) -> Option<[f32; 3]> {
+        self.get(key).and_then(parse_vec3)
+    }
+
+    /// Index of the inline model of a brush entity, from a `model` key like `*3`.
+    pub fn model_index(&self) -> Option<usize> {
+        self.get("model").and_then(|m| {
+            if m.starts_with('*') {
+                m[1..].parse().ok()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The horizontal bounds from the `mapcoordsmins` and `mapcoordsmaxs` keys of a worldspawn,
+    /// which maps set for their minimap. The keys name opposite corners, usually upper left
+    /// and lower right, so they are sorted into mins and maxs. A z component is ignored.
+    pub fn map_coords(&self) -> Option<([f32; 2], [f32; 2])> {
+        let corner = |key: &str| {
+            self.get(key).and_then(parse_floats).and_then(|c| {
+                if c.len() == 2 || c.len() == 3 {
+                    Some([c[0], c[1]])
+                } else {
+                    None
+                }
+            })
+        };
+        match (corner("mapcoordsmins"), corner("mapcoordsmaxs")) {
+            (Some(a), Some(b)) if a[0] != b[0] && a[1] != b[1] => {
+                Some(([a[0].min(b[0]), a[1].min(b[1])], [a[0].max(b[0]), a[1].max(b[1])]))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Parses a value of numbers separated by spaces, or returns `None` if any of them isn't one.
+pub fn parse_floats(value: &str) -> Option<Vec<f32>> {
+    value.split_whitespace().map(|n| n.parse().ok()).collect()
+}
+
+/// Parses a single number like `light`, integers included.
+pub fn parse_f32(value: &str) -> Option<f32> {
+    value.trim().parse().ok()
+}
+
+pub fn parse_vec2(value: &str) -> Option<[f32; 2]> {
+    match parse_floats(value) {
+        Some(ref numbers) if numbers.len() == 2 => Some([numbers[0], numbers[1]]),
+        _ => None,
+    }
+}
+
+/// Parses a value of three numbers separated by spaces, like origins, angles and colors.
+pub fn parse_vec3(value: &str) -> Option<[f32; 3]> {
+    match parse_floats(value) {
+        Some(ref numbers) if numbers.len() == 3 => Some([numbers[0], numbers[1], numbers[2]]),
+        _ => None,
+    }
+}
+
+/// A point light from a `light` entity.
+pub struct Light {
+    pub origin: [f32; 3],
+    /// Distance in units at which the light fades out, the `light` key.
+    pub intensity: f32,
+    /// `_color` scaled so the brightest component is 1.
+    pub color: [f32; 3],
+}
+
+/// Collects the `light` entities with an origin. Intensity and color default to 300 and white
+/// like in q3map2.
+pub fn lights(entities: &[Entity]) -> Vec<Light> {
+    entities.iter()
+            .filter(|e| e.classname() == "light")
+            .filter_map(|e| {
+                e.get_vec3("origin").map(|origin| {
+                    let intensity = e.get_f32("light");
+                    let color = e.get_vec3("_color").unwrap_or([1.0; 3]);
+                    let max = color[0].max(color[1]).max(color[2]);
+                    Light {
+                        origin: origin,
+                        intensity: intensity.unwrap_or(300.0),
+                        color: if max > 0.0 {
+                            [color[0] / max, color[1] / max, color[2] / max]
+                        } else {
+                            [1.0; 3]
+                        },
+                    }
+                })
+            })
+            .collect()
+}
+
+pub fn parse(source: &str) -> Vec<Entity> {
+    let mut entities = Vec::new();
+    let mut current: Option<Entity> = None;
+    let mut key: Option<String> = None;
+    let mut chars = source.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                current = Some(Entity { properties: Vec::new() });
+                key = None;
+            }
+            '}' => {
+                if let Some(entity) = current.take() {
+                    entities.push(entity);
+                }
+            }
+            '"' => {
+                let token: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                if let Some(ref mut entity) = current {
+                    match key.take() {
+                        Some(k) => entity.properties.push((k, token)),
+                        None => key = Some(token),
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    entities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_f32_reads_integers_and_surrounding_whitespace() {
+        assert_eq!(parse_f32("300"), Some(300.0));
+        assert_eq!(parse_f32(" 1.5 "), Some(1.5));
+        assert_eq!(parse_f32("-8"), Some(-8.0));
+    }
+
+    #[test]
+    fn parse_f32_rejects_anything_but_one_number() {
+        assert_eq!(parse_f32(""), None);
+        assert_eq!(parse_f32("1 2"), None);
+        assert_eq!(parse_f32("x"), None);
+    }
+
+    #[test]
+    fn parse_vec2_needs_exactly_two_numbers() {
+        assert_eq!(parse_vec2("1 2"), Some([1.0, 2.0]));
+        assert_eq!(parse_vec2("  -1.5   2  "), Some([-1.5, 2.0]));
+        assert_eq!(parse_vec2(""), None);
+        assert_eq!(parse_vec2("1"), None);
+        assert_eq!(parse_vec2("1 2 3"), None);
+        assert_eq!(parse_vec2("1 x"), None);
+    }
+
+    #[test]
+    fn parse_vec3_needs_exactly_three_numbers() {
+        assert_eq!(parse_vec3("-64 128 0"), Some([-64.0, 128.0, 0.0]));
+        assert_eq!(parse_vec3(" 1\t2  0.5 "), Some([1.0, 2.0, 0.5]));
+        assert_eq!(parse_vec3(""), None);
+        assert_eq!(parse_vec3("1 2"), None);
+        assert_eq!(parse_vec3("1 x 3"), None);
+        assert_eq!(parse_vec3("1 2 3 4"), None);
+        assert_eq!(parse_vec3("1,2,3"), None);
+    }
+}
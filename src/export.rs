@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use cgmath::{Point3, Vector3};
+use bsp_reader::BspMap;
+use coords::UpAxis;
+use geometry;
+use patch;
+
+/// Planar faces are in the same plane if the components of their normals differ by less than
+/// `1 / NORMAL_QUANTIZATION` and their distances by less than `1 / DIST_QUANTIZATION` units,
+/// give or take rounding at the boundaries.
+const NORMAL_QUANTIZATION: f32 = 100.0;
+const DIST_QUANTIZATION: f32 = 2.0;
+
+/// Writes the world model as Wavefront OBJ with one group per texture. Positions and normals are
+/// converted from the Z up Quake 3 space to `up`, triangles are wound counter clockwise and
+/// patches are tessellated with `tessellation` subdivisions. With `group_planes` the planar
+/// faces of a texture are further split into one group per plane, so coplanar faces end up
+/// together. Meshes and patches stay in the group of their texture.
+pub fn write_obj<W: Write>(map: &BspMap,
+                           out: &mut W,
+                           tessellation: usize,
+                           group_planes: bool,
+                           up: UpAxis)
+                           -> io::Result<()> {
+    let mut vertexes = map.vertexes.clone();
+    let mut groups: BTreeMap<(i32, Option<[i32; 4]>), Vec<u32>> = BTreeMap::new();
+
+    if let Some(model) = map.models.get(0) {
+        for face in &map.faces[model.face as usize..(model.face + model.n_faces) as usize] {
+            let indices = match face.f_type {
+                1 | 3 => geometry::resolve_face(map, face, true).unwrap().0,
+                2 => {
+                    let patch = patch::tessellate(face, &map.vertexes, tessellation);
+                    let base = vertexes.len() as u32;
+                    vertexes.extend_from_slice(&patch.vertexes);
+                    patch.indices.iter().map(|i| i + base).collect()
+                }
+                _ => continue,
+            };
+            let plane = if group_planes && face.f_type == 1 {
+                let position = map.vertexes[face.vertex as usize].position;
+                let dist = geometry::dot(face.normal, position);
+                Some([(face.normal[0] * NORMAL_QUANTIZATION).round() as i32,
+                      (face.normal[1] * NORMAL_QUANTIZATION).round() as i32,
+                      (face.normal[2] * NORMAL_QUANTIZATION).round() as i32,
+                      (dist * DIST_QUANTIZATION).round() as i32])
+            } else {
+                None
+            };
+            groups.entry((face.texture, plane))
+                  .or_insert_with(Vec::new)
+                  .extend_from_slice(&indices);
+        }
+    }
+
+    let all_indices: Vec<u32> = groups.values().flat_map(|g| g.iter().cloned()).collect();
+    let (vertexes, all_indices) = geometry::compact(&vertexes, &all_indices);
+
+    try!(writeln!(out, "# {} vertexes, {} triangles", vertexes.len(), all_indices.len() / 3));
+    for v in &vertexes {
+        let p = up.point(Point3::from(v.position));
+        try!(writeln!(out, "v {} {} {}", p.x, p.y, p.z));
+    }
+    for v in &vertexes {
+        try!(writeln!(out, "vt {} {}", v.texcoord[0][0], 1.0 - v.texcoord[0][1]));
+    }
+    for v in &vertexes {
+        let n = up.vector(Vector3::from(v.normal));
+        try!(writeln!(out, "vn {} {} {}", n.x, n.y, n.z));
+    }
+
+    let mut start = 0;
+    let mut plane_groups = 0;
+    for (&(texture, plane), indices) in &groups {
+        let name = map.textures.get(texture as usize).map_or("unknown", |t| &t.name[..]);
+        if plane.is_some() {
+            try!(writeln!(out, "g {}_plane{}", name, plane_groups));
+            plane_groups += 1;
+        } else {
+            try!(writeln!(out, "g {}", name));
+        }
+        try!(writeln!(out, "usemtl {}", name));
+        for triangle in all_indices[start..start + indices.len()].chunks(3) {
+            if triangle.len() < 3 {
+                break;
+            }
+            // Quake 3 front faces are clockwise
+            let (a, b, c) = (triangle[0] + 1, triangle[2] + 1, triangle[1] + 1);
+            try!(writeln!(out, "f {}/{}/{} {}/{}/{} {}/{}/{}", a, a, a, b, b, b, c, c, c));
+        }
+        start += indices.len();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,687 @@
+use std::collections::HashMap;
+use bsp_reader::{self, BspMap, Face, Vertex};
+use collision;
+
+/// Vertex attributes are rounded to multiples of `1 / QUANTIZATION` before comparing them, so
+/// vertexes that only differ by float noise from the compiler are merged.
+const QUANTIZATION: f32 = 4096.0;
+
+pub fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+pub fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+/// Quake 3 winds front faces clockwise, so the triangle normal `(b - a) x (c - a)` has to point
+/// away from the surface normal. Triangles that disagree are flipped in place. For faces without
+/// a meaningful face normal (meshes), `normal` can be `None` and the vertex normals are used.
+/// Returns the number of flipped triangles.
+pub fn fix_winding(vertexes: &[Vertex], indices: &mut [u32], normal: Option<[f32; 3]>) -> usize {
+    let mut fixed = 0;
+
+    for triangle in indices.chunks_mut(3) {
+        if triangle.len() < 3 {
+            break;
+        }
+
+        let a = &vertexes[triangle[0] as usize];
+        let b = &vertexes[triangle[1] as usize];
+        let c = &vertexes[triangle[2] as usize];
+        let normal = normal.unwrap_or([a.normal[0] + b.normal[0] + c.normal[0],
+                                       a.normal[1] + b.normal[1] + c.normal[1],
+                                       a.normal[2] + b.normal[2] + c.normal[2]]);
+        let winding = cross(sub(b.position, a.position), sub(c.position, a.position));
+
+        if dot(winding, normal) > 0.0 {
+            triangle.swap(1, 2);
+            fixed += 1;
+        }
+    }
+
+    fixed
+}
+
+/// Returns the center of the bounds of model `index` and a distance from it at which the whole
+/// model is in view, or `None` if there's no such model.
+pub fn model_view(map: &BspMap, index: usize) -> Option<([f32; 3], f32)> {
+    map.models.get(index).map(|model| {
+        let center = [(model.mins[0] + model.maxs[0]) / 2.0,
+                      (model.mins[1] + model.maxs[1]) / 2.0,
+                      (model.mins[2] + model.maxs[2]) / 2.0];
+        let size = sub(model.maxs, model.mins);
+        (center, dot(size, size).sqrt().max(64.0))
+    })
+}
+
+/// Returns every face with the model that lists it, in the order of the models. Models don't
+/// share faces in maps from q3map2, but a face listed by two models would be drawn twice, so it
+/// only goes to the first one. Also returns the number of faces skipped for that reason.
+pub fn model_faces(map: &BspMap) -> (Vec<(usize, usize)>, usize) {
+    let mut model_faces = Vec::new();
+    let mut claimed = vec![false; map.faces.len()];
+    let mut shared = 0;
+    for (m, model) in map.models.iter().enumerate() {
+        let start = model.face.max(0) as usize;
+        let end = (model.face as i64 + model.n_faces as i64).max(0) as usize;
+        for f in start..end.min(map.faces.len()) {
+            if claimed[f] {
+                shared += 1;
+            } else {
+                claimed[f] = true;
+                model_faces.push((m, f));
+            }
+        }
+    }
+    (model_faces, shared)
+}
+
+/// Checks that the meshverts of a polygon or mesh face are a whole number of triangles within
+/// the meshvert lump, and that each one points at a vertex of the face itself.
+pub fn check_meshverts(map: &BspMap, face: &Face) -> Result<(), String> {
+    if face.n_meshverts % 3 != 0 {
+        return Err(format!("{} meshverts aren't whole triangles", face.n_meshverts));
+    }
+    if face.meshvert < 0 || face.n_meshverts < 0 ||
+       face.meshvert as i64 + face.n_meshverts as i64 > map.meshverts.len() as i64 {
+        return Err(format!("meshverts {}..{} out of range",
+                           face.meshvert,
+                           face.meshvert as i64 + face.n_meshverts as i64));
+    }
+    if face.vertex < 0 || face.n_vertexes < 0 ||
+       face.vertex as i64 + face.n_vertexes as i64 > map.vertexes.len() as i64 {
+        return Err(format!("vertexes {}..{} out of range",
+                           face.vertex,
+                           face.vertex as i64 + face.n_vertexes as i64));
+    }
+    let range = face.meshvert as usize..(face.meshvert + face.n_meshverts) as usize;
+    if let Some(m) = map.meshverts[range].iter().find(|&&m| m < 0 || m >= face.n_vertexes) {
+        return Err(format!("meshvert {} outside the face's {} vertexes", m, face.n_vertexes));
+    }
+    Ok(())
+}
+
+/// Resolves the meshverts of a polygon or mesh face into indices of the vertex lump and fixes
+/// their winding if asked to. Polygons and meshes are indexed the same way, both list their
+/// triangles as meshverts relative to the first vertex of the face. Only the winding fix differs,
+/// polygons have a face normal and meshes don't. Returns the indices and the number of flipped
+/// triangles, no indices for faces that fail `check_meshverts`, or `None` for other face types.
+pub fn resolve_face(map: &BspMap, face: &Face, fix: bool) -> Option<(Vec<u32>, usize)> {
+    if face.f_type != 1 && face.f_type != 3 {
+        return None;
+    }
+    if check_meshverts(map, face).is_err() {
+        return Some((Vec::new(), 0));
+    }
+
+    let range = face.meshvert as usize..(face.meshvert + face.n_meshverts) as usize;
+    let meshverts = &map.meshverts[range];
+    let mut indices: Vec<u32> = meshverts.iter().map(|m| (m + face.vertex) as u32).collect();
+    let mut fixed = 0;
+    if fix {
+        let normal = if face.f_type == 1 {
+            Some(face.normal)
+        } else {
+            None
+        };
+        fixed = fix_winding(&map.vertexes, &mut indices, normal);
+    }
+    Some((indices, fixed))
+}
+
+/// Removes the triangles with an area below `min_area`, which includes those with repeated or
+/// collinear vertexes. Their normals are NaN and they cover no pixels anyway. Returns the number
+/// of removed triangles.
+pub fn drop_degenerate(vertexes: &[Vertex], indices: &mut Vec<u32>, min_area: f32) -> usize {
+    let before = indices.len() / 3;
+    let kept: Vec<u32> = indices.chunks(3)
+                                .filter(|triangle| {
+                                    if triangle.len() < 3 {
+                                        return false;
+                                    }
+                                    let a = vertexes[triangle[0] as usize].position;
+                                    let b = vertexes[triangle[1] as usize].position;
+                                    let c = vertexes[triangle[2] as usize].position;
+                                    let normal = cross(sub(b, a), sub(c, a));
+                                    let area = dot(normal, normal).sqrt() / 2.0;
+                                    // Even with no minimum, triangles without any area go
+                                    area > 0.0 && area >= min_area
+                                })
+                                .flat_map(|triangle| triangle.iter().cloned())
+                                .collect();
+    *indices = kept;
+    before - indices.len() / 3
+}
+
+/// Returns the vertexes of `face`, leaving out indices past either end of the lump. The range
+/// is computed in i64 so corrupt headers can't overflow it.
+fn face_vertexes<'a>(map: &'a BspMap, face: &Face) -> &'a [Vertex] {
+    let count = map.vertexes.len() as i64;
+    let start = (face.vertex as i64).max(0).min(count) as usize;
+    let end = (face.vertex as i64 + face.n_vertexes as i64).max(0).min(count) as usize;
+    &map.vertexes[start.min(end)..end]
+}
+
+/// Returns the bounds of the vertexes of every face. For patches these are the bounds of the
+/// control points, which contain the whole curved surface.
+pub fn face_bounds(map: &BspMap) -> Vec<([f32; 3], [f32; 3])> {
+    map.faces
+       .iter()
+       .map(|face| bsp_reader::world_bounds(face_vertexes(map, face)))
+       .collect()
+}
+
+/// Returns the average of the vertexes of every face, or `None` for faces without vertexes.
+/// Vertex indices out of range are ignored like in `face_bounds`.
+pub fn face_centroids(map: &BspMap) -> Vec<Option<[f32; 3]>> {
+    map.faces
+       .iter()
+       .map(|face| {
+           let vertexes = face_vertexes(map, face);
+           if vertexes.is_empty() {
+               return None;
+           }
+           let mut sum = [0.0; 3];
+           for vertex in vertexes {
+               for axis in 0..3 {
+                   sum[axis] += vertex.position[axis];
+               }
+           }
+           let count = vertexes.len() as f32;
+           Some([sum[0] / count, sum[1] / count, sum[2] / count])
+       })
+       .collect()
+}
+
+/// Returns the faces whose bounds intersect the sphere of `radius` around `point`, sorted by
+/// index. `bounds` are the bounds from `face_bounds`, the BSP tree is used to find candidates.
+pub fn faces_near(map: &BspMap,
+                  bounds: &[([f32; 3], [f32; 3])],
+                  point: [f32; 3],
+                  radius: f32)
+                  -> Vec<usize> {
+    let mins = [point[0] - radius, point[1] - radius, point[2] - radius];
+    let maxs = [point[0] + radius, point[1] + radius, point[2] + radius];
+
+    let mut faces = Vec::new();
+    for leaf in collision::leaves_in_box(map, mins, maxs) {
+        let leaf = &map.leafs[leaf];
+        for i in 0..leaf.n_leaffaces {
+            let face = map.leaffaces[(leaf.leafface + i) as usize] as usize;
+            let (face_mins, face_maxs) = match bounds.get(face) {
+                Some(&b) => b,
+                None => continue,
+            };
+            // Squared distance from the center to the closest point of the box
+            let mut distance = 0.0;
+            for a in 0..3 {
+                let closest = point[a].max(face_mins[a]).min(face_maxs[a]);
+                distance += (point[a] - closest) * (point[a] - closest);
+            }
+            if distance <= radius * radius {
+                faces.push(face);
+            }
+        }
+    }
+    // Faces are referenced by every leaf they touch
+    faces.sort();
+    faces.dedup();
+    faces
+}
+
+/// Returns the closest polygon or mesh face hit by the segment from `start` to `end` and the
+/// fraction of the segment at the hit. Only faces in the leafs the segment's bounds touch are
+/// tested, so callers should stop the segment at the first solid hit. `tested` is scratch space
+/// with an entry per face, kept by the caller so repeated picks don't allocate it.
+pub fn pick_face(map: &BspMap,
+                 bounds: &[([f32; 3], [f32; 3])],
+                 start: [f32; 3],
+                 end: [f32; 3],
+                 tested: &mut [bool])
+                 -> Option<(usize, f32)> {
+    let mins = [start[0].min(end[0]), start[1].min(end[1]), start[2].min(end[2])];
+    let maxs = [start[0].max(end[0]), start[1].max(end[1]), start[2].max(end[2])];
+    let direction = sub(end, start);
+
+    for tested in tested.iter_mut() {
+        *tested = false;
+    }
+    let mut closest: Option<(usize, f32)> = None;
+    for leaf in collision::leaves_in_box(map, mins, maxs) {
+        let leaf = &map.leafs[leaf];
+        for i in 0..leaf.n_leaffaces {
+            let index = map.leaffaces[(leaf.leafface + i) as usize] as usize;
+            if index >= tested.len() || tested[index] {
+                continue;
+            }
+            tested[index] = true;
+            let (face_mins, face_maxs) = bounds[index];
+            if !(0..3).all(|a| face_mins[a] <= maxs[a] && face_maxs[a] >= mins[a]) {
+                continue;
+            }
+            let indices = match resolve_face(map, &map.faces[index], false) {
+                Some((indices, _)) => indices,
+                None => continue,
+            };
+            for triangle in indices.chunks(3) {
+                if triangle.len() < 3 {
+                    break;
+                }
+                let a = map.vertexes[triangle[0] as usize].position;
+                let b = map.vertexes[triangle[1] as usize].position;
+                let c = map.vertexes[triangle[2] as usize].position;
+                if let Some(fraction) = intersect_triangle(start, direction, a, b, c) {
+                    if closest.map_or(true, |(_, f)| fraction < f) {
+                        closest = Some((index, fraction));
+                    }
+                }
+            }
+        }
+    }
+    closest
+}
+
+/// Returns the edges of a polygon or mesh face that only belong to one of its triangles, which
+/// is its outline without the edges between its triangles.
+pub fn face_outline(map: &BspMap, face: &Face) -> Vec<([f32; 3], [f32; 3])> {
+    let indices = match resolve_face(map, face, false) {
+        Some((indices, _)) => indices,
+        None => return Vec::new(),
+    };
+
+    let mut edges: HashMap<(u32, u32), usize> = HashMap::new();
+    for triangle in indices.chunks(3) {
+        if triangle.len() < 3 {
+            break;
+        }
+        for e in 0..3 {
+            let (a, b) = (triangle[e], triangle[(e + 1) % 3]);
+            *edges.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+        }
+    }
+    edges.iter()
+         .filter(|&(_, &count)| count == 1)
+         .map(|(&(a, b), _)| (map.vertexes[a as usize].position, map.vertexes[b as usize].position))
+         .collect()
+}
+
+/// Half the size of the square every brush side starts as before it's clipped to the brush.
+const BRUSH_SIDE_EXTENT: f32 = 65536.0;
+/// Points this close to a clipping plane count as on it.
+const CLIP_EPSILON: f32 = 0.01;
+
+/// Rebuilds the convex polygons that bound a brush from the planes of its sides, which is all
+/// the BSP keeps of brushes. Every side starts as a huge square in its plane and is clipped to
+/// the back of all other sides. Sides that are clipped away entirely, like the bevel planes the
+/// compiler adds for collision, are left out.
+pub fn brush_polygons(map: &BspMap, brush: &bsp_reader::Brush) -> Vec<Vec<[f32; 3]>> {
+    let planes: Vec<&bsp_reader::Plane> =
+        (0..brush.n_brushsides)
+            .filter_map(|i| map.brushsides.get((brush.brushside + i) as usize))
+            .filter_map(|side| map.planes.get(side.plane as usize))
+            .collect();
+
+    let mut polygons = Vec::new();
+    for (i, plane) in planes.iter().enumerate() {
+        let mut polygon = plane_square(plane);
+        for (j, other) in planes.iter().enumerate() {
+            if i != j {
+                polygon = clip_polygon(&polygon, other);
+                if polygon.len() < 3 {
+                    break;
+                }
+            }
+        }
+        if polygon.len() >= 3 {
+            polygons.push(polygon);
+        }
+    }
+    polygons
+}
+
+fn plane_square(plane: &bsp_reader::Plane) -> Vec<[f32; 3]> {
+    let normal = plane.normal;
+    // Any axis that isn't close to the normal works to span the plane
+    let axis = if normal[2].abs() < 0.9 {
+        [0.0, 0.0, 1.0]
+    } else {
+        [1.0, 0.0, 0.0]
+    };
+    let u = cross(normal, axis);
+    let u_length = dot(u, u).sqrt();
+    let u = [u[0] / u_length * BRUSH_SIDE_EXTENT,
+             u[1] / u_length * BRUSH_SIDE_EXTENT,
+             u[2] / u_length * BRUSH_SIDE_EXTENT];
+    let v = cross(normal, u);
+    let center = [normal[0] * plane.dist, normal[1] * plane.dist, normal[2] * plane.dist];
+    [(1.0, 1.0), (1.0, -1.0), (-1.0, -1.0), (-1.0, 1.0)]
+        .iter()
+        .map(|&(a, b)| {
+            [center[0] + u[0] * a + v[0] * b,
+             center[1] + u[1] * a + v[1] * b,
+             center[2] + u[2] * a + v[2] * b]
+        })
+        .collect()
+}
+
+/// Keeps the part of a convex polygon behind `plane`.
+fn clip_polygon(polygon: &[[f32; 3]], plane: &bsp_reader::Plane) -> Vec<[f32; 3]> {
+    let distances: Vec<f32> = polygon.iter().map(|&p| dot(p, plane.normal) - plane.dist).collect();
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for i in 0..polygon.len() {
+        let j = (i + 1) % polygon.len();
+        let (a, b) = (polygon[i], polygon[j]);
+        let (da, db) = (distances[i], distances[j]);
+        if da <= CLIP_EPSILON {
+            clipped.push(a);
+        }
+        if (da > CLIP_EPSILON && db < -CLIP_EPSILON) || (da < -CLIP_EPSILON && db > CLIP_EPSILON) {
+            let t = da / (da - db);
+            clipped.push([a[0] + (b[0] - a[0]) * t,
+                          a[1] + (b[1] - a[1]) * t,
+                          a[2] + (b[2] - a[2]) * t]);
+        }
+    }
+    clipped
+}
+
+/// Möller-Trumbore intersection of the ray `origin + t * direction` with a triangle from either
+/// side. Returns `t` if it's between 0 and 1.
+fn intersect_triangle(origin: [f32; 3],
+                      direction: [f32; 3],
+                      a: [f32; 3],
+                      b: [f32; 3],
+                      c: [f32; 3])
+                      -> Option<f32> {
+    let ab = sub(b, a);
+    let ac = sub(c, a);
+    let p = cross(direction, ac);
+    let determinant = dot(ab, p);
+    if determinant.abs() < 1e-6 {
+        return None;
+    }
+
+    let s = sub(origin, a);
+    let u = dot(s, p) / determinant;
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+    let q = cross(s, ab);
+    let v = dot(direction, q) / determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = dot(ac, q) / determinant;
+    if t >= 0.0 && t <= 1.0 {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+/// Keeps only the vertexes referenced by `indices`, merges duplicates and remaps the indices.
+/// Two vertexes are duplicates if all of their attributes are equal after quantization.
+pub fn compact(vertexes: &[Vertex], indices: &[u32]) -> (Vec<Vertex>, Vec<u32>) {
+    let mut compacted = Vec::new();
+    let mut remap: HashMap<[i32; 14], u32> = HashMap::new();
+    let mut new_indices = Vec::with_capacity(indices.len());
+
+    for &index in indices {
+        let vertex = vertexes[index as usize];
+        let new_index = *remap.entry(vertex_key(&vertex)).or_insert_with(|| {
+            compacted.push(vertex);
+            (compacted.len() - 1) as u32
+        });
+        new_indices.push(new_index);
+    }
+
+    (compacted, new_indices)
+}
+
+fn vertex_key(vertex: &Vertex) -> [i32; 14] {
+    let q = |f: f32| (f * QUANTIZATION).round() as i32;
+    let (position, normal, texcoord) = (vertex.position, vertex.normal, vertex.texcoord);
+    let color = vertex.color;
+    [q(position[0]),
+     q(position[1]),
+     q(position[2]),
+     q(normal[0]),
+     q(normal[1]),
+     q(normal[2]),
+     q(texcoord[0][0]),
+     q(texcoord[0][1]),
+     q(texcoord[1][0]),
+     q(texcoord[1][1]),
+     color[0] as i32,
+     color[1] as i32,
+     color[2] as i32,
+     color[3] as i32]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsp_reader::{self, Vertex};
+    use byteorder::{ByteOrder, LittleEndian};
+
+    fn vertex(position: [f32; 3]) -> Vertex {
+        Vertex {
+            position: position,
+            texcoord: [[0.0; 2]; 2],
+            normal: [0.0, 0.0, 1.0],
+            color: [255; 4],
+        }
+    }
+
+    /// A triangle in the xy plane, clockwise when seen from above.
+    fn triangle() -> Vec<Vertex> {
+        vec![vertex([0.0, 0.0, 0.0]), vertex([0.0, 1.0, 0.0]), vertex([1.0, 0.0, 0.0])]
+    }
+
+    #[test]
+    fn fix_winding_flips_only_counter_clockwise_triangles() {
+        let vertexes = triangle();
+        // The first triangle is already right, the second is wound the other way
+        let mut indices = vec![0, 1, 2, 0, 2, 1];
+        let fixed = fix_winding(&vertexes, &mut indices, Some([0.0, 0.0, 1.0]));
+        assert_eq!(fixed, 1);
+        assert_eq!(indices, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn fix_winding_uses_vertex_normals_without_a_face_normal() {
+        let vertexes = triangle();
+        let mut indices = vec![0, 2, 1];
+        assert_eq!(fix_winding(&vertexes, &mut indices, None), 1);
+        assert_eq!(indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn drop_degenerate_drops_triangles_without_area() {
+        let mut vertexes = triangle();
+        vertexes.push(vertex([2.0, 0.0, 0.0]));
+        // A good triangle, one with a repeated vertex and one along the x axis
+        let mut indices = vec![0, 1, 2, 0, 2, 2, 0, 2, 3];
+        assert_eq!(drop_degenerate(&vertexes, &mut indices, 0.0), 2);
+        assert_eq!(indices, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn drop_degenerate_drops_triangles_below_the_minimum_area() {
+        let mut vertexes = triangle();
+        vertexes.push(vertex([0.0, 0.01, 0.0]));
+        vertexes.push(vertex([0.01, 0.0, 0.0]));
+        let mut indices = vec![0, 3, 4, 0, 1, 2];
+        assert_eq!(drop_degenerate(&vertexes, &mut indices, 0.001), 1);
+        assert_eq!(indices, vec![0, 1, 2]);
+    }
+
+    fn default_map() -> BspMap {
+        bsp_reader::load_bytes(include_bytes!("../assets/default.bsp").to_vec()).unwrap()
+    }
+
+    #[test]
+    fn mesh_faces_resolve_through_their_meshverts_like_polygons() {
+        let mut map = default_map();
+        let (polygon, _) = resolve_face(&map, &map.faces[0], false).unwrap();
+        map.faces[0].f_type = 3;
+        assert_eq!(check_meshverts(&map, &map.faces[0]), Ok(()));
+        let (mesh, _) = resolve_face(&map, &map.faces[0], false).unwrap();
+
+        let face = &map.faces[0];
+        let range = face.meshvert as usize..(face.meshvert + face.n_meshverts) as usize;
+        let expected: Vec<u32> = map.meshverts[range]
+                                     .iter()
+                                     .map(|&m| (face.vertex + m) as u32)
+                                     .collect();
+        assert!(!mesh.is_empty());
+        assert_eq!(mesh, expected);
+        assert_eq!(mesh, polygon);
+    }
+
+    #[test]
+    fn check_meshverts_rejects_bad_meshverts() {
+        let mut map = default_map();
+        map.faces[0].f_type = 3;
+        map.faces[0].n_meshverts -= 1;
+        assert!(check_meshverts(&map, &map.faces[0]).is_err());
+
+        let mut map = default_map();
+        let first = map.faces[0].meshvert as usize;
+        map.meshverts[first] = map.faces[0].n_vertexes;
+        assert!(check_meshverts(&map, &map.faces[0]).is_err());
+        assert_eq!(resolve_face(&map, &map.faces[0], false), Some((Vec::new(), 0)));
+
+        let mut map = default_map();
+        map.faces[0].meshvert = ::std::i32::MAX;
+        assert!(check_meshverts(&map, &map.faces[0]).is_err());
+    }
+
+    #[test]
+    fn model_faces_gives_each_face_to_the_first_model_only() {
+        let mut map = default_map();
+        let (faces, shared) = model_faces(&map);
+        let count = map.faces.len();
+        assert_eq!(faces, (0..count).map(|f| (0, f)).collect::<Vec<_>>());
+        assert_eq!(shared, 0);
+
+        // A second model listing the faces of the world again
+        map.models.push(bsp_reader::Model {
+            mins: [0.0; 3],
+            maxs: [0.0; 3],
+            face: 0,
+            n_faces: count as i32,
+            brush: 0,
+            n_brushes: 0,
+        });
+        let (faces, shared) = model_faces(&map);
+        assert_eq!(faces, (0..count).map(|f| (0, f)).collect::<Vec<_>>());
+        assert_eq!(shared, count);
+    }
+
+    #[test]
+    fn a_map_without_models_loads_with_nothing_to_show() {
+        // Empty the models lump, the eighth entry of the lump directory
+        let mut data = include_bytes!("../assets/default.bsp").to_vec();
+        LittleEndian::write_i32(&mut data[8 + 7 * 8 + 4..], 0);
+        let map = bsp_reader::load_bytes(data).unwrap();
+        assert!(map.models.is_empty());
+        assert!(bsp_reader::validate_references(&map).is_empty());
+        assert_eq!(model_faces(&map), (Vec::new(), 0));
+        assert_eq!(model_view(&map, 0), None);
+    }
+
+    #[test]
+    fn model_view_sees_the_whole_model() {
+        let map = default_map();
+        let (center, distance) = model_view(&map, 0).unwrap();
+        let model = &map.models[0];
+        for a in 0..3 {
+            assert_eq!(center[a], (model.mins[a] + model.maxs[a]) / 2.0);
+        }
+        let half = sub(model.maxs, center);
+        assert!(distance >= dot(half, half).sqrt());
+    }
+
+    #[test]
+    fn faces_near_finds_the_wall_next_to_the_point() {
+        let map = default_map();
+        let bounds = face_bounds(&map);
+        let near = faces_near(&map, &bounds, [200.0, 0.0, 64.0], 64.0);
+        assert_eq!(near.len(), 1);
+        assert_eq!((bounds[near[0]].0)[0], 256.0);
+        assert!(faces_near(&map, &bounds, [0.0, 0.0, 64.0], 64.0).is_empty());
+    }
+
+    #[test]
+    fn faces_near_matches_checking_every_face() {
+        let map = default_map();
+        let bounds = face_bounds(&map);
+        let points: [[f32; 3]; 3] = [[0.0, 0.0, 0.0],
+                                     [200.0, -220.0, 150.0],
+                                     [-250.0, 250.0, -60.0]];
+        let within = |point: [f32; 3], radius: f32, (mins, maxs): ([f32; 3], [f32; 3])| {
+            let closest = [point[0].max(mins[0]).min(maxs[0]),
+                           point[1].max(mins[1]).min(maxs[1]),
+                           point[2].max(mins[2]).min(maxs[2])];
+            let offset = sub(point, closest);
+            dot(offset, offset) <= radius * radius
+        };
+        for &point in &points {
+            for &radius in &[1.0, 50.0, 130.0, 400.0] {
+                let expected: Vec<usize> = (0..bounds.len())
+                                               .filter(|&f| within(point, radius, bounds[f]))
+                                               .collect();
+                assert_eq!(faces_near(&map, &bounds, point, radius),
+                           expected,
+                           "{:?} within {}",
+                           point,
+                           radius);
+            }
+        }
+    }
+
+    #[test]
+    fn face_bounds_and_centroids_clamp_vertex_ranges_that_overflow_i32() {
+        let mut map = default_map();
+        map.faces[0].vertex = ::std::i32::MAX - 1;
+        map.faces[1].vertex = -10;
+        map.faces[1].n_vertexes = ::std::i32::MAX;
+
+        let centroids = face_centroids(&map);
+        assert_eq!(centroids[0], None);
+        assert!(centroids[1].is_some());
+        let bounds = face_bounds(&map);
+        assert_eq!(bounds[0], ([0.0; 3], [0.0; 3]));
+        assert_eq!(bounds[1], bsp_reader::world_bounds(&map.vertexes));
+    }
+
+    #[test]
+    fn compact_merges_vertexes_within_the_tolerance_and_remaps_the_indices() {
+        let mut vertexes = triangle();
+        // A copy of vertex 1 with float noise, and one that's really different
+        vertexes.push(vertex([0.0, 1.00001, 0.0]));
+        vertexes.push(vertex([0.0, 1.01, 0.0]));
+        let (compacted, indices) = compact(&vertexes, &[2, 1, 0, 2, 3, 0, 4, 3, 0]);
+        assert_eq!(indices, vec![0, 1, 2, 0, 1, 2, 3, 1, 2]);
+        let positions: Vec<[f32; 3]> = compacted.iter().map(|v| v.position).collect();
+        assert_eq!(positions,
+                   vec![[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 0.0], [0.0, 1.01, 0.0]]);
+    }
+
+    #[test]
+    fn compact_keeps_vertexes_with_different_attributes_apart() {
+        let mut vertexes = triangle();
+        let mut lit = vertexes[0];
+        lit.color = [0, 0, 0, 255];
+        vertexes.push(lit);
+        let (compacted, indices) = compact(&vertexes, &[0, 1, 2, 3, 1, 2]);
+        assert_eq!(compacted.len(), 4);
+        assert_eq!(indices, vec![0, 1, 2, 3, 1, 2]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::cmp::Ordering;
+use bsp_reader::BspMap;
+use geometry::{self, cross, dot, sub};
+use lines::LineVertex;
+
+/// Faces further away than this from the camera get no label.
+const LABEL_RADIUS: f32 = 512.0;
+/// Labels on at most this many of the nearest faces, more are unreadable.
+const MAX_LABELS: usize = 8;
+/// Height of a label's letters relative to its distance, so labels stay the same size on screen.
+const LETTER_HEIGHT: f32 = 0.015;
+
+/// Segments of a 16 segment display in a cell 1 wide and 2 high. Bit `i` of a glyph lights
+/// segment `i`: the outline runs clockwise from the top left in bits 0 to 7, bits 8 to 15 are
+/// the spokes from the center, clockwise from the top left.
+const SEGMENTS: [([f32; 2], [f32; 2]); 16] = [([0.0, 2.0], [0.5, 2.0]),
+                                              ([0.5, 2.0], [1.0, 2.0]),
+                                              ([1.0, 2.0], [1.0, 1.0]),
+                                              ([1.0, 1.0], [1.0, 0.0]),
+                                              ([1.0, 0.0], [0.5, 0.0]),
+                                              ([0.5, 0.0], [0.0, 0.0]),
+                                              ([0.0, 0.0], [0.0, 1.0]),
+                                              ([0.0, 1.0], [0.0, 2.0]),
+                                              ([0.5, 1.0], [0.0, 2.0]),
+                                              ([0.5, 1.0], [0.5, 2.0]),
+                                              ([0.5, 1.0], [1.0, 2.0]),
+                                              ([0.5, 1.0], [1.0, 1.0]),
+                                              ([0.5, 1.0], [1.0, 0.0]),
+                                              ([0.5, 1.0], [0.5, 0.0]),
+                                              ([0.5, 1.0], [0.0, 0.0]),
+                                              ([0.5, 1.0], [0.0, 1.0])];
+
+/// Glyphs for what shows up in texture names and the console, letters are upper case only.
+const GLYPHS: [(char, u16); 43] = [
+    ('A', 0x88cf), ('B', 0x2a3f), ('C', 0x00f3), ('D', 0x223f), ('E', 0x80f3), ('F', 0x80c3),
+    ('G', 0x08fb), ('H', 0x88cc), ('I', 0x2233), ('J', 0x007c), ('K', 0x94c0), ('L', 0x00f0),
+    ('M', 0x05cc), ('N', 0x11cc), ('O', 0x00ff), ('P', 0x88c7), ('Q', 0x10ff), ('R', 0x98c7),
+    ('S', 0x88bb), ('T', 0x2203), ('U', 0x00fc), ('V', 0x44c0), ('W', 0x50cc), ('X', 0x5500),
+    ('Y', 0x2500), ('Z', 0x4433), ('0', 0x44ff), ('1', 0x040c), ('2', 0x8877), ('3', 0x083f),
+    ('4', 0x888c), ('5', 0x90b3), ('6', 0x88fb), ('7', 0x000f), ('8', 0x88ff), ('9', 0x88bf),
+    ('/', 0x4400), ('_', 0x0030), ('-', 0x8800), ('+', 0xaa00), ('.', 0x0020), (',', 0x4000),
+    (']', 0x001e)];
+
+/// Adds `text` as line segments centered on `center`, running along `right` with its letters
+/// `height` units high along `up`. Characters without a glyph are left blank.
+pub fn text_lines(vertexes: &mut Vec<LineVertex>,
+                  text: &str,
+                  center: [f32; 3],
+                  right: [f32; 3],
+                  up: [f32; 3],
+                  height: f32,
+                  color: [f32; 3]) {
+    let scale = height / 2.0;
+    let advance = 1.5;
+    let width = text.chars().count() as f32 * advance - 0.5;
+    let point = |x: f32, y: f32| {
+        let (x, y) = ((x - width / 2.0) * scale, (y - 1.0) * scale);
+        [center[0] + right[0] * x + up[0] * y,
+         center[1] + right[1] * x + up[1] * y,
+         center[2] + right[2] * x + up[2] * y]
+    };
+
+    for (i, c) in text.chars().enumerate() {
+        let upper = c.to_uppercase().next().unwrap_or(c);
+        let glyph = match GLYPHS.iter().find(|g| g.0 == upper) {
+            Some(&(_, glyph)) => glyph,
+            None => continue,
+        };
+        let left = i as f32 * advance;
+        for (bit, &(from, to)) in SEGMENTS.iter().enumerate() {
+            if glyph & (1 << bit) != 0 {
+                vertexes.push(LineVertex {
+                    position: point(left + from[0], from[1]),
+                    color: color,
+                });
+                vertexes.push(LineVertex {
+                    position: point(left + to[0], to[1]),
+                    color: color,
+                });
+            }
+        }
+    }
+}
+
+/// Adds labels with the texture names of the faces nearest to `camera` that are in front of it,
+/// facing the camera. `bounds` and `centroids` are those of `geometry::face_bounds` and
+/// `geometry::face_centroids`, everything is in Quake 3 space.
+pub fn face_labels(vertexes: &mut Vec<LineVertex>,
+                   map: &BspMap,
+                   bounds: &[([f32; 3], [f32; 3])],
+                   centroids: &[Option<[f32; 3]>],
+                   camera: [f32; 3],
+                   forward: [f32; 3]) {
+    let mut faces: Vec<(usize, [f32; 3], f32)> =
+        geometry::faces_near(map, bounds, camera, LABEL_RADIUS)
+            .into_iter()
+            .filter_map(|f| centroids.get(f).and_then(|&c| c).map(|c| (f, c)))
+            .filter(|&(_, c)| dot(sub(c, camera), forward) > 0.0)
+            .map(|(f, c)| {
+                let offset = sub(c, camera);
+                (f, c, dot(offset, offset).sqrt())
+            })
+            .collect();
+    faces.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal));
+
+    // Billboarded, so the text lies in the plane facing the camera with z staying up
+    let right = normalize(cross(forward, [0.0, 0.0, 1.0]));
+    let up = cross(right, forward);
+    for &(face, centroid, distance) in faces.iter().take(MAX_LABELS) {
+        let texture = map.faces[face].texture as usize;
+        let name = match map.textures.get(texture) {
+            Some(texture) => texture.name.trim_left_matches("textures/"),
+            None => continue,
+        };
+        // Lifted off the face so it isn't hidden in its surface
+        let normal = map.faces[face].normal;
+        let center = [centroid[0] + normal[0] * 2.0,
+                      centroid[1] + normal[1] * 2.0,
+                      centroid[2] + normal[2] * 2.0];
+        text_lines(vertexes,
+                   name,
+                   center,
+                   right,
+                   up,
+                   distance * LETTER_HEIGHT,
+                   [1.0, 1.0, 0.4]);
+    }
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = dot(v, v).sqrt();
+    if length == 0.0 {
+        // Looking straight up or down, any horizontal axis works
+        return [1.0, 0.0, 0.0];
+    }
+    [v[0] / length, v[1] / length, v[2] / length]
+}
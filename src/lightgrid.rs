@@ -0,0 +1,97 @@
+use std::f32::consts::PI;
+use bsp_reader::BspMap;
+
+/// Size of a light grid cell, the default gridsize of the worldspawn.
+pub const CELL_SIZE: [f32; 3] = [64.0, 64.0, 128.0];
+
+/// Returns the grid origin and the number of cells along each axis, computed from the bounds of
+/// the world model like the engine does.
+pub fn grid_dimensions(map: &BspMap) -> Option<([f32; 3], [usize; 3])> {
+    let world = match map.models.get(0) {
+        Some(world) => world,
+        None => return None,
+    };
+    let mut origin = [0.0; 3];
+    let mut counts = [0; 3];
+    for i in 0..3 {
+        origin[i] = CELL_SIZE[i] * (world.mins[i] / CELL_SIZE[i]).ceil();
+        let max = CELL_SIZE[i] * (world.maxs[i] / CELL_SIZE[i]).floor();
+        counts[i] = ((max - origin[i]) / CELL_SIZE[i]).max(0.0) as usize + 1;
+    }
+    Some((origin, counts))
+}
+
+/// Interpolates the light grid at `point` like the engine does for models. Returns the ambient
+/// and directional colors in 0..1 and the unit direction towards the light. Cells inside solid
+/// geometry are black and left out, and points outside of the grid use the nearest cells.
+/// Returns `None` if the map has no light grid.
+pub fn sample_lightgrid(map: &BspMap, point: [f32; 3]) -> Option<([f32; 3], [f32; 3], [f32; 3])> {
+    let (origin, counts) = match grid_dimensions(map) {
+        Some(dimensions) => dimensions,
+        None => return None,
+    };
+    if map.lightvols.len() != counts[0] * counts[1] * counts[2] {
+        return None;
+    }
+
+    let mut base = [0; 3];
+    let mut fraction = [0.0; 3];
+    for i in 0..3 {
+        let position = ((point[i] - origin[i]) / CELL_SIZE[i])
+                           .max(0.0)
+                           .min((counts[i] - 1) as f32);
+        base[i] = (position.floor() as usize).min(counts[i].saturating_sub(2));
+        fraction[i] = position - base[i] as f32;
+    }
+
+    let mut ambient = [0.0; 3];
+    let mut directional = [0.0; 3];
+    let mut direction = [0.0; 3];
+    let mut total_factor = 0.0;
+    for corner in 0..8 {
+        let mut index = [0; 3];
+        let mut factor = 1.0;
+        for i in 0..3 {
+            if corner & (1 << i) != 0 {
+                index[i] = (base[i] + 1).min(counts[i] - 1);
+                factor *= fraction[i];
+            } else {
+                index[i] = base[i];
+                factor *= 1.0 - fraction[i];
+            }
+        }
+
+        let cell = &map.lightvols[index[0] + counts[0] * (index[1] + counts[1] * index[2])];
+        if cell.ambient.iter().chain(cell.directional.iter()).all(|&c| c == 0) {
+            continue;
+        }
+        total_factor += factor;
+
+        let longitude = cell.dir[0] as f32 * 2.0 * PI / 256.0;
+        let latitude = cell.dir[1] as f32 * 2.0 * PI / 256.0;
+        let normal = [latitude.cos() * longitude.sin(),
+                      latitude.sin() * longitude.sin(),
+                      longitude.cos()];
+        for i in 0..3 {
+            ambient[i] += factor * cell.ambient[i] as f32 / 255.0;
+            directional[i] += factor * cell.directional[i] as f32 / 255.0;
+            direction[i] += factor * normal[i];
+        }
+    }
+
+    if total_factor > 0.0 && total_factor < 0.99 {
+        for i in 0..3 {
+            ambient[i] /= total_factor;
+            directional[i] /= total_factor;
+        }
+    }
+    let length = (direction[0] * direction[0] + direction[1] * direction[1] +
+                  direction[2] * direction[2])
+                     .sqrt();
+    if length > 0.0 {
+        for d in &mut direction {
+            *d /= length;
+        }
+    }
+    Some((ambient, directional, direction))
+}
@@ -0,0 +1,90 @@
+use glium;
+use glium::Surface;
+
+#[derive(Copy, Clone)]
+pub struct LineVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+}
+
+implement_vertex!(LineVertex, position, color);
+
+/// Collects colored line segments during a frame and draws them in one call.
+pub struct LineRenderer {
+    program: glium::Program,
+    vertexes: Vec<LineVertex>,
+}
+
+impl LineRenderer {
+    pub fn new(program: glium::Program) -> LineRenderer {
+        LineRenderer {
+            program: program,
+            vertexes: Vec::new(),
+        }
+    }
+
+    /// Adds pairs of vertexes as segments, like the ones from `primitives::box_lines`.
+    pub fn lines(&mut self, vertexes: &[LineVertex]) {
+        self.vertexes.extend_from_slice(vertexes);
+    }
+
+    pub fn line(&mut self, from: [f32; 3], to: [f32; 3], color: [f32; 3]) {
+        self.vertexes.push(LineVertex {
+            position: from,
+            color: color,
+        });
+        self.vertexes.push(LineVertex {
+            position: to,
+            color: color,
+        });
+    }
+
+    /// Draws and clears the collected lines. Without `depth_test` the lines are drawn on top of
+    /// everything, which is what screen space overlays want.
+    pub fn draw<F, S>(&mut self,
+                      display: &F,
+                      target: &mut S,
+                      mvp: [[f32; 4]; 4],
+                      depth_test: bool)
+        where F: glium::backend::Facade,
+              S: Surface
+    {
+        if self.vertexes.is_empty() {
+            return;
+        }
+
+        let vertex_buffer = glium::VertexBuffer::dynamic(display, &self.vertexes).unwrap();
+        self.draw_buffer(target, &vertex_buffer, mvp, depth_test);
+        self.vertexes.clear();
+    }
+
+    /// Draws a prebuilt buffer of line vertexes, for lines that don't change between frames.
+    pub fn draw_buffer<S>(&self,
+                          target: &mut S,
+                          vertex_buffer: &glium::VertexBuffer<LineVertex>,
+                          mvp: [[f32; 4]; 4],
+                          depth_test: bool)
+        where S: Surface
+    {
+        let test = if depth_test {
+            glium::draw_parameters::DepthTest::IfLessOrEqual
+        } else {
+            glium::draw_parameters::DepthTest::Overwrite
+        };
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: test,
+                write: false,
+                ..Default::default()
+            },
+            line_width: Some(2.0),
+            ..Default::default()
+        };
+        target.draw(vertex_buffer,
+                    &glium::index::NoIndices(glium::index::PrimitiveType::LinesList),
+                    &self.program,
+                    &uniform!{mvp: mvp},
+                    &params)
+              .unwrap();
+    }
+}
@@ -1,128 +1,992 @@
-mod bsp_reader;
-
 extern crate byteorder;
 #[macro_use]
 extern crate glium;
 extern crate cgmath;
 extern crate time;
 extern crate image;
+extern crate toml;
+extern crate flate2;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+
+mod areas;
+mod benchmark;
+mod bsp_reader;
+mod camera_path;
+mod collision;
+mod commands;
+mod config;
+mod coords;
+mod console;
+mod entities;
+mod export;
+mod geometry;
+mod lightgrid;
+mod labels;
+mod lines;
+mod materials;
+mod minimap;
+mod movement;
+mod occlusion;
+mod patch;
+mod pk3;
+mod primitives;
+mod resolution;
+mod screenshot;
+mod shader;
+mod viewmodel;
+mod volumes;
 
-use bsp_reader::BSPReader;
+use areas::Areas;
+use benchmark::{Benchmark, Comparison};
+use camera_path::CameraPath;
+use config::{Config, CrosshairStyle};
+use console::{Command, Console};
+use lines::{LineRenderer, LineVertex};
+use movement::Player;
+use occlusion::OcclusionCuller;
+use resolution::DynamicResolution;
+use screenshot::Screenshot;
+use minimap::Minimap;
+use viewmodel::Viewmodel;
+use volumes::{VolumeKind, Volumes};
 use glium::{DisplayBuild, Surface};
+use glium::backend::Facade;
+use glium::draw_parameters::SamplesPassedQuery;
 use std::fs::File;
-use std::io::Read;
+use std::env;
+use std::process;
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::mem;
+use std::usize;
 use glium::glutin::*;
 use cgmath::*;
 
 #[derive(Copy, Clone)]
 struct Vertex {
     position: [f32; 3],
+    texcoord: [[f32; 2]; 2],
     normal: [f32; 3],
     color: [f32; 4],
+    /// 1 for surfaces inside a fog volume.
+    fog_volume: f32,
+    /// `f_type` of the face the vertex belongs to.
+    face_type: f32,
+}
+
+implement_vertex!(Vertex, position, texcoord, normal, color, fog_volume, face_type);
+
+const NORMAL_LENGTH: f32 = 8.0;
+
+const CLEAR_COLOR: [f32; 3] = [0.8, 0.8, 1.0];
+
+/// Entity lights passed to the shader at once, has to match world.frag.
+const MAX_LIGHTS: usize = 8;
+
+/// Names and colors of the face types 1 to 4 in the face type render mode, the colors have to
+/// match world.frag.
+const FACE_TYPE_NAMES: [&'static str; 4] = ["polygon", "patch", "mesh", "billboard"];
+const FACE_TYPE_COLORS: [[f32; 3]; 4] = [[0.6, 0.6, 0.6],
+                                         [1.0, 0.5, 0.1],
+                                         [0.2, 0.8, 1.0],
+                                         [1.0, 0.2, 0.8]];
+/// Letter height of the face type legend in pixels.
+const LEGEND_HEIGHT: f32 = 12.0;
+
+/// Phases of a frame timed by the profiler. Overlays include everything drawn after the world,
+/// the occlusion queries and stretching the scaled framebuffer over the window.
+const PHASES: [&'static str; 4] = ["culling", "opaque", "water", "overlays"];
+const PHASE_CULLING: usize = 0;
+const PHASE_OPAQUE: usize = 1;
+const PHASE_WATER: usize = 2;
+const PHASE_OVERLAYS: usize = 3;
+
+const PATCH_LOD_LEVELS: [usize; 3] = [10, 5, 2];
+const PATCH_LOD_DISTANCES: [f32; 2] = [600.0, 1800.0];
+
+/// A room of 512 by 512 units with a spawn point and a light, built in with the `default-map`
+/// feature. It has a single leaf, no lightmaps and its one texture has no image.
+#[cfg(feature = "default-map")]
+const DEFAULT_MAP: Option<&'static [u8]> = Some(include_bytes!("../assets/default.bsp"));
+#[cfg(not(feature = "default-map"))]
+const DEFAULT_MAP: Option<&'static [u8]> = None;
+
+struct Batch {
+    model: usize,
+    /// Clusters that reference the faces of this batch. The batch is keyed by the first cluster
+    /// of each face, but faces spanning several clusters are seen from all of them. Empty if
+    /// batches aren't split by cluster.
+    clusters: Vec<i32>,
+    texture: i32,
+    lightmap: i32,
+    /// Average of the batch vertexes in render space, used to draw roughly front to back.
+    center: Point3<f32>,
+    /// Distance from `center` to the farthest vertex of the batch.
+    radius: f32,
+    faces: usize,
+    indices: glium::index::IndexBuffer<u32>,
+}
+
+struct PatchLods {
+    model: usize,
+    /// Clusters that reference the patch, empty if batches aren't split by cluster.
+    clusters: Vec<i32>,
+    texture: i32,
+    lightmap: i32,
+    center: Point3<f32>,
+    /// Distance from `center` to the farthest control point, which the surface never leaves.
+    radius: f32,
+    /// Center of all patches connected to this one in render space. The level is chosen by its
+    /// distance, so connected patches switch together and their welded edges keep matching.
+    lod_center: Point3<f32>,
+    lods: Vec<glium::index::IndexBuffer<u32>>,
+}
+
+impl PatchLods {
+    fn select(&self, camera_pos: Point3<f32>) -> &glium::index::IndexBuffer<u32> {
+        let distance = (self.lod_center - camera_pos).length();
+        let lod = PATCH_LOD_DISTANCES.iter().take_while(|&&d| distance > d).count();
+        &self.lods[lod]
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum RenderMode {
+    Textured,
+    TexturedLightmap,
+    VertexColor,
+    Lightmap,
+    Normals,
+    FlatWhite,
+    Deluxemap,
+    LightmapUv,
+    EntityLights,
+    FaceType,
+}
+
+impl RenderMode {
+    fn next(self) -> RenderMode {
+        match self {
+            RenderMode::Textured => RenderMode::TexturedLightmap,
+            RenderMode::TexturedLightmap => RenderMode::VertexColor,
+            RenderMode::VertexColor => RenderMode::Lightmap,
+            RenderMode::Lightmap => RenderMode::Normals,
+            RenderMode::Normals => RenderMode::FlatWhite,
+            RenderMode::FlatWhite => RenderMode::Deluxemap,
+            RenderMode::Deluxemap => RenderMode::LightmapUv,
+            RenderMode::LightmapUv => RenderMode::EntityLights,
+            RenderMode::EntityLights => RenderMode::FaceType,
+            RenderMode::FaceType => RenderMode::Textured,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<RenderMode> {
+        match name {
+            "textured" => Some(RenderMode::Textured),
+            "texturedlightmap" => Some(RenderMode::TexturedLightmap),
+            "vertexcolor" => Some(RenderMode::VertexColor),
+            "lightmap" => Some(RenderMode::Lightmap),
+            "normals" => Some(RenderMode::Normals),
+            "flatwhite" => Some(RenderMode::FlatWhite),
+            "deluxemap" => Some(RenderMode::Deluxemap),
+            "lightmapuv" => Some(RenderMode::LightmapUv),
+            "entitylights" => Some(RenderMode::EntityLights),
+            "facetype" => Some(RenderMode::FaceType),
+            _ => None,
+        }
+    }
+}
+
+/// The lights closest to the camera for the entity lights render mode. Positions are in render
+/// space with the intensity in w, colors are vec4 to match the std140 array stride.
+#[derive(Copy, Clone)]
+struct LightBlock {
+    light_positions: [[f32; 4]; MAX_LIGHTS],
+    light_colors: [[f32; 4]; MAX_LIGHTS],
+    light_count: i32,
+}
+
+implement_uniform_block!(LightBlock, light_positions, light_colors, light_count);
+
+/// What a pass clears before it draws. A pass that covers every pixel, like a skybox would,
+/// doesn't need the color cleared, and passes drawn on top of others like the viewmodel only
+/// need a fresh depth buffer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Clear {
+    ColorAndDepth([f32; 3]),
+    Depth,
+}
+
+impl Clear {
+    fn apply<S: Surface>(self, target: &mut S) {
+        match self {
+            Clear::ColorAndDepth(c) => target.clear_color_and_depth((c[0], c[1], c[2], 1.0), 1.0),
+            Clear::Depth => target.clear_depth(1.0),
+        }
+    }
+}
+
+#[derive(Default)]
+struct RenderStats {
+    draw_calls: usize,
+    triangles: usize,
+    /// Faces in batches skipped for being smaller than `min_screen_size`.
+    skipped_faces: usize,
+    /// CPU time of each of `PHASES` with the profiler on. Draw calls return before the GPU is
+    /// done, so the draw phases measure how long it takes to submit them.
+    phase_ns: [u64; 4],
+    /// Size of the target the world was drawn into.
+    pixels: u32,
+}
+
+/// Current time in nanoseconds if `profiling`, otherwise always 0 so the differences of
+/// unprofiled frames are 0 without reading the clock.
+fn profile_time(profiling: bool) -> u64 {
+    if profiling {
+        time::precise_time_ns()
+    } else {
+        0
+    }
 }
 
-implement_vertex!(Vertex, position, normal, color);
+/// A draw of part of the world: texture, lightmap and the indices into the world vertex buffer.
+type Draw<'a> = (i32, i32, &'a glium::index::IndexBuffer<u32>);
+
+struct World {
+    vertex_buffer: glium::VertexBuffer<Vertex>,
+    program: glium::Program,
+    textures: HashMap<i32, glium::texture::SrgbTexture2d>,
+    /// Second texture of terrain blends by texture index, mixed in by vertex alpha.
+    blend_textures: HashMap<i32, glium::texture::SrgbTexture2d>,
+    lightmaps: HashMap<i32, glium::texture::SrgbTexture2d>,
+    /// Deluxemaps by the index of the lightmap they belong to.
+    deluxemaps: HashMap<i32, glium::texture::Texture2d>,
+    white: glium::texture::SrgbTexture2d,
+    /// Deluxemap fallback for lightmaps without one.
+    straight_up: glium::texture::Texture2d,
+    lights: glium::uniforms::UniformBuffer<LightBlock>,
+    /// Indices of textures with water contents, drawn in the water pass.
+    water_textures: HashSet<i32>,
+    backface_culling: bool,
+    /// Indices of textures whose shader is drawn from both sides even with backface culling.
+    double_sided: HashSet<i32>,
+    /// Indices of textures whose pixels are discarded below half alpha.
+    alpha_tested: HashSet<i32>,
+    /// Depth state of the opaque world, toggled at runtime to debug z-fighting.
+    depth_write: bool,
+    depth_less_or_equal: bool,
+    /// Whether the frame phases are timed.
+    profile: bool,
+}
+
+#[derive(Copy, Clone)]
+struct WorldUniforms {
+    model: [[f32; 4]; 4],
+    view: [[f32; 4]; 4],
+    perspective: [[f32; 4]; 4],
+    render_mode: RenderMode,
+    fog_color: [f32; 3],
+    fog_density: f32,
+    fog_volume_color: [f32; 3],
+    fog_volume_density: f32,
+    flip_lightmap_v: bool,
+    modulate_vertex_colors: bool,
+    /// Seconds since the start, for the water warp.
+    time: f32,
+    water_tint: [f32; 3],
+    water_opacity: f32,
+    water_warp: f32,
+    water_warp_speed: f32,
+}
+
+impl World {
+    fn is_water(&self, texture: i32) -> bool {
+        self.water_textures.contains(&texture)
+    }
+
+    fn print_depth_state(&self) {
+        println!("Depth write {}, depth test {}",
+                 if self.depth_write { "on" } else { "off" },
+                 if self.depth_less_or_equal { "IfLessOrEqual" } else { "IfLess" });
+    }
+
+    /// Draws `draws` opaque and then `water_draws`, which should be sorted back to front, see
+    /// through on top of them. Nothing is cleared, that's up to the caller. Fragments that pass
+    /// the depth test are counted in `fragments`.
+    fn draw<S: Surface>(&self,
+                        target: &mut S,
+                        draws: &[Draw],
+                        water_draws: &[Draw],
+                        uniforms: WorldUniforms,
+                        wireframe: bool,
+                        depth_prepass: bool,
+                        fragments: Option<&SamplesPassedQuery>)
+                        -> RenderStats {
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: if self.depth_less_or_equal {
+                    glium::draw_parameters::DepthTest::IfLessOrEqual
+                } else {
+                    glium::draw_parameters::DepthTest::IfLess
+                },
+                write: self.depth_write,
+                ..Default::default()
+            },
+            polygon_mode: if wireframe {
+                glium::draw_parameters::PolygonMode::Line
+            } else {
+                glium::draw_parameters::PolygonMode::Fill
+            },
+            // Quake 3 winds front faces clockwise
+            backface_culling: if self.backface_culling {
+                glium::draw_parameters::BackfaceCullingMode::CullCounterClockwise
+            } else {
+                glium::draw_parameters::BackfaceCullingMode::CullingDisabled
+            },
+            samples_passed_query: fragments.map(|q| q.into()),
+            ..Default::default()
+        };
+        // The prepass only fills the depth buffer, so the color pass shades every pixel once. It
+        // uses the same program so both passes produce exactly the same depth. Without depth
+        // writes there is nothing for the second pass to compare with.
+        let passes = if depth_prepass && !wireframe && self.depth_write {
+            vec![glium::DrawParameters { color_mask: (false, false, false, false), ..params.clone() },
+                 glium::DrawParameters {
+                     depth: glium::Depth {
+                         test: glium::draw_parameters::DepthTest::IfEqual,
+                         write: false,
+                         ..Default::default()
+                     },
+                     ..params.clone()
+                 }]
+        } else {
+            vec![params.clone()]
+        };
+
+        let (width, height) = target.get_dimensions();
+        let mut stats = RenderStats { pixels: width * height, ..RenderStats::default() };
+        let opaque_start = profile_time(self.profile);
+        for params in &passes {
+            self.draw_pass(target, draws, &uniforms, params, false, &mut stats);
+        }
+        stats.phase_ns[PHASE_OPAQUE] = profile_time(self.profile) - opaque_start;
+
+        // Water is blended over what's behind it, so it doesn't hide the surfaces behind other
+        // water from the depth test
+        let water_params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: false,
+                ..Default::default()
+            },
+            blend: glium::Blend::alpha_blending(),
+            ..params
+        };
+        let water_start = profile_time(self.profile);
+        self.draw_pass(target, water_draws, &uniforms, &water_params, true, &mut stats);
+        stats.phase_ns[PHASE_WATER] = profile_time(self.profile) - water_start;
+        stats
+    }
+
+    fn draw_pass<S: Surface>(&self,
+                             target: &mut S,
+                             draws: &[Draw],
+                             uniforms: &WorldUniforms,
+                             params: &glium::DrawParameters,
+                             water: bool,
+                             stats: &mut RenderStats) {
+        let double_sided_params = glium::DrawParameters {
+            backface_culling: glium::draw_parameters::BackfaceCullingMode::CullingDisabled,
+            ..params.clone()
+        };
+        for &(texture, lightmap, indices) in draws {
+            let params = if self.double_sided.contains(&texture) {
+                &double_sided_params
+            } else {
+                params
+            };
+            let uniforms = uniform!{
+                model: uniforms.model,
+                view: uniforms.view,
+                perspective: uniforms.perspective,
+                render_mode: uniforms.render_mode as i32,
+                diffuse_map: self.textures.get(&texture).unwrap_or(&self.white),
+                blend_map: self.blend_textures.get(&texture).unwrap_or(&self.white),
+                blend: self.blend_textures.contains_key(&texture),
+                alpha_test: self.alpha_tested.contains(&texture),
+                lightmap: self.lightmaps.get(&lightmap).unwrap_or(&self.white),
+                deluxemap: self.deluxemaps.get(&lightmap).unwrap_or(&self.straight_up),
+                fog_color: uniforms.fog_color,
+                fog_density: uniforms.fog_density,
+                fog_volume_color: uniforms.fog_volume_color,
+                fog_volume_density: uniforms.fog_volume_density,
+                flip_lightmap_v: uniforms.flip_lightmap_v,
+                modulate_vertex_colors: uniforms.modulate_vertex_colors,
+                Lights: &self.lights,
+                water: water,
+                time: uniforms.time,
+                water_tint: uniforms.water_tint,
+                water_opacity: uniforms.water_opacity,
+                water_warp: uniforms.water_warp,
+                water_warp_speed: uniforms.water_warp_speed,
+            };
+            target.draw(&self.vertex_buffer, indices, &self.program, &uniforms, params).unwrap();
+            stats.draw_calls += 1;
+            stats.triangles += indices.len() / 3;
+        }
+    }
+}
 
 fn main() {
-    let mut bsp = BSPReader::new("data/maps/q3dm0.bsp");
-    let header = bsp.read_header();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(code) = commands::run(&args) {
+        process::exit(code);
+    }
+
+    let mut config = Config::from_args(&args);
+
+    // Without a map of its own the binary can still show the room built into it
+    let use_default_map = DEFAULT_MAP.is_some() && config.map == Config::default().map &&
+                          !std::path::Path::new(&config.map).exists();
+    let loaded = match DEFAULT_MAP {
+        Some(data) if use_default_map => {
+            println!("{} doesn't exist, showing the built in room", config.map);
+            bsp_reader::load_bytes(data.to_vec())
+        }
+        _ => bsp_reader::load(&config.map),
+    };
+    let map = match loaded {
+        Ok(map) => map,
+        Err(error) => {
+            println!("Couldn't load {}: {}", config.map, error);
+            process::exit(1);
+        }
+    };
+    let reference_errors = bsp_reader::validate_references(&map);
+    if !reference_errors.is_empty() {
+        for error in &reference_errors {
+            println!("{}", error);
+        }
+        println!("Couldn't load {}: {} broken references", config.map, reference_errors.len());
+        process::exit(1);
+    }
+    // Every face belongs to a model, so without models there's nothing to draw, but the camera
+    // and console still work
+    if map.models.is_empty() {
+        println!("Warning: {} has no models, nothing will be drawn", config.map);
+    }
 
-    let display = glium::glutin::WindowBuilder::new()
-                      .with_title("Guac - Quake III in shit".to_owned())
-                      .with_dimensions(1280, 720)
-                      .with_depth_buffer(24)
-                      .build_glium()
-                      .unwrap();
+    let display = match build_display(&config.render, config.render.samples) {
+        Ok(display) => display,
+        Err(error) if config.render.samples > 0 => {
+            println!("Couldn't create a window with {}x MSAA ({}), disabling it",
+                     config.render.samples,
+                     error);
+            build_display(&config.render, 0).unwrap()
+        }
+        Err(error) => panic!("Couldn't create a window: {}", error),
+    };
 
     let window = display.get_window().unwrap();
+    if config.render.samples > 0 {
+        match window.get_pixel_format().multisampling {
+            Some(samples) => println!("MSAA: {}x", samples),
+            None => println!("MSAA isn't supported by this context"),
+        }
+    }
 
-    let models = bsp.read_models(&header.direntries);
-    let faces = bsp.read_faces(&header.direntries);
-    let vertexes = bsp.read_vertexes(&header.direntries);
-    let meshverts = bsp.read_meshverts(&header.direntries);
-    let textures = bsp.read_textures(&header.direntries);
+    let models = &map.models;
+    let faces = &map.faces;
+    let vertexes = &map.vertexes;
+    let textures = &map.textures;
+    let lightmaps = &map.lightmaps;
 
     //println!("{:#?}", textures);
-    let model = &models[0];
 
     let mut loaded_textures = HashMap::new();
+    let mut blend_textures = HashMap::new();
     for i in 0..textures.len() {
         let texture = &textures[i];
         if texture.name.starts_with("textures/") || texture.name.starts_with("models/") {
-            let mut path = format!("data/{}.jpg", texture.name);
-            let jpg = match File::open(&path) {
-                Ok(_) => true,
-                Err(_) => {
-                    path = format!("data/{}.tga", texture.name);
-                    false
-                }
-            };
-            let image_format = if jpg {
-                image::ImageFormat::JPEG
-            } else {
-                image::ImageFormat::TGA
+            // Terrain shaders have no image of their own, they blend the images of two others
+            let (name, blend_name) = match terrain_blend_names(&texture.name) {
+                Some((from, to)) => (from, Some(to)),
+                None => (texture.name.clone(), None),
             };
+            if let Some(loaded) = load_texture(&display, &config.render.data_path, &name) {
+                loaded_textures.insert(i as i32, loaded);
+            }
+            if let Some(loaded) = blend_name.and_then(|n| {
+                load_texture(&display, &config.render.data_path, &n)
+            }) {
+                blend_textures.insert(i as i32, loaded);
+            }
+        }
+    }
 
-            match File::open(&path) {
-                Ok(file) => {
-                    let image = image::load(file, image_format).unwrap().to_rgba();
-                    let image_dimensions = image.dimensions();
-                    let image = glium::texture::RawImage2d::from_raw_rgba_reversed(image.into_raw(), image_dimensions);
-                    loaded_textures.insert(i, glium::texture::Texture2d::new(&display, image));
-                    println!("Opened {}", &path);
+    let worldspawn = entities::parse(&map.entities)
+                         .into_iter()
+                         .find(|e| e.classname() == "worldspawn");
+
+    // q3map2 -deluxe stores a deluxemap after every lightmap. The deluxemaps hold directions, not
+    // colors, so they are uploaded without sRGB decoding.
+    let deluxe_mapping = worldspawn.as_ref().and_then(|w| w.get("deluxeMapping")) == Some("1");
+    let mut lightmap_images = load_external_lightmaps(&config.map);
+    if lightmap_images.is_empty() {
+        for lightmap in lightmaps.iter() {
+            let data = lightmap.map.iter().flat_map(|t| vec![t[0], t[1], t[2], 255]).collect();
+            lightmap_images.push(glium::texture::RawImage2d::from_raw_rgba(data, (128, 128)));
+        }
+    }
+    let mut loaded_lightmaps = HashMap::new();
+    let mut deluxemaps = HashMap::new();
+    for (i, image) in lightmap_images.into_iter().enumerate() {
+        let i = i as i32;
+        if deluxe_mapping && i % 2 == 1 {
+            deluxemaps.insert(i - 1, glium::texture::Texture2d::new(&display, image).unwrap());
+        } else {
+            loaded_lightmaps.insert(i, glium::texture::SrgbTexture2d::new(&display, image).unwrap());
+        }
+    }
+    println!("Lightmaps: {}, deluxemaps: {}", loaded_lightmaps.len(), deluxemaps.len());
+
+    let white = glium::texture::SrgbTexture2d::new(&display, vec![vec![(255u8, 255u8, 255u8)]]).unwrap();
+    let straight_up = glium::texture::Texture2d::new(&display, vec![vec![(128u8, 128u8, 255u8)]]).unwrap();
+
+    // Every model gets its own batches so they can be shown one at a time
+    let (model_faces, shared_faces) = geometry::model_faces(&map);
+    println!("Models: {}, faces: {}", models.len(), model_faces.len());
+    if shared_faces > 0 {
+        println!("Skipped {} faces already listed by an earlier model", shared_faces);
+    }
+    let model_t = coords::q3_to_render();
+
+    // Faces in a fog volume reference an effect whose brush has fog contents
+    let is_fog = |effect: &bsp_reader::Effect| {
+        map.brushes.get(effect.brush as usize).map_or(false, |b| {
+            textures[b.texture as usize].contents & collision::CONTENTS_FOG != 0
+        })
+    };
+    let mut effect_faces: HashMap<&str, usize> = HashMap::new();
+    let mut fogged_faces = 0;
+    let mut face_type_counts = [0; 4];
+
+    let mut render_vertexes = vertexes.clone();
+    let mut in_fog = vec![false; render_vertexes.len()];
+    let mut face_types = vec![0.0; render_vertexes.len()];
+    // Without usable visdata every cluster counts as visible from everywhere, which leaves
+    // culling to the frustum and the other methods
+    let use_vis = match map.vis_problem() {
+        Some(problem) => {
+            println!("Notice: {}, culling by the frustum only", problem);
+            false
+        }
+        None => true,
+    };
+    // Batches are only split by cluster if they can be hidden by the PVS, occlusion queries,
+    // areaportals or by their screen size, batches of the whole map would never be small enough.
+    // Sorting needs them too, a batch of the whole map is both near and far.
+    let face_clusters = if use_vis || config.render.occlusion_queries ||
+                           config.render.area_portals ||
+                           config.render.min_screen_size > 0.0 ||
+                           config.render.sort_draws {
+        occlusion::face_clusters(&map)
+    } else {
+        Vec::new()
+    };
+    let face_bounds = geometry::face_bounds(&map);
+    let face_centroids = geometry::face_centroids(&map);
+    let mut batch_indices: HashMap<(usize, i32, i32, i32), Vec<u32>> = HashMap::new();
+    let mut batch_faces: HashMap<(usize, i32, i32, i32), usize> = HashMap::new();
+    let mut batch_clusters: HashMap<(usize, i32, i32, i32), Vec<i32>> = HashMap::new();
+    let mut patch_lods = Vec::new();
+    let patch_group_centers = patch::group_centers(faces, &vertexes);
+    // Patches of the same level are welded together, so their shared edges have no cracks
+    let mut welders: Vec<patch::Welder> = PATCH_LOD_LEVELS.iter()
+                                                          .map(|_| patch::Welder::new())
+                                                          .collect();
+    let mut welded_vertexes = 0;
+    let mut fixed_triangles = 0;
+    let mut degenerate_triangles = 0;
+    let mut face_triangles = 0;
+    // Triangles of the finest patch level and indices of all levels, which all stay on the GPU
+    let mut patch_triangles = 0;
+    let mut patch_indices = 0;
+    let build_start = time::precise_time_ns();
+    let resolved = resolve_faces(&map, &model_faces, config.render.fix_winding);
+    for (&(model_index, face_index), resolved) in model_faces.iter().zip(resolved) {
+        let face = &faces[face_index];
+        let effect = bsp_reader::effect_for_face(face, &map.effects);
+        if let Some(effect) = effect {
+            *effect_faces.entry(&effect.name[..]).or_insert(0) += 1;
+        }
+        let face_fog = effect.map_or(false, |e| is_fog(e));
+        if face_fog {
+            fogged_faces += 1;
+        }
+        if face.f_type >= 1 && face.f_type <= 4 {
+            face_type_counts[face.f_type as usize - 1] += 1;
+        }
+
+        if face.f_type == 1 || face.f_type == 3 {
+            if let Err(error) = geometry::check_meshverts(&map, face) {
+                println!("Skipping face {}: {}", face_index, error);
+                continue;
+            }
+            for fog in &mut in_fog[face.vertex as usize..(face.vertex + face.n_vertexes) as usize] {
+                *fog = face_fog;
+            }
+            let range = face.vertex as usize..(face.vertex + face.n_vertexes) as usize;
+            for face_type in &mut face_types[range] {
+                *face_type = face.f_type as f32;
+            }
+
+            let (mut face_indices, fixed) = resolved.unwrap();
+            fixed_triangles += fixed;
+            degenerate_triangles += geometry::drop_degenerate(&vertexes,
+                                                              &mut face_indices,
+                                                              config.render.min_triangle_area);
+            face_triangles += face_indices.len() / 3;
+            if face_indices.is_empty() {
+                continue;
+            }
+
+            let clusters = face_clusters.get(face_index).map_or(&[][..], |c| &c[..]);
+            let key = (model_index,
+                       clusters.first().cloned().unwrap_or(-1),
+                       face.texture,
+                       face.lm_index);
+            batch_indices.entry(key).or_insert_with(Vec::new).extend_from_slice(&face_indices);
+            *batch_faces.entry(key).or_insert(0) += 1;
+            let key_clusters = batch_clusters.entry(key).or_insert_with(Vec::new);
+            for &cluster in clusters {
+                if !key_clusters.contains(&cluster) {
+                    key_clusters.push(cluster);
                 }
-                Err(_) => {
-                    println!("Couldn't open {}", &path);
+            }
+        } else if face.f_type == 2 {
+            if let Err(error) = patch::grid_size(face) {
+                println!("Skipping patch face {}: {}", face_index, error);
+                continue;
+            }
+            let controls = &vertexes[face.vertex as usize..(face.vertex + face.n_vertexes) as usize];
+            let mut center = Vector3::new(0.0, 0.0, 0.0);
+            for control in controls {
+                center = center + Vector3::from(control.position);
+            }
+            let center = Point3::from_vec(center / controls.len() as f32);
+            let radius = controls.iter().fold(0.0f32, |radius, control| {
+                radius.max((Point3::from(control.position) - center).length())
+            });
+
+            let mut lods = Vec::new();
+            for (&level, welder) in PATCH_LOD_LEVELS.iter().zip(&mut welders) {
+                let mut patch = patch::tessellate(face, &vertexes, level);
+                welded_vertexes += welder.weld(&mut patch);
+                let base = render_vertexes.len() as u32;
+                render_vertexes.extend_from_slice(&patch.vertexes);
+                in_fog.resize(render_vertexes.len(), face_fog);
+                face_types.resize(render_vertexes.len(), 2.0);
+                let mut indices: Vec<u32> = patch.indices.iter().map(|i| i + base).collect();
+                degenerate_triangles += geometry::drop_degenerate(&render_vertexes,
+                                                                  &mut indices,
+                                                                  config.render.min_triangle_area);
+                if lods.is_empty() {
+                    patch_triangles += indices.len() / 3;
                 }
+                patch_indices += indices.len();
+                lods.push(glium::index::IndexBuffer::new(&display,
+                                                         glium::index::PrimitiveType::TrianglesList,
+                                                         &indices).unwrap());
             }
+
+            patch_lods.push(PatchLods {
+                model: model_index,
+                clusters: face_clusters.get(face_index).cloned().unwrap_or_else(Vec::new),
+                texture: face.texture,
+                lightmap: face.lm_index,
+                center: coords::q3_to_render_point(center),
+                radius: radius,
+                lod_center: coords::q3_to_render_point(patch_group_centers[face_index]
+                                                           .map_or(center, Point3::from)),
+                lods: lods,
+            });
         }
     }
 
-    let model_faces = &faces[model.face as usize..(model.face + model.n_faces) as usize];
-    println!("Model faces: {}", model_faces.len());
+    let mut batch_keys: Vec<(usize, i32, i32, i32)> = batch_indices.keys().cloned().collect();
+    batch_keys.sort();
+    let mut batches = Vec::new();
+    for key in batch_keys {
+        let mut center = Vector3::new(0.0, 0.0, 0.0);
+        for &index in &batch_indices[&key] {
+            center = center + Vector3::from(vertexes[index as usize].position);
+        }
+        let center = Point3::from_vec(center / batch_indices[&key].len() as f32);
+        let radius = batch_indices[&key].iter().fold(0.0f32, |radius, &index| {
+            let position = Point3::from(vertexes[index as usize].position);
+            radius.max((position - center).length())
+        });
+
+        let indices = glium::index::IndexBuffer::new(&display,
+                                                     glium::index::PrimitiveType::TrianglesList,
+                                                     &batch_indices[&key]).unwrap();
+        batches.push(Batch {
+            model: key.0,
+            clusters: batch_clusters.remove(&key).unwrap_or_else(Vec::new),
+            texture: key.2,
+            lightmap: key.3,
+            center: coords::q3_to_render_point(center),
+            radius: radius,
+            faces: batch_faces[&key],
+            indices: indices,
+        });
+    }
+    println!("Batches: {}, patches: {}, built in {} ms",
+             batches.len(),
+             patch_lods.len(),
+             (time::precise_time_ns() - build_start) / 1000000);
+    if config.render.fix_winding {
+        println!("Fixed winding of {} triangles", fixed_triangles);
+    }
+    if degenerate_triangles > 0 {
+        println!("Dropped {} degenerate triangles", degenerate_triangles);
+    }
+    if welded_vertexes > 0 {
+        println!("Welded {} patch vertexes onto neighbouring patches", welded_vertexes);
+    }
+
+    for (name, count) in &effect_faces {
+        println!("Effect {}: {} faces", name, count);
+    }
+    if fogged_faces > 0 {
+        println!("Faces in fog volumes: {}", fogged_faces);
+    }
+
     let mut vertex_buffer: Vec<Vertex> = Vec::new();
-    for vertex in vertexes {
+    for ((vertex, fog), face_type) in render_vertexes.into_iter().zip(in_fog).zip(face_types) {
         vertex_buffer.push(Vertex {
             position: vertex.position,
+            texcoord: vertex.texcoord,
             normal: vertex.normal,
-            color: [vertex.color[0] as f32 / 256.0,
-                    vertex.color[1] as f32 / 256.0,
-                    vertex.color[2] as f32 / 256.0,
-                    vertex.color[3] as f32 / 256.0],
+            color: color_to_f32(vertex.color, config.render.linear_vertex_colors),
+            fog_volume: if fog {
+                1.0
+            } else {
+                0.0
+            },
+            face_type: face_type,
         });
     }
+    let buffer_bytes = vertex_buffer.len() * mem::size_of::<Vertex>() +
+                       (face_triangles * 3 + patch_indices) * mem::size_of::<u32>();
+    println!("Vertexes: {}, triangles: {} in faces, {} in patches, GPU buffers: {:.1} MiB",
+             vertex_buffer.len(),
+             face_triangles,
+             patch_triangles,
+             buffer_bytes as f32 / (1024.0 * 1024.0));
     let vertex_buffer = glium::VertexBuffer::new(&display, &vertex_buffer).unwrap();
-    let mut face_indices : HashMap<usize, glium::index::IndexBuffer<u32>> = HashMap::new();
-    for i in 0..model_faces.len() {
-        let face = &model_faces[i];
-        if face.f_type == 1 || face.f_type == 3 {
-            let mut index_buffer: Vec<u32> = Vec::new();
+    // Cloned so the config can still be changed from the console
+    let shader_path = config.render.shader_path.clone();
+    let shader_path = shader_path.as_ref().map(|p| &p[..]);
+    // Without a skybox the void shows the clear color, maps can suggest one with _color
+    let clear_color = config.render
+                            .clear_color
+                            .or_else(|| {
+                                worldspawn.as_ref()
+                                          .and_then(|w| w.get_vec3("_color"))
+                                          .and_then(|c| config::check_color("_color", c))
+                            })
+                            .unwrap_or(CLEAR_COLOR);
+    let materials = materials::read(&config.render.data_path);
+    let textures_in = |names: &HashSet<String>| -> HashSet<i32> {
+        textures.iter()
+                .enumerate()
+                .filter(|&(_, t)| names.contains(&t.name.to_lowercase()))
+                .map(|(i, _)| i as i32)
+                .collect()
+    };
+    let double_sided = textures_in(&materials.double_sided);
+    let alpha_tested = textures_in(&materials.alpha_tested);
+    println!("Double sided textures: {}, alpha tested textures: {}",
+             double_sided.len(),
+             alpha_tested.len());
+    let water_textures: HashSet<i32> = if config.render.water {
+        textures.iter()
+                .enumerate()
+                .filter(|&(_, t)| VolumeKind::classify(t.contents) == Some(VolumeKind::Water))
+                .map(|(i, _)| i as i32)
+                .collect()
+    } else {
+        HashSet::new()
+    };
+    let mut world = World {
+        vertex_buffer: vertex_buffer,
+        program: load_program(&display, shader_path, "world.vert", "world.frag"),
+        textures: loaded_textures,
+        blend_textures: blend_textures,
+        lightmaps: loaded_lightmaps,
+        deluxemaps: deluxemaps,
+        white: white,
+        straight_up: straight_up,
+        lights: glium::uniforms::UniformBuffer::dynamic(&display,
+                                                        LightBlock {
+                                                            light_positions: [[0.0; 4];
+                                                                              MAX_LIGHTS],
+                                                            light_colors: [[0.0; 4]; MAX_LIGHTS],
+                                                            light_count: 0,
+                                                        })
+                    .unwrap(),
+        water_textures: water_textures,
+        backface_culling: config.render.backface_culling,
+        double_sided: double_sided,
+        alpha_tested: alpha_tested,
+        depth_write: true,
+        depth_less_or_equal: false,
+        profile: false,
+    };
+    // Light entities in render space, for maps without lightmaps
+    let entity_lights: Vec<(Point3<f32>, entities::Light)> =
+        entities::lights(&entities::parse(&map.entities))
+            .into_iter()
+            .map(|light| {
+                let origin = Point3::from(light.origin);
+                (coords::q3_to_render_point(origin), light)
+            })
+            .collect();
+    println!("Light entities: {}", entity_lights.len());
+    let line_program = load_program(&display, shader_path, "lines.vert", "lines.frag");
+    let mut line_renderer = LineRenderer::new(line_program);
+    let mut occlusion = if config.render.occlusion_queries {
+        let box_program = load_program(&display, shader_path, "lines.vert", "lines.frag");
+        Some(OcclusionCuller::new(&display, box_program, occlusion::cluster_bounds(&map)))
+    } else {
+        None
+    };
 
-            for relative_vertex_index in
-                &meshverts[face.meshvert as usize..(face.meshvert + face.n_meshverts) as usize] {
-                index_buffer.push((relative_vertex_index + face.vertex) as u32);
-            }
+    let mut normal_lines = Vec::new();
+    for vertex in vertexes.iter() {
+        let n = vertex.normal;
+        let color = [n[0].abs(), n[1].abs(), n[2].abs()];
+        let tip = [vertex.position[0] + n[0] * NORMAL_LENGTH,
+                   vertex.position[1] + n[1] * NORMAL_LENGTH,
+                   vertex.position[2] + n[2] * NORMAL_LENGTH];
+        normal_lines.push(LineVertex {
+            position: vertex.position,
+            color: color,
+        });
+        normal_lines.push(LineVertex {
+            position: tip,
+            color: color,
+        });
+    }
+    let normal_lines = glium::VertexBuffer::new(&display, &normal_lines).unwrap();
+    let mut show_normals = false;
+    let mut trace: Option<collision::Trace> = None;
+    let mut show_crosshair = config.render.crosshair;
+    let viewmodel_program = load_program(&display, shader_path, "lines.vert", "lines.frag");
+    let mut viewmodel = Viewmodel::new(&display, viewmodel_program);
+    let mut show_viewmodel = config.render.viewmodel;
+    let minimap_program = load_program(&display, shader_path, "lines.vert", "lines.frag");
+    let minimap = Minimap::new(&display,
+                               &map,
+                               &minimap_program,
+                               config.render.minimap_size,
+                               worldspawn.as_ref().and_then(|w| w.map_coords()));
+    let mut show_minimap = config.render.minimap;
+    let mut show_leaf_bounds = false;
+    // Axes and grid at the origin of the map, in Quake 3 space like the other world lines
+    let mut gizmo_lines = Vec::new();
+    primitives::axis_gizmo(&mut gizmo_lines, 1024.0, 128.0);
+    let mut show_gizmo = false;
+    let mut show_labels = false;
+    let volumes_program = load_program(&display, shader_path, "lines.vert", "lines.frag");
+    let volumes = Volumes::new(&display, &map, volumes_program);
+    // Some(include_solid) while the brush volumes are shown
+    let mut show_volumes: Option<bool> = None;
+    let mut inspect = false;
+    let mut inspected: Option<(usize, String)> = None;
+    // Faces are only picked again when the view moved
+    let mut last_pick: Option<(Point3<f32>, Vector3<f32>)> = None;
+    let mut pick_tested = vec![false; map.faces.len()];
 
-            let index_buffer : glium::index::IndexBuffer<u32> = glium::index::IndexBuffer::new(&display,
-                                                              glium::index::PrimitiveType::TrianglesList,
-                                                              &index_buffer).unwrap();
+    let mut camera_pos = match config.camera.start {
+        Some(start) => {
+            coords::q3_to_render_point(Point3::new(start[0], start[1], start[2]))
+        }
+        None => Point3::new(0.0, 0.0, 0.0),
+    };
+    let (mut pitch, mut yaw) = match config.camera.start_angles {
+        Some((pitch, yaw)) => (deg(pitch), deg(yaw)),
+        None => (deg(0.0), deg(180.0)),
+    };
 
-            face_indices.insert(i, index_buffer);
+    let mut render_mode = RenderMode::TexturedLightmap;
+
+    let mut drawn_textures: Vec<i32> = batches.iter().map(|b| b.texture).collect();
+    drawn_textures.extend(patch_lods.iter().map(|p| p.texture));
+    drawn_textures.sort();
+    drawn_textures.dedup();
+    let mut isolated_texture = config.render.only_texture.as_ref().and_then(|name| {
+        let texture = drawn_textures.iter().cloned().find(|&t| textures[t as usize].name == *name);
+        match texture {
+            Some(_) => println!("Only drawing {}", name),
+            None => println!("No faces use texture {}", name),
         }
+        texture
+    });
+    update_title(&window, &textures, isolated_texture, false, None);
+
+    let mut dynamic_resolution = if config.render.dynamic_resolution {
+        Some(DynamicResolution::new(&display,
+                                    (config.render.width, config.render.height),
+                                    config.render.min_scale,
+                                    config.render.max_scale,
+                                    config.render.target_frame_time / 1000.0))
+    } else {
+        None
+    };
+
+    // Quake 3 takes fog from shader fogparms, which aren't read from the shader scripts, so the
+    // fog comes from the config or from fogcolor/fogdensity keys on the worldspawn
+    let fog_color = config.render
+                          .fog_color
+                          .or(worldspawn.as_ref().and_then(|w| w.get_vec3("fogcolor")))
+                          .unwrap_or(clear_color);
+    let fog_density = config.render
+                            .fog_density
+                            .or(worldspawn.as_ref().and_then(|w| w.get_f32("fogdensity")))
+                            .unwrap_or(0.0);
+    if fog_density > 0.0 {
+        println!("Fog: color {:?}, density {}", fog_color, fog_density);
     }
-    let vertex_shader_src = read_shader("src/shaders/world.vert");
-    let fragment_shader_src = read_shader("src/shaders/world.frag");
-    let program = glium::Program::from_source(&display,
-                                              &vertex_shader_src,
-                                              &fragment_shader_src,
-                                              None)
-                      .unwrap();
+    // Nothing covers the void between the world's faces, so the world pass starts from the
+    // clear color
+    let world_clear = Clear::ColorAndDepth(clear_color);
+
+    let mut areas = if config.render.area_portals {
+        let areas = Areas::new(&map);
+        println!("Areas: {}, areaportals: {}", areas.area_count(), areas.portal_count());
+        Some(areas)
+    } else {
+        None
+    };
+
+    let mut shown_model = 0;
+    // Position culling was frozen at, culling and patch LODs use it instead of the camera
+    let mut frozen_culling: Option<Point3<f32>> = None;
 
-    let mut camera_pos = Point3::new(0.0, 0.0, 0.0);
-    let mut pitch = deg(0.0);
-    let mut yaw = deg(180.0);
+    let mut console = Console::new();
+    // Name and entity index of the last goto_entity, repeating it cycles through the matches
+    let mut last_goto_entity: Option<(String, usize)> = None;
+    let mut pending_screenshot = None;
+    // Phase times summed since the last print, and the averages shown on screen
+    let mut profile_totals = [0u64; 4];
+    let mut profile_frames = 0;
+    let mut profile_text: Vec<String> = Vec::new();
+    let mut wireframe = false;
 
     let mut cursor_caught = false;
 
@@ -130,8 +994,41 @@ fn main() {
     let mut cursor_dy = 0;
 
     let mut pressed_keys = HashSet::new();
+    let mut velocity = Vector3::new(0.0, 0.0, 0.0);
+    let mut camera_path = CameraPath::new();
+    // Seconds into the recording or playback of the camera path
+    let mut recording: Option<f32> = None;
+    let mut playback: Option<f32> = None;
+    // Walking instead of flying while there is a player
+    let mut player: Option<Player> = None;
 
+    let comparison = if config.cull_benchmark {
+        Some(Comparison::Culling)
+    } else if config.sort_benchmark {
+        Some(Comparison::Sorting)
+    } else {
+        None
+    };
+    let mut benchmark = comparison.map(|comparison| {
+        // Smoothing would make the views of the two passes differ
+        config.camera.smoothing = 0.0;
+        if config.render.vsync {
+            println!("Warning: vsync caps the frame times the benchmark measures");
+        }
+        Benchmark::new(&map, comparison)
+    });
+    // Rank of each cluster in the front to back order of the leafs, usize::MAX if not reached
+    let cluster_count = map.leafs.iter().map(|l| l.cluster + 1).max().unwrap_or(0).max(0);
+    let mut cluster_rank = vec![usize::MAX; cluster_count as usize];
+
+    let mut last_camera_pos = camera_pos;
+    // What the camera shows trails camera_pos, pitch and yaw while smoothing is on
+    let mut smooth_pos = camera_pos;
+    let mut smooth_angles = (pitch.s, yaw.s);
+    // Set when the camera jumps somewhere, so the view doesn't glide there
+    let mut snap_view = false;
     let mut last_time = time::precise_time_ns();
+    let start_time = last_time;
     let mut last_fps_update = last_time;
 
     loop {
@@ -139,59 +1036,865 @@ fn main() {
         let dt = (current_time - last_time) as f32 / 1e9f32;
         last_time = current_time;
 
-        if current_time - last_fps_update > 1e9 as u64 {
+        let print_stats = current_time - last_fps_update > 1e9 as u64;
+        if print_stats {
             println!("FPS: {}", 1.0 / dt);
+            if let Some(ref resolution) = dynamic_resolution {
+                println!("Resolution scale: {:.2}", resolution.scale);
+            }
             last_fps_update = current_time;
         }
 
+        if let Some(ref benchmark) = benchmark {
+            let (position, direction) = benchmark.camera();
+            camera_pos = coords::q3_to_render_point(Point3::from(position));
+            let direction = coords::q3_to_render_vector(Vector3::from(direction));
+            pitch = deg(0.0);
+            yaw = deg(direction.z.atan2(direction.x).to_degrees());
+        }
         let camera_direction = Vector3::new(pitch.cos() * yaw.cos(),
                                             pitch.sin(),
                                             pitch.cos() * yaw.sin());
 
         let camera_sideways = camera_direction.cross(Vector3::new(0.0, 1.0, 0.0)).normalize();
 
-        let model_m: [[f32; 4]; 4] = Matrix4::from(Matrix3::from_angle_x(Rad::from(deg(-90.0))))
-                                         .into();
-        let view_m: [[f32; 4]; 4] = Matrix4::look_at(camera_pos,
-                                                     camera_pos + camera_direction,
-                                                     Vector3::new(0.0, 1.0, 0.0))
-                                        .into();
-        let perspective_m: [[f32; 4]; 4] = perspective(deg(45.0), 1280.0 / 720.0, 1.0, 10000.0)
-                                               .into();
+        if snap_view {
+            smooth_pos = camera_pos;
+            smooth_angles = (pitch.s, yaw.s);
+            snap_view = false;
+        }
+        let (view_pos, view_direction) = if config.camera.smoothing > 0.0 {
+            let blend = 1.0 - (-dt / config.camera.smoothing).exp();
+            smooth_pos = smooth_pos + (camera_pos - smooth_pos) * blend;
+            smooth_angles.0 += (pitch.s - smooth_angles.0) * blend;
+            smooth_angles.1 += (yaw.s - smooth_angles.1) * blend;
+            let (pitch, yaw) = (deg(smooth_angles.0), deg(smooth_angles.1));
+            (smooth_pos,
+             Vector3::new(pitch.cos() * yaw.cos(), pitch.sin(), pitch.cos() * yaw.sin()))
+        } else {
+            (camera_pos, camera_direction)
+        };
+        let view_t = Matrix4::look_at(view_pos,
+                                      view_pos + view_direction,
+                                      Vector3::new(0.0, 1.0, 0.0));
+        let aspect = config.render.width as f32 / config.render.height as f32;
+        let perspective_t = perspective(deg(config.camera.fov),
+                                        aspect,
+                                        config.camera.near,
+                                        config.camera.far);
+        let model_m: [[f32; 4]; 4] = model_t.into();
+        let view_m: [[f32; 4]; 4] = view_t.into();
+        let perspective_m: [[f32; 4]; 4] = perspective_t.into();
 
         let mut target = display.draw();
-        target.clear_color_and_depth((0.8, 0.8, 1.0, 1.0), 1.0);
+        if let Some(ref mut resolution) = dynamic_resolution {
+            resolution.update(&display, dt, target.get_dimensions());
+        }
 
-        let params = glium::DrawParameters {
-            depth: glium::Depth {
-                test: glium::draw_parameters::DepthTest::IfLess,
-                write: true,
-                ..Default::default()
-            },
-            ..Default::default()
+        let culling_start = profile_time(world.profile);
+        let cull_pos = frozen_culling.unwrap_or(view_pos);
+        if frozen_culling.is_none() {
+            if let Some(ref mut occlusion) = occlusion {
+                occlusion.collect();
+            }
+        }
+        // Size in pixels of something one unit across, one unit away
+        let pixels_per_unit = config.render.height as f32 /
+                              (config.camera.fov.to_radians() / 2.0).tan() /
+                              2.0;
+        let cull_q3: [f32; 3] = coords::render_to_q3_point(cull_pos).into();
+        let cull_leaf = map.leafs.get(collision::find_leaf(&map, cull_q3));
+        let cull_area = cull_leaf.map_or(-1, |l| l.area);
+        let cull_cluster = cull_leaf.map_or(-1, |l| l.cluster);
+        let mut skipped_faces = 0;
+        let culling = benchmark.as_ref().map_or(true, |b| b.enabled(Comparison::Culling));
+        let sorting = config.render.sort_draws &&
+                      benchmark.as_ref().map_or(true, |b| b.enabled(Comparison::Sorting));
+        if sorting {
+            for rank in &mut cluster_rank {
+                *rank = usize::MAX;
+            }
+            let view_q3: [f32; 3] = coords::render_to_q3_point(view_pos).into();
+            let leaves = collision::leaves_front_to_back(&map, view_q3);
+            for (rank, leaf) in leaves.into_iter().enumerate() {
+                let cluster = map.leafs[leaf].cluster;
+                if cluster >= 0 && cluster_rank[cluster as usize] == usize::MAX {
+                    cluster_rank[cluster as usize] = rank;
+                }
+            }
+        }
+        // The closures borrow the culling state, so they end before the occlusion queries
+        let mut sorted_draws: Vec<(Point3<f32>, usize, Draw)> = {
+            // Batches rank by their nearest cluster
+            let rank = |clusters: &[i32]| {
+                clusters.iter()
+                        .filter(|&&c| c >= 0)
+                        .map(|&c| cluster_rank[c as usize])
+                        .min()
+                        .unwrap_or(usize::MAX)
+            };
+            let cluster_visible = |cluster: i32| {
+                (!use_vis || map.visdata.is_cluster_visible(cull_cluster, cluster)) &&
+                occlusion.as_ref().map_or(true, |o| o.is_visible(cluster)) &&
+                areas.as_ref().map_or(true, |a| a.is_cluster_visible(cull_area, cluster))
+            };
+            // Whether something of this size covers at least min_screen_size pixels, or the
+            // camera is inside of it
+            let big_enough = |center: Point3<f32>, radius: f32| {
+                let distance = (center - cull_pos).length();
+                let size = 2.0 * radius / distance * pixels_per_unit;
+                distance <= radius || size >= config.render.min_screen_size
+            };
+            let mut draws: Vec<(Point3<f32>, usize, Draw)> =
+                batches.iter()
+                       .filter(|b| b.model == shown_model)
+                       .filter(|b| {
+                           !culling || b.clusters.is_empty() ||
+                           b.clusters.iter().any(|&c| cluster_visible(c))
+                       })
+                       .filter(|b| {
+                           let shown = !culling || big_enough(b.center, b.radius);
+                           if !shown {
+                               skipped_faces += b.faces;
+                           }
+                           shown
+                       })
+                       .map(|b| {
+                           (b.center, rank(&b.clusters), (b.texture, b.lightmap, &b.indices))
+                       })
+                       .collect();
+            draws.extend(patch_lods.iter()
+                                   .filter(|p| p.model == shown_model)
+                                   .filter(|p| {
+                                       !culling || p.clusters.is_empty() ||
+                                       p.clusters.iter().any(|&c| cluster_visible(c))
+                                   })
+                                   .filter(|p| {
+                                       let shown = !culling || big_enough(p.center, p.radius);
+                                       if !shown {
+                                           skipped_faces += 1;
+                                       }
+                                       shown
+                                   })
+                                   .map(|p| {
+                                       (p.center,
+                                        rank(&p.clusters),
+                                        (p.texture, p.lightmap, p.select(cull_pos)))
+                                   }));
+            draws
+        };
+        if let Some(t) = isolated_texture {
+            sorted_draws.retain(|d| (d.2).0 == t);
+        }
+        // Drawing near batches first lets the depth test reject the hidden fragments of far ones.
+        // Clusters are ordered by walking the tree, the distance only orders the batches within
+        // a cluster.
+        if sorting {
+            sorted_draws.sort_by(|a, b| {
+                match a.1.cmp(&b.1) {
+                    Ordering::Equal => {
+                        let a = (a.0 - view_pos).length2();
+                        let b = (b.0 - view_pos).length2();
+                        a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+                    }
+                    order => order,
+                }
+            });
+        }
+        // Water is blended, so it's drawn after everything else and back to front
+        let (mut water_draws, sorted_draws): (Vec<_>, Vec<_>) =
+            sorted_draws.into_iter().partition(|d| world.is_water((d.2).0));
+        water_draws.sort_by(|a, b| {
+            let a = (a.0 - view_pos).length2();
+            let b = (b.0 - view_pos).length2();
+            b.partial_cmp(&a).unwrap_or(Ordering::Equal)
+        });
+        let draws: Vec<Draw> = sorted_draws.into_iter().map(|d| d.2).collect();
+        let water_draws: Vec<Draw> = water_draws.into_iter().map(|d| d.2).collect();
+        let culling_ns = profile_time(world.profile) - culling_start;
+        if let RenderMode::EntityLights = render_mode {
+            // The lights whose range reaches closest to the camera
+            let mut nearest: Vec<&(Point3<f32>, entities::Light)> = entity_lights.iter().collect();
+            nearest.sort_by(|a, b| {
+                let a = (a.0 - view_pos).length() - a.1.intensity;
+                let b = (b.0 - view_pos).length() - b.1.intensity;
+                a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+            });
+            let mut block = LightBlock {
+                light_positions: [[0.0; 4]; MAX_LIGHTS],
+                light_colors: [[0.0; 4]; MAX_LIGHTS],
+                light_count: nearest.len().min(MAX_LIGHTS) as i32,
+            };
+            for (i, &&(position, ref light)) in nearest.iter().take(MAX_LIGHTS).enumerate() {
+                block.light_positions[i] = [position.x, position.y, position.z, light.intensity];
+                block.light_colors[i] = [light.color[0], light.color[1], light.color[2], 1.0];
+            }
+            world.lights.write(&block);
+        }
+        let uniforms = WorldUniforms {
+            model: model_m,
+            view: view_m,
+            perspective: perspective_m,
+            render_mode: render_mode,
+            fog_color: fog_color,
+            fog_density: fog_density,
+            fog_volume_color: config.render.fog_volume_color,
+            fog_volume_density: config.render.fog_volume_density,
+            flip_lightmap_v: config.render.flip_lightmap_v,
+            modulate_vertex_colors: config.render.modulate_vertex_colors,
+            time: (current_time - start_time) as f32 / 1e9,
+            water_tint: config.render.water_tint,
+            water_opacity: config.render.water_opacity,
+            water_warp: config.render.water_warp,
+            water_warp_speed: config.render.water_warp_speed,
         };
 
-        for i in 0..model_faces.len() {
-            let face = &model_faces[i];
-            if face.f_type == 1 || face.f_type == 3 {
-                let index_buffer = face_indices.get(&i).unwrap();
-                target.draw(&vertex_buffer,
-                            index_buffer,
-                            &program,
-                            &uniform!{model: model_m, view: view_m, perspective: perspective_m},
-                            &params)
-                      .unwrap();
+        // Leafs in clusters that are drawn are green, culled ones red. Leafs get darker in the
+        // order they are walked front to back, near ones should be the brightest.
+        if show_leaf_bounds {
+            let order = collision::leaves_front_to_back(&map, cull_q3);
+            let mut boxes = Vec::new();
+            for (rank, leaf) in order.iter()
+                                     .enumerate()
+                                     .filter_map(|(r, &l)| map.leafs.get(l).map(|l| (r, l)))
+                                     .filter(|&(_, l)| l.cluster >= 0) {
+                let visible = (!use_vis ||
+                               map.visdata.is_cluster_visible(cull_cluster, leaf.cluster)) &&
+                              occlusion.as_ref().map_or(true, |o| o.is_visible(leaf.cluster)) &&
+                              areas.as_ref()
+                                   .map_or(true, |a| a.is_cluster_visible(cull_area, leaf.cluster));
+                let brightness = 1.0 - 0.75 * rank as f32 / order.len() as f32;
+                let color = if visible {
+                    [0.0, brightness, 0.0]
+                } else {
+                    [brightness, 0.0, 0.0]
+                };
+                let mins = [leaf.mins[0] as f32, leaf.mins[1] as f32, leaf.mins[2] as f32];
+                let maxs = [leaf.maxs[0] as f32, leaf.maxs[1] as f32, leaf.maxs[2] as f32];
+                primitives::box_lines(&mut boxes, mins, maxs, color);
+            }
+            line_renderer.lines(&boxes);
+        }
+
+        if show_gizmo {
+            line_renderer.lines(&gizmo_lines);
+        }
+
+        if show_labels {
+            let mut label_lines = Vec::new();
+            labels::face_labels(&mut label_lines,
+                                &map,
+                                &face_bounds,
+                                &face_centroids,
+                                coords::render_to_q3_point(view_pos).into(),
+                                coords::render_to_q3_vector(view_direction).into());
+            line_renderer.lines(&label_lines);
+        }
+
+        if inspect {
+            let start = coords::render_to_q3_point(view_pos);
+            let direction = coords::render_to_q3_vector(view_direction);
+            let picked = if last_pick != Some((start, direction)) {
+                last_pick = Some((start, direction));
+                let hit = collision::trace_ray(&map,
+                                               start.into(),
+                                               (start + direction * 8192.0).into());
+                // Slightly past the brush hit so the face on its surface is still in the segment
+                let end = Point3::from(hit.end) + direction * 1.0;
+                geometry::pick_face(&map, &face_bounds, start.into(), end.into(), &mut pick_tested)
+                    .map(|(face, _)| face)
+            } else {
+                inspected.as_ref().map(|i| i.0)
+            };
+            if picked != inspected.as_ref().map(|i| i.0) {
+                inspected = picked.map(|f| {
+                    let face = &map.faces[f];
+                    let info = match map.textures.get(face.texture as usize) {
+                        Some(texture) => {
+                            format!("face {} ({}, {})",
+                                    f,
+                                    texture.name,
+                                    collision::describe_contents(texture.contents))
+                        }
+                        None => format!("face {}", f),
+                    };
+                    println!("Inspecting {}", info);
+                    (f, info)
+                });
+                update_title(&window,
+                             &textures,
+                             isolated_texture,
+                             frozen_culling.is_some(),
+                             inspected.as_ref().map(|i| &i.1[..]));
+            }
+            if let Some((face, _)) = inspected {
+                for (a, b) in geometry::face_outline(&map, &map.faces[face]) {
+                    line_renderer.line(a, b, [0.0, 1.0, 1.0]);
+                }
+            }
+        }
+
+        if let Some(ref trace) = trace {
+            line_renderer.line(trace.start, trace.end, [1.0, 1.0, 0.0]);
+            if trace.hit() {
+                let hit = Vector3::from(trace.end);
+                let normal = Vector3::from(trace.normal);
+                line_renderer.line(trace.end, (hit + normal * 32.0).into(), [1.0, 0.0, 0.0]);
+                for axis in 0..3 {
+                    let mut offset = Vector3::new(0.0, 0.0, 0.0);
+                    offset[axis] = 4.0;
+                    line_renderer.line((hit - offset).into(), (hit + offset).into(), [0.0, 1.0, 0.0]);
+                }
+            }
+        }
+        let world_mvp: [[f32; 4]; 4] = (perspective_t * view_t * model_t).into();
+        let camera_q3: [f32; 3] = coords::render_to_q3_point(view_pos).into();
+        let normals = if show_normals {
+            Some(&normal_lines)
+        } else {
+            None
+        };
+
+        // The world is drawn into the scaled framebuffer if there is one and stretched over the
+        // window afterwards, screen space overlays like the crosshair stay at full resolution.
+        let world_start = profile_time(world.profile);
+        let fragments = benchmark.as_ref().and_then(|_| SamplesPassedQuery::new(&display).ok());
+        let mut stats = match dynamic_resolution {
+            Some(ref resolution) => {
+                let mut framebuffer = resolution.framebuffer(&display);
+                world_clear.apply(&mut framebuffer);
+                let stats = world.draw(&mut framebuffer,
+                                       &draws,
+                                       &water_draws,
+                                       uniforms,
+                                       wireframe,
+                                       config.render.depth_prepass,
+                                       fragments.as_ref());
+                if let (Some(occlusion), None) = (occlusion.as_mut(), frozen_culling) {
+                    occlusion.query(&display, &mut framebuffer, world_mvp, camera_q3);
+                }
+                if let Some(include_solid) = show_volumes {
+                    volumes.draw(&mut framebuffer, world_mvp, include_solid);
+                }
+                draw_world_lines(&display, &mut framebuffer, &mut line_renderer, normals, world_mvp);
+                framebuffer.fill(&target, glium::uniforms::MagnifySamplerFilter::Linear);
+                stats
+            }
+            None => {
+                world_clear.apply(&mut target);
+                let stats = world.draw(&mut target,
+                                       &draws,
+                                       &water_draws,
+                                       uniforms,
+                                       wireframe,
+                                       config.render.depth_prepass,
+                                       fragments.as_ref());
+                if let (Some(occlusion), None) = (occlusion.as_mut(), frozen_culling) {
+                    occlusion.query(&display, &mut target, world_mvp, camera_q3);
+                }
+                if let Some(include_solid) = show_volumes {
+                    volumes.draw(&mut target, world_mvp, include_solid);
+                }
+                draw_world_lines(&display, &mut target, &mut line_renderer, normals, world_mvp);
+                stats
+            }
+        };
+
+        stats.skipped_faces = skipped_faces;
+        stats.phase_ns[PHASE_CULLING] = culling_ns;
+        // The world passes are timed inside, the rest of the world block counts as overlays
+        let world_ns = profile_time(world.profile) - world_start;
+        let overlays_start = profile_time(world.profile);
+
+        // Only the world is in screenshots, overlays are sized for the window. The draws were
+        // culled for the window, at a bigger size the screen size culling skips a bit too much.
+        if let Some((size, path)) = pending_screenshot.take() {
+            let size = size.resolve((config.render.width, config.render.height));
+            match Screenshot::new(&display, size) {
+                Ok(screenshot) => {
+                    let aspect = size.0 as f32 / size.1 as f32;
+                    let shot_uniforms = WorldUniforms {
+                        perspective: perspective(deg(config.camera.fov),
+                                                 aspect,
+                                                 config.camera.near,
+                                                 config.camera.far)
+                                         .into(),
+                        ..uniforms
+                    };
+                    let mut framebuffer = screenshot.framebuffer(&display);
+                    world_clear.apply(&mut framebuffer);
+                    world.draw(&mut framebuffer,
+                               &draws,
+                               &water_draws,
+                               shot_uniforms,
+                               wireframe,
+                               config.render.depth_prepass,
+                               None);
+                    match screenshot.save(&path) {
+                        Ok(()) => {
+                            console.print(format!("Saved a {}x{} screenshot to {}",
+                                                  size.0,
+                                                  size.1,
+                                                  path))
+                        }
+                        Err(error) => console.print(format!("Couldn't save {}: {}", path, error)),
+                    }
+                }
+                Err(error) => console.print(format!("Couldn't take a screenshot: {}", error)),
+            }
+        }
+
+        viewmodel.update((camera_pos - last_camera_pos).length(), dt);
+        last_camera_pos = camera_pos;
+        if show_viewmodel {
+            Clear::Depth.apply(&mut target);
+            viewmodel.draw(&mut target, perspective_t, config.render.viewmodel_bob);
+        }
+
+        if show_minimap {
+            let direction = coords::render_to_q3_vector(view_direction);
+            minimap.draw(&display,
+                         &mut target,
+                         &mut line_renderer,
+                         (config.render.width, config.render.height),
+                         camera_q3,
+                         direction.into());
+        }
+
+        if let RenderMode::FaceType = render_mode {
+            let (width, height) = (config.render.width as f32, config.render.height as f32);
+            let mut legend = Vec::new();
+            for (i, (name, color)) in FACE_TYPE_NAMES.iter().zip(&FACE_TYPE_COLORS).enumerate() {
+                let text = format!("{} {}", name, face_type_counts[i]);
+                // Text is centered on its position, the legend is aligned left in the top corner
+                let half_width = (text.len() as f32 * 1.5 - 0.5) * LEGEND_HEIGHT / 4.0;
+                let y = height - (i as f32 + 1.0) * 1.5 * LEGEND_HEIGHT;
+                labels::text_lines(&mut legend,
+                                   &text,
+                                   [16.0 + half_width, y, 0.0],
+                                   [1.0, 0.0, 0.0],
+                                   [0.0, 1.0, 0.0],
+                                   LEGEND_HEIGHT,
+                                   *color);
+            }
+            line_renderer.lines(&legend);
+            line_renderer.draw(&display,
+                               &mut target,
+                               ortho(0.0, width, 0.0, height, -1.0, 1.0).into(),
+                               false);
+        }
+
+        if console.open {
+            let (width, height) = (config.render.width as f32, config.render.height as f32);
+            let mut text_lines = Vec::new();
+            let prompt = console.prompt();
+            let lines = console.output.iter().chain(Some(&prompt));
+            for (i, text) in lines.enumerate() {
+                // Aligned left in the top corner, with the input line below the output
+                let half_width = (text.len() as f32 * 1.5 - 0.5) * LEGEND_HEIGHT / 4.0;
+                let y = height - (i as f32 + 1.0) * 1.5 * LEGEND_HEIGHT;
+                labels::text_lines(&mut text_lines,
+                                   text,
+                                   [16.0 + half_width, y, 0.0],
+                                   [1.0, 0.0, 0.0],
+                                   [0.0, 1.0, 0.0],
+                                   LEGEND_HEIGHT,
+                                   [1.0, 1.0, 1.0]);
+            }
+            line_renderer.lines(&text_lines);
+            line_renderer.draw(&display,
+                               &mut target,
+                               ortho(0.0, width, 0.0, height, -1.0, 1.0).into(),
+                               false);
+        }
+
+        if show_crosshair {
+            let (width, height) = (config.render.width as f32, config.render.height as f32);
+            let (x, y) = (width / 2.0, height / 2.0);
+            let size = match config.render.crosshair_style {
+                CrosshairStyle::Cross => 8.0,
+                CrosshairStyle::Dot => 1.0,
+            };
+            line_renderer.line([x - size, y, 0.0], [x + size, y, 0.0], [1.0, 1.0, 1.0]);
+            line_renderer.line([x, y - size, 0.0], [x, y + size, 0.0], [1.0, 1.0, 1.0]);
+            line_renderer.draw(&display,
+                               &mut target,
+                               ortho(0.0, width, 0.0, height, -1.0, 1.0).into(),
+                               false);
+        }
+
+        if world.profile {
+            let (width, height) = (config.render.width as f32, config.render.height as f32);
+            let mut text_lines = Vec::new();
+            for (i, text) in profile_text.iter().enumerate() {
+                // Text is centered on its position, aligned left in the bottom corner here
+                let half_width = (text.len() as f32 * 1.5 - 0.5) * LEGEND_HEIGHT / 4.0;
+                let y = (profile_text.len() - i) as f32 * 1.5 * LEGEND_HEIGHT;
+                labels::text_lines(&mut text_lines,
+                                   text,
+                                   [16.0 + half_width, y, 0.0],
+                                   [1.0, 0.0, 0.0],
+                                   [0.0, 1.0, 0.0],
+                                   LEGEND_HEIGHT,
+                                   [1.0, 1.0, 1.0]);
+            }
+            line_renderer.lines(&text_lines);
+            line_renderer.draw(&display,
+                               &mut target,
+                               ortho(0.0, width, 0.0, height, -1.0, 1.0).into(),
+                               false);
+
+            stats.phase_ns[PHASE_OVERLAYS] = world_ns - stats.phase_ns[PHASE_OPAQUE] -
+                                             stats.phase_ns[PHASE_WATER] +
+                                             profile_time(true) - overlays_start;
+            for (total, &phase) in profile_totals.iter_mut().zip(&stats.phase_ns) {
+                *total += phase;
+            }
+            profile_frames += 1;
+        }
+
+        if print_stats {
+            if world.profile && profile_frames > 0 {
+                profile_text = PHASES.iter()
+                                     .zip(&profile_totals)
+                                     .map(|(name, &total)| {
+                                         format!("{} {:.2} ms",
+                                                 name,
+                                                 total as f32 / profile_frames as f32 / 1e6)
+                                     })
+                                     .collect();
+                println!("Frame phases: {}", profile_text.join(", "));
+                profile_totals = [0; 4];
+                profile_frames = 0;
+            }
+            println!("Draw calls: {}, triangles: {}", stats.draw_calls, stats.triangles);
+            if config.render.min_screen_size > 0.0 {
+                println!("Faces too small to draw: {}", stats.skipped_faces);
+            }
+            if let Some(ref occlusion) = occlusion {
+                println!("Occluded clusters: {}", occlusion.occluded());
             }
         }
 
         target.finish().unwrap();
 
+        if let Some(ref mut benchmark) = benchmark {
+            // Waits for the GPU, which the frame time of the next frame would have done anyway
+            let overdraw = fragments.map_or(0.0, |f| f.get() as f32 / stats.pixels.max(1) as f32);
+            if benchmark.record(dt, stats.draw_calls, stats.triangles, overdraw) {
+                benchmark.print_report();
+                return;
+            }
+        }
+
         for ev in display.poll_events() {
             match ev {
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.console ||
+                       console.open && key == VirtualKeyCode::Escape => {
+                    console.toggle();
+                    pressed_keys.clear();
+                }
+                Event::ReceivedCharacter(c) if console.open => console.type_char(c),
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Back))
+                    if console.open => console.backspace(),
+                Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Return))
+                    if console.open => {
+                    match console.submit() {
+                        Some(Ok(Command::Help)) => console.print_help(),
+                        Some(Ok(Command::Wireframe(on))) => wireframe = on,
+                        Some(Ok(Command::Fov(fov))) => config.camera.fov = fov,
+                        Some(Ok(Command::Goto(position))) => {
+                            let position = Point3::new(position[0], position[1], position[2]);
+                            camera_pos = coords::q3_to_render_point(position);
+                            snap_view = true;
+                            if let Some(ref mut player) = player {
+                                *player = Player::new(position.to_vec(), &config.physics);
+                            }
+                        }
+                        Some(Ok(Command::GotoEntity(name))) => {
+                            let previous = match last_goto_entity {
+                                Some((ref last, index)) if *last == name => Some(index),
+                                _ => None,
+                            };
+                            match find_entity(&map, &name, previous) {
+                                Some((index, position)) => {
+                                    let position = Point3::from(position);
+                                    camera_pos = coords::q3_to_render_point(position);
+                                    snap_view = true;
+                                    if let Some(ref mut player) = player {
+                                        *player = Player::new(position.to_vec(), &config.physics);
+                                    }
+                                    last_goto_entity = Some((name, index));
+                                }
+                                None => {
+                                    console.print(format!("No entity with targetname or \
+                                                           classname {}",
+                                                          name))
+                                }
+                            }
+                        }
+                        Some(Ok(Command::RenderMode(name))) => {
+                            match RenderMode::from_name(&name) {
+                                Some(mode) => {
+                                    render_mode = mode;
+                                    print_face_types(render_mode, &face_type_counts);
+                                }
+                                None => console.print(format!("Unknown render mode {}", name)),
+                            }
+                        }
+                        Some(Ok(Command::Model(index))) => {
+                            if index < models.len() {
+                                shown_model = index;
+                                camera_pos =
+                                    show_model(&map, shown_model, camera_direction);
+                                snap_view = true;
+                            } else {
+                                console.print(format!("There are only {} models", models.len()));
+                            }
+                        }
+                        Some(Ok(Command::Noclip)) => {
+                            player = match player {
+                                Some(_) => None,
+                                None => {
+                                    let eye = coords::render_to_q3_point(camera_pos);
+                                    Some(Player::new(eye.to_vec(), &config.physics))
+                                }
+                            };
+                            snap_view = true;
+                            console.print(format!("Noclip {}",
+                                                  if player.is_some() { "off" } else { "on" }));
+                        }
+                        Some(Ok(Command::Portals(open))) => {
+                            match areas {
+                                Some(ref mut areas) => areas.set_all_open(open),
+                                None => {
+                                    console.print("Areaportals are off, start with --area-portals"
+                                                      .to_owned())
+                                }
+                            }
+                        }
+                        Some(Ok(Command::Screenshot(size, path))) => {
+                            let path = path.unwrap_or_else(|| {
+                                format!("screenshot-{}.png", time::get_time().sec)
+                            });
+                            pending_screenshot = Some((size, path));
+                        }
+                        Some(Err(error)) => console.print(error),
+                        None => {}
+                    }
+                }
+                Event::KeyboardInput(..) if console.open => {}
                 Event::KeyboardInput(ElementState::Pressed, _, Some(VirtualKeyCode::Escape)) => {
                     window.set_cursor_state(CursorState::Normal);
                     cursor_caught = false;
                 }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key)) if key == config.controls.render_mode => {
+                    render_mode = render_mode.next();
+                    println!("Render mode: {:?}", render_mode);
+                    print_face_types(render_mode, &face_type_counts);
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.normals => {
+                    show_normals = !show_normals;
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.depth_write => {
+                    world.depth_write = !world.depth_write;
+                    world.print_depth_state();
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.depth_test => {
+                    world.depth_less_or_equal = !world.depth_less_or_equal;
+                    world.print_depth_state();
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.profiler => {
+                    world.profile = !world.profile;
+                    profile_totals = [0; 4];
+                    profile_frames = 0;
+                    profile_text.clear();
+                    println!("Profiler {}", if world.profile { "on" } else { "off" });
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.reload_shaders => {
+                    // The embedded shaders never change, so without a shader directory the
+                    // sources next to the code are read
+                    let directory = shader_path.unwrap_or("src/shaders");
+                    match shader::program(&display, Some(directory), "world.vert", "world.frag") {
+                        Ok(program) => {
+                            world.program = program;
+                            println!("Reloaded world shaders from {}", directory);
+                        }
+                        Err(error) => println!("Keeping the old world shaders: {}", error),
+                    }
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.freeze_culling => {
+                    frozen_culling = match frozen_culling {
+                        Some(_) => None,
+                        None => Some(camera_pos),
+                    };
+                    println!("Culling {}",
+                             if frozen_culling.is_some() {
+                                 "frozen"
+                             } else {
+                                 "unfrozen"
+                             });
+                    update_title(&window,
+                                 &textures,
+                                 isolated_texture,
+                                 frozen_culling.is_some(),
+                                 inspected.as_ref().map(|i| &i.1[..]));
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.leaf_bounds => {
+                    show_leaf_bounds = !show_leaf_bounds;
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.inspect => {
+                    inspect = !inspect;
+                    last_pick = None;
+                    if !inspect && inspected.is_some() {
+                        inspected = None;
+                        update_title(&window,
+                                     &textures,
+                                     isolated_texture,
+                                     frozen_culling.is_some(),
+                                     None);
+                    }
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.viewmodel => {
+                    show_viewmodel = !show_viewmodel;
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.record_path => {
+                    if recording.is_some() {
+                        println!("Recorded a camera path of {:.1} s", camera_path.duration());
+                        recording = None;
+                    } else {
+                        println!("Recording the camera path");
+                        camera_path.clear();
+                        playback = None;
+                        recording = Some(0.0);
+                    }
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.play_path => {
+                    if playback.is_some() {
+                        playback = None;
+                    } else if recording.is_none() && !camera_path.is_empty() {
+                        println!("Playing the camera path, the mouse still looks around");
+                        playback = Some(0.0);
+                    }
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.gizmo => {
+                    show_gizmo = !show_gizmo;
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.labels => {
+                    show_labels = !show_labels;
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.volumes => {
+                    show_volumes = match show_volumes {
+                        None => Some(false),
+                        Some(false) => Some(true),
+                        Some(true) => None,
+                    };
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.minimap => {
+                    show_minimap = !show_minimap;
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.crosshair => {
+                    show_crosshair = !show_crosshair;
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if (key == config.controls.next_model ||
+                        key == config.controls.previous_model) && !models.is_empty() => {
+                    shown_model = if key == config.controls.next_model {
+                        (shown_model + 1) % models.len()
+                    } else {
+                        (shown_model + models.len() - 1) % models.len()
+                    };
+                    camera_pos = show_model(&map, shown_model, camera_direction);
+                    snap_view = true;
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.print_position => {
+                    let position = coords::render_to_q3_point(camera_pos);
+                    println!("--start {:.1},{:.1},{:.1},{:.1},{:.1}",
+                             position.x,
+                             position.y,
+                             position.z,
+                             pitch.s,
+                             yaw.s % 360.0);
+                    let contents = collision::point_contents(&map, position.into());
+                    println!("Contents: {}", collision::describe_contents(contents));
+                    if let Some(leaf) = map.leafs.get(collision::find_leaf(&map, position.into())) {
+                        println!("Cluster: {}, area: {}", leaf.cluster, leaf.area);
+                    }
+                    let near = geometry::faces_near(&map, &face_bounds, position.into(), 128.0);
+                    println!("Faces within 128 units: {:?}", near);
+                    if let Some((ambient, directional, direction)) =
+                           lightgrid::sample_lightgrid(&map, position.into()) {
+                        println!("Light grid: ambient {:?}, directional {:?} from {:?}",
+                                 ambient,
+                                 directional,
+                                 direction);
+                    }
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.trace => {
+                    let start = coords::render_to_q3_point(camera_pos);
+                    let direction = coords::render_to_q3_vector(camera_direction);
+                    let end = start + direction * 8192.0;
+                    let result = collision::trace_ray(&map, start.into(), end.into());
+                    if result.hit() {
+                        println!("Trace hit brush {:?} at {:?}, normal {:?}",
+                                 result.brush,
+                                 result.end,
+                                 result.normal);
+                    } else {
+                        println!("Trace hit nothing");
+                    }
+                    trace = Some(result);
+                }
+                Event::KeyboardInput(ElementState::Pressed, _, Some(key))
+                    if key == config.controls.next_texture ||
+                       key == config.controls.previous_texture => {
+                    let position = isolated_texture.and_then(|t| {
+                        drawn_textures.iter().position(|&d| d == t)
+                    });
+                    let count = drawn_textures.len();
+                    let next = if key == config.controls.next_texture {
+                        match position {
+                            None => Some(0),
+                            Some(p) if p + 1 < count => Some(p + 1),
+                            Some(_) => None,
+                        }
+                    } else {
+                        match position {
+                            None if count > 0 => Some(count - 1),
+                            Some(p) if p > 0 => Some(p - 1),
+                            _ => None,
+                        }
+                    };
+                    isolated_texture = next.map(|i| drawn_textures[i]);
+                    match isolated_texture {
+                        Some(t) => println!("Only drawing {}", textures[t as usize].name),
+                        None => println!("Drawing all textures"),
+                    }
+                    update_title(&window,
+                                 &textures,
+                                 isolated_texture,
+                                 frozen_culling.is_some(),
+                                 inspected.as_ref().map(|i| &i.1[..]));
+                }
                 Event::KeyboardInput(state, _, Some(key)) => {
                     if state == ElementState::Pressed {
                         pressed_keys.insert(key);
@@ -205,10 +1908,12 @@ fn main() {
                 }
                 Event::MouseMoved((x, y)) => {
                     if cursor_caught {
-                        cursor_dx = x - 1280 / 2;
-                        cursor_dy = y - 720 / 2;
+                        let center_x = config.render.width as i32 / 2;
+                        let center_y = config.render.height as i32 / 2;
+                        cursor_dx = x - center_x;
+                        cursor_dy = y - center_y;
 
-                        window.set_cursor_position(1280 / 2, 720 / 2);
+                        window.set_cursor_position(center_x, center_y);
                     }
                 }
                 Event::Closed => return,
@@ -216,8 +1921,20 @@ fn main() {
             }
         }
 
-        yaw = yaw + deg(cursor_dx as f32 * 0.6);
-        pitch = pitch - deg(cursor_dy as f32 * 0.6);
+        // Slow is for lining up shots precisely, so it slows looking around too
+        let slow = pressed_keys.contains(&config.controls.slow);
+        let look_scale = if slow {
+            config.camera.slow_multiplier
+        } else {
+            1.0
+        };
+        let pitch_delta = cursor_dy as f32 * config.camera.sensitivity_y * look_scale;
+        yaw = yaw + deg(cursor_dx as f32 * config.camera.sensitivity_x * look_scale);
+        pitch = if config.camera.invert_y {
+            pitch + deg(pitch_delta)
+        } else {
+            pitch - deg(pitch_delta)
+        };
 
         if pitch > deg(89.0) {
             pitch = deg(89.0);
@@ -227,20 +1944,72 @@ fn main() {
             pitch = deg(-89.0);
         }
 
-        if pressed_keys.contains(&VirtualKeyCode::W) {
-            camera_pos = camera_pos + camera_direction * 500.0 * dt;
+        let speed = if slow {
+            config.camera.speed * config.camera.slow_multiplier
+        } else if pressed_keys.contains(&config.controls.sprint) {
+            config.camera.speed * config.camera.sprint_multiplier
+        } else {
+            config.camera.speed
+        };
+
+        let mut wish = Vector3::new(0.0, 0.0, 0.0);
+        if pressed_keys.contains(&config.controls.forward) {
+            wish = wish + camera_direction;
         }
 
-        if pressed_keys.contains(&VirtualKeyCode::S) {
-            camera_pos = camera_pos + camera_direction * (-500.0) * dt;
+        if pressed_keys.contains(&config.controls.back) {
+            wish = wish - camera_direction;
         }
 
-        if pressed_keys.contains(&VirtualKeyCode::D) {
-            camera_pos = camera_pos + camera_sideways * 500.0 * dt;
+        if pressed_keys.contains(&config.controls.right) {
+            wish = wish + camera_sideways;
         }
 
-        if pressed_keys.contains(&VirtualKeyCode::A) {
-            camera_pos = camera_pos + camera_sideways * (-500.0) * dt;
+        if pressed_keys.contains(&config.controls.left) {
+            wish = wish - camera_sideways;
+        }
+
+        if let Some(time) = playback {
+            match camera_path.position_at(time + dt) {
+                Some(position) => {
+                    // The path is smooth already, smoothing would only make the view lag it
+                    camera_pos = position;
+                    snap_view = true;
+                    playback = Some(time + dt);
+                }
+                None => {
+                    println!("Camera path finished");
+                    playback = None;
+                }
+            }
+        } else if let Some(ref mut player) = player {
+            // Walking only uses the horizontal part of the view direction
+            let mut wish_q3 = coords::render_to_q3_vector(wish);
+            wish_q3.z = 0.0;
+            if wish_q3.length2() > 0.0 {
+                wish_q3 = wish_q3.normalize();
+            }
+            let jump = pressed_keys.contains(&config.controls.jump);
+            player.update(&map, &config.physics, wish_q3, jump, dt);
+            let eye = Point3::from_vec(player.eye(&config.physics));
+            camera_pos = coords::q3_to_render_point(eye);
+        } else if config.camera.smooth_movement {
+            velocity = movement::friction(velocity, config.camera.friction, dt);
+            if wish.length2() > 0.0 {
+                velocity = movement::accelerate(velocity,
+                                                wish.normalize(),
+                                                speed,
+                                                config.camera.acceleration,
+                                                dt);
+            }
+            camera_pos = camera_pos + velocity * dt;
+        } else {
+            camera_pos = camera_pos + wish * speed * dt;
+        }
+
+        if let Some(time) = recording {
+            camera_path.record(time, camera_pos);
+            recording = Some(time + dt);
         }
 
         cursor_dx = 0;
@@ -248,8 +2017,255 @@ fn main() {
     }
 }
 
-fn read_shader(path: &str) -> String {
-    let mut string = String::new();
-    File::open(path).unwrap().read_to_string(&mut string);
-    string
+/// Resolves all faces, in parallel with the `rayon` feature. The results are in the order of
+/// `faces` either way, so the batches come out the same.
+#[cfg(feature = "rayon")]
+fn resolve_faces(map: &bsp_reader::BspMap,
+                 faces: &[(usize, usize)],
+                 fix_winding: bool)
+                 -> Vec<Option<(Vec<u32>, usize)>> {
+    use rayon::prelude::*;
+    faces.par_iter().map(|&(_, f)| geometry::resolve_face(map, &map.faces[f], fix_winding)).collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+fn resolve_faces(map: &bsp_reader::BspMap,
+                 faces: &[(usize, usize)],
+                 fix_winding: bool)
+                 -> Vec<Option<(Vec<u32>, usize)>> {
+    faces.iter().map(|&(_, f)| geometry::resolve_face(map, &map.faces[f], fix_winding)).collect()
+}
+
+/// Loads `<data_path>/<name>.jpg`, or the `.tga` if there is no JPEG.
+fn load_texture<F: Facade>(display: &F,
+                           data_path: &str,
+                           name: &str)
+                           -> Option<glium::texture::SrgbTexture2d> {
+    let mut path = format!("{}/{}.jpg", data_path, name);
+    let jpg = match File::open(&path) {
+        Ok(_) => true,
+        Err(_) => {
+            path = format!("{}/{}.tga", data_path, name);
+            false
+        }
+    };
+    let image_format = if jpg {
+        image::ImageFormat::JPEG
+    } else {
+        image::ImageFormat::TGA
+    };
+
+    match File::open(&path) {
+        Ok(file) => {
+            let image = image::load(file, image_format).unwrap().to_rgba();
+            let image_dimensions = image.dimensions();
+            let image = glium::texture::RawImage2d::from_raw_rgba(image.into_raw(), image_dimensions);
+            println!("Opened {}", &path);
+            Some(glium::texture::SrgbTexture2d::new(display, image).unwrap())
+        }
+        Err(_) => {
+            println!("Couldn't open {}", &path);
+            None
+        }
+    }
+}
+
+/// q3map2 terrain shaders are named `<base>_<a>to<b>` and blend from the image of `<base>_<a>`
+/// to the one of `<base>_<b>` by vertex alpha. Shader scripts are only read for their cull
+/// mode and alpha test, not their stages' blending, so the names are the only hint. Returns
+/// the two texture names for such shaders.
+fn terrain_blend_names(name: &str) -> Option<(String, String)> {
+    let underscore = match name.rfind('_') {
+        Some(underscore) => underscore,
+        None => return None,
+    };
+    let (base, suffix) = (&name[..underscore], &name[underscore + 1..]);
+    let to = match suffix.find("to") {
+        Some(to) => to,
+        None => return None,
+    };
+    let (from, to) = (&suffix[..to], &suffix[to + 2..]);
+    let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_digit(10));
+    if is_number(from) && is_number(to) {
+        Some((format!("{}_{}", base, from), format!("{}_{}", base, to)))
+    } else {
+        None
+    }
+}
+
+/// Loads `maps/<name>/lm_0000.tga` and following lightmaps that q3map2 writes with `-external`.
+/// If there are any, they replace the lightmap lump entirely.
+fn load_external_lightmaps(map_path: &str) -> Vec<glium::texture::RawImage2d<'static, u8>> {
+    let mut lightmaps = Vec::new();
+    let directory = std::path::Path::new(map_path).with_extension("");
+
+    for i in 0.. {
+        let path = directory.join(format!("lm_{:04}.tga", i));
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => break,
+        };
+        let image = match image::load(file, image::ImageFormat::TGA) {
+            Ok(image) => image.to_rgba(),
+            Err(error) => {
+                println!("Couldn't load {}: {}", path.display(), error);
+                break;
+            }
+        };
+        let dimensions = image.dimensions();
+        lightmaps.push(glium::texture::RawImage2d::from_raw_rgba(image.into_raw(), dimensions));
+    }
+
+    if !lightmaps.is_empty() {
+        println!("Using {} external lightmaps from {}", lightmaps.len(), directory.display());
+    }
+    lightmaps
+}
+
+fn build_display(config: &config::RenderConfig,
+                 samples: u16)
+                 -> Result<glium::backend::glutin_backend::GlutinFacade,
+                           glium::GliumCreationError<CreationError>> {
+    let mut window_builder = glium::glutin::WindowBuilder::new()
+                                 .with_title("Guac - Quake III in shit".to_owned())
+                                 .with_dimensions(config.width, config.height)
+                                 .with_depth_buffer(24)
+                                 .with_srgb(Some(true));
+    if config.vsync {
+        window_builder = window_builder.with_vsync();
+    }
+    if samples > 0 {
+        window_builder = window_builder.with_multisampling(samples);
+    }
+    window_builder.build_glium()
+}
+
+fn draw_world_lines<F: Facade, S: Surface>(display: &F,
+                                           target: &mut S,
+                                           line_renderer: &mut LineRenderer,
+                                           normals: Option<&glium::VertexBuffer<LineVertex>>,
+                                           mvp: [[f32; 4]; 4]) {
+    if let Some(normals) = normals {
+        line_renderer.draw_buffer(target, normals, mvp, true);
+    }
+    line_renderer.draw(display, target, mvp, true);
+}
+
+/// Finds the first entity after the one at index `after` whose targetname or classname is
+/// `name`, wrapping around, and returns its index and position in Quake 3 space. Brush entities
+/// without an origin are at the center of their model. Prints which entity was found.
+fn find_entity(map: &bsp_reader::BspMap,
+               name: &str,
+               after: Option<usize>)
+               -> Option<(usize, [f32; 3])> {
+    let entities = entities::parse(&map.entities);
+    let matches: Vec<(usize, [f32; 3])> =
+        entities.iter()
+                .enumerate()
+                .filter(|&(_, e)| e.classname() == name || e.get("targetname") == Some(name))
+                .filter_map(|(i, e)| {
+                    let center = e.model_index().and_then(|m| map.models.get(m)).map(|m| {
+                        [(m.mins[0] + m.maxs[0]) / 2.0,
+                         (m.mins[1] + m.maxs[1]) / 2.0,
+                         (m.mins[2] + m.maxs[2]) / 2.0]
+                    });
+                    e.get_vec3("origin").or(center).map(|p| (i, p))
+                })
+                .collect();
+    let position = after.and_then(|a| matches.iter().position(|m| m.0 > a)).unwrap_or(0);
+    matches.get(position).map(|&(index, origin)| {
+        println!("{} {} of {}: {} at {:?}",
+                 name,
+                 position + 1,
+                 matches.len(),
+                 entities[index].classname(),
+                 origin);
+        (index, origin)
+    })
+}
+
+/// Prints which model is shown and returns a camera position that frames its bounds when
+/// looking in `direction`.
+fn show_model(map: &bsp_reader::BspMap,
+              index: usize,
+              direction: Vector3<f32>)
+              -> Point3<f32> {
+    let (center, distance) = match geometry::model_view(map, index) {
+        Some(view) => view,
+        None => {
+            println!("There is no model {}", index);
+            return Point3::new(0.0, 0.0, 0.0);
+        }
+    };
+    let name = format!("*{}", index);
+    let entity = entities::parse(&map.entities).into_iter().find(|e| {
+        index == 0 && e.classname() == "worldspawn" || e.get("model") == Some(&name[..])
+    });
+    match entity {
+        Some(entity) => println!("Model {}: {}", index, entity.classname()),
+        None => println!("Model {}: no entity", index),
+    }
+
+    coords::q3_to_render_point(Point3::from(center)) - direction * distance
+}
+
+/// Prints how many faces there are of each type when switching to the face type render mode.
+/// Billboards are never drawn, so they only show up here and in the legend.
+fn print_face_types(render_mode: RenderMode, counts: &[usize; 4]) {
+    if let RenderMode::FaceType = render_mode {
+        let summary: Vec<String> = FACE_TYPE_NAMES.iter()
+                                                  .zip(counts)
+                                                  .map(|(name, count)| format!("{} {}", count, name))
+                                                  .collect();
+        println!("Face types: {}", summary.join(", "));
+    }
+}
+
+fn update_title(window: &Window,
+                textures: &[bsp_reader::Texture],
+                texture: Option<i32>,
+                frozen_culling: bool,
+                inspected: Option<&str>) {
+    let mut title = "Guac - Quake III in shit".to_owned();
+    if let Some(t) = texture {
+        let name = &textures[t as usize].name;
+        title.push_str(&format!(" - only {}", name));
+    }
+    if frozen_culling {
+        title.push_str(" - culling frozen");
+    }
+    if let Some(inspected) = inspected {
+        title.push_str(&format!(" - {}", inspected));
+    }
+    window.set_title(&title);
+}
+
+fn color_to_f32(color: [u8; 4], linear: bool) -> [f32; 4] {
+    let channel = |c: u8| {
+        let c = c as f32 / 255.0;
+        if !linear {
+            c
+        } else if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+
+    [channel(color[0]), channel(color[1]), channel(color[2]), color[3] as f32 / 255.0]
+}
+
+/// Builds a program or exits with the read error or compiler log.
+fn load_program<F: Facade>(display: &F,
+                           directory: Option<&str>,
+                           vertex: &str,
+                           fragment: &str)
+                           -> glium::Program {
+    match shader::program(display, directory, vertex, fragment) {
+        Ok(program) => program,
+        Err(error) => {
+            println!("Couldn't build shader program {} + {}: {}", vertex, fragment, error);
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,121 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+
+/// The few things read from shader scripts, by shader name. Names are lower case like the game
+/// compares them.
+#[derive(Default)]
+pub struct Materials {
+    /// Shaders drawn from both sides, which set `cull none`, `cull disable` or `cull twosided`.
+    pub double_sided: HashSet<String>,
+    /// Shaders with a stage that sets `alphaFunc GE128`, whose pixels are either drawn or not.
+    pub alpha_tested: HashSet<String>,
+}
+
+/// Reads the shader scripts in `<data_path>/scripts`. Only the cull mode and alpha test are
+/// read, everything else in the scripts is skipped.
+pub fn read(data_path: &str) -> Materials {
+    let mut materials = Materials::default();
+    let entries = match fs::read_dir(format!("{}/scripts", data_path)) {
+        Ok(entries) => entries,
+        Err(_) => return materials,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.extension().map_or(true, |e| e != "shader") {
+            continue;
+        }
+        match read_script(&path.to_string_lossy()) {
+            Ok(source) => parse(&source, &mut materials),
+            Err(error) => println!("Couldn't read {}: {}", path.display(), error),
+        }
+    }
+    materials
+}
+
+fn read_script(path: &str) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)));
+    // Scripts are ASCII in practice, but some have stray Latin-1 bytes in comments
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Adds the shaders in `source` to the sets of `materials` they belong to.
+fn parse(source: &str, materials: &mut Materials) {
+    let mut depth = 0;
+    let mut shader: Option<String> = None;
+    let mut tokens = tokenize(source).into_iter();
+    while let Some(token) = tokens.next() {
+        match &token[..] {
+            "{" => depth += 1,
+            "}" => depth = if depth > 0 { depth - 1 } else { 0 },
+            _ if depth == 0 => shader = Some(token.to_lowercase()),
+            // Stages can't change the cull mode, only the shader itself
+            _ if depth == 1 && token.to_lowercase() == "cull" => {
+                let mode = tokens.next().map(|m| m.to_lowercase());
+                match mode.as_ref().map(|m| &m[..]) {
+                    Some("none") | Some("disable") | Some("twosided") => {
+                        if let Some(ref name) = shader {
+                            materials.double_sided.insert(name.clone());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // Only the common mask test, GT0 and LT128 are drawn without a test
+            _ if depth == 2 && token.to_lowercase() == "alphafunc" => {
+                let function = tokens.next().map(|f| f.to_lowercase());
+                if function.as_ref().map(|f| &f[..]) == Some("ge128") {
+                    if let Some(ref name) = shader {
+                        materials.alpha_tested.insert(name.clone());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Splits a script into words and braces, without `//` and `/* */` comments. Quoted strings
+/// are single tokens.
+fn tokenize(source: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' if chars.peek() == Some(&'/') => {
+                while let Some(c) = chars.next() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                while let Some(c) = chars.next() {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            '{' | '}' => tokens.push(c.to_string()),
+            '"' => tokens.push(chars.by_ref().take_while(|&c| c != '"').collect()),
+            c if c.is_whitespace() => {}
+            c => {
+                let mut token = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || next == '{' || next == '}' {
+                        break;
+                    }
+                    token.push(next);
+                    chars.next();
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    tokens
+}
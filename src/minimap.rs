@@ -0,0 +1,176 @@
+use cgmath;
+use glium;
+use glium::{BlitTarget, Surface};
+use glium::backend::Facade;
+use glium::framebuffer::{DepthRenderBuffer, SimpleFrameBuffer};
+use glium::texture::{DepthFormat, SrgbTexture2d};
+use bsp_reader::BspMap;
+use geometry;
+use lines::{LineRenderer, LineVertex};
+
+const SURF_SKY: i32 = 0x4;
+const SURF_NODRAW: i32 = 0x80;
+/// Surfaces whose normal points up at least this much count as floors.
+const MIN_FLOOR_NORMAL: f32 = 0.7;
+
+/// A top down map of the floors of the world, shaded lighter the higher they are. It's rendered
+/// once when the map is loaded and copied into a corner of the screen every frame together with
+/// a marker for the camera.
+pub struct Minimap {
+    texture: SrgbTexture2d,
+    /// Quake 3 x and y of the lower left corner and the width and height of the square shown.
+    origin: [f32; 2],
+    extent: f32,
+}
+
+impl Minimap {
+    /// Renders the floors of the world model into a `size` by `size` texture with `program`,
+    /// which takes `LineVertex` triangles like the line program. The map shows `coords`, the
+    /// horizontal mins and maxs, if the map sets them and the bounds of the geometry otherwise.
+    pub fn new<F: Facade>(display: &F,
+                          map: &BspMap,
+                          program: &glium::Program,
+                          size: u32,
+                          coords: Option<([f32; 2], [f32; 2])>)
+                          -> Minimap {
+        let (mut mins, mut maxs) = map.bounds();
+        if let Some((coords_mins, coords_maxs)) = coords {
+            mins[0] = coords_mins[0];
+            mins[1] = coords_mins[1];
+            maxs[0] = coords_maxs[0];
+            maxs[1] = coords_maxs[1];
+        }
+        let mut vertexes = Vec::new();
+        if let Some(model) = map.models.get(0) {
+            for face in &map.faces[model.face as usize..(model.face + model.n_faces) as usize] {
+                let flags = map.textures.get(face.texture as usize).map_or(0, |t| t.flags);
+                if flags & (SURF_SKY | SURF_NODRAW) != 0 {
+                    continue;
+                }
+                let indices = match geometry::resolve_face(map, face, false) {
+                    Some((indices, _)) => indices,
+                    None => continue,
+                };
+                for triangle in indices.chunks(3) {
+                    if triangle.len() < 3 {
+                        break;
+                    }
+                    let a = map.vertexes[triangle[0] as usize].position;
+                    let b = map.vertexes[triangle[1] as usize].position;
+                    let c = map.vertexes[triangle[2] as usize].position;
+                    // Front faces are clockwise, so the surface normal is the negated cross
+                    let normal = geometry::cross(geometry::sub(b, a), geometry::sub(c, a));
+                    let length = geometry::dot(normal, normal).sqrt();
+                    if length == 0.0 || -normal[2] / length < MIN_FLOOR_NORMAL {
+                        continue;
+                    }
+                    for &position in &[a, b, c] {
+                        let height = (position[2] - mins[2]) / (maxs[2] - mins[2]).max(1.0);
+                        let shade = 0.2 + 0.7 * height;
+                        vertexes.push(LineVertex {
+                            position: position,
+                            color: [shade, shade, shade],
+                        });
+                    }
+                }
+            }
+        }
+
+        let extent = (maxs[0] - mins[0]).max(maxs[1] - mins[1]).max(1.0);
+        let origin = [(mins[0] + maxs[0] - extent) / 2.0, (mins[1] + maxs[1] - extent) / 2.0];
+        // Looking down the z axis, so the highest floors are closest
+        let projection: [[f32; 4]; 4] = cgmath::ortho(origin[0],
+                                                      origin[0] + extent,
+                                                      origin[1],
+                                                      origin[1] + extent,
+                                                      -maxs[2] - 1.0,
+                                                      -mins[2] + 1.0)
+                                            .into();
+
+        let texture = SrgbTexture2d::empty(display, size, size).unwrap();
+        {
+            let depth = DepthRenderBuffer::new(display, DepthFormat::I24, size, size).unwrap();
+            let mut framebuffer = SimpleFrameBuffer::with_depth_buffer(display, &texture, &depth)
+                                      .unwrap();
+            framebuffer.clear_color_and_depth((0.0, 0.0, 0.0, 1.0), 1.0);
+            let params = glium::DrawParameters {
+                depth: glium::Depth {
+                    test: glium::draw_parameters::DepthTest::IfLess,
+                    write: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let vertex_buffer = glium::VertexBuffer::new(display, &vertexes).unwrap();
+            framebuffer.draw(&vertex_buffer,
+                             &glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+                             program,
+                             &uniform!{ mvp: projection },
+                             &params)
+                       .unwrap();
+        }
+
+        Minimap {
+            texture: texture,
+            origin: origin,
+            extent: extent,
+        }
+    }
+
+    /// Copies the map into the upper right corner of `target`, which is `screen` pixels big, and
+    /// marks `position` and the horizontal part of `direction`, both in Quake 3 space.
+    pub fn draw<F: Facade, S: Surface>(&self,
+                                       display: &F,
+                                       target: &mut S,
+                                       line_renderer: &mut LineRenderer,
+                                       screen: (u32, u32),
+                                       position: [f32; 3],
+                                       direction: [f32; 3]) {
+        const MARGIN: u32 = 16;
+        let size = self.texture.get_width();
+        let left = screen.0.saturating_sub(size + MARGIN);
+        let bottom = screen.1.saturating_sub(size + MARGIN);
+        SimpleFrameBuffer::new(display, &self.texture)
+            .unwrap()
+            .blit_whole_color_to(target,
+                                 &BlitTarget {
+                                     left: left,
+                                     bottom: bottom,
+                                     width: size as i32,
+                                     height: size as i32,
+                                 },
+                                 glium::uniforms::MagnifySamplerFilter::Linear);
+
+        let (left, bottom, size) = (left as f32, bottom as f32, size as f32);
+        let x = left + (position[0] - self.origin[0]) / self.extent * size;
+        let y = bottom + (position[1] - self.origin[1]) / self.extent * size;
+        let length = (direction[0] * direction[0] + direction[1] * direction[1]).sqrt();
+        let red = [1.0, 0.2, 0.2];
+        if length > 0.0 {
+            let (dx, dy) = (direction[0] / length * 12.0, direction[1] / length * 12.0);
+            line_renderer.line([x, y, 0.0], [x + dx, y + dy, 0.0], red);
+        }
+        line_renderer.line([x - 3.0, y, 0.0], [x + 3.0, y, 0.0], red);
+        line_renderer.line([x, y - 3.0, 0.0], [x, y + 3.0, 0.0], red);
+
+        let corners = [[left, bottom, 0.0],
+                       [left + size, bottom, 0.0],
+                       [left + size, bottom + size, 0.0],
+                       [left, bottom + size, 0.0]];
+        let mut border = Vec::new();
+        for i in 0..4 {
+            for &corner in &[corners[i], corners[(i + 1) % 4]] {
+                border.push(LineVertex {
+                    position: corner,
+                    color: [1.0, 1.0, 1.0],
+                });
+            }
+        }
+        line_renderer.lines(&border);
+        line_renderer.draw(display,
+                           target,
+                           cgmath::ortho(0.0, screen.0 as f32, 0.0, screen.1 as f32, -1.0, 1.0)
+                               .into(),
+                           false);
+    }
+}
@@ -0,0 +1,395 @@
+use cgmath::{EuclideanVector, Vector, Vector3};
+use bsp_reader::BspMap;
+use collision::{push_out_of_solid, trace_sphere};
+use config::PhysicsConfig;
+
+/// Slows `velocity` down like PM_Friction of Quake 3 does for noclip movement, stopping
+/// completely below one unit per second.
+pub fn friction(velocity: Vector3<f32>, friction: f32, dt: f32) -> Vector3<f32> {
+    let speed = velocity.length();
+    if speed < 1.0 {
+        return Vector3::zero();
+    }
+    let new_speed = (speed - speed * friction * dt).max(0.0);
+    velocity * (new_speed / speed)
+}
+
+/// Speeds `velocity` up towards `wish_speed` along the unit vector `wish_dir` like
+/// PM_Accelerate. Speed in other directions is left alone.
+pub fn accelerate(velocity: Vector3<f32>,
+                  wish_dir: Vector3<f32>,
+                  wish_speed: f32,
+                  acceleration: f32,
+                  dt: f32)
+                  -> Vector3<f32> {
+    let add_speed = wish_speed - velocity.dot(wish_dir);
+    if add_speed <= 0.0 {
+        return velocity;
+    }
+    let accel_speed = (acceleration * dt * wish_speed).min(add_speed);
+    velocity + wish_dir * accel_speed
+}
+
+/// Slows `velocity` down like PM_Friction does on the ground. Below `stop_speed` the player
+/// slows down as if moving at `stop_speed`, so it comes to a stop quickly. Like in the game only
+/// the horizontal speed counts, landing fast doesn't brake harder.
+pub fn ground_friction(velocity: Vector3<f32>,
+                       friction: f32,
+                       stop_speed: f32,
+                       dt: f32)
+                       -> Vector3<f32> {
+    let speed = Vector3::new(velocity.x, velocity.y, 0.0).length();
+    if speed < 1.0 {
+        return Vector3::new(0.0, 0.0, velocity.z);
+    }
+    let drop = speed.max(stop_speed) * friction * dt;
+    velocity * ((speed - drop).max(0.0) / speed)
+}
+
+/// Removes the part of `velocity` going into a plane with `normal`, pushing slightly away from it
+/// by `overclip` like PM_ClipVelocity.
+pub fn clip_velocity(velocity: Vector3<f32>, normal: Vector3<f32>, overclip: f32) -> Vector3<f32> {
+    let backoff = velocity.dot(normal);
+    let backoff = if backoff < 0.0 {
+        backoff * overclip
+    } else {
+        backoff / overclip
+    };
+    velocity - normal * backoff
+}
+
+/// Height of the eyes above the origin of the player box, DEFAULT_VIEWHEIGHT of the game.
+const VIEW_HEIGHT: f32 = 26.0;
+/// Steepest ground that can be walked on, MIN_WALK_NORMAL of the game.
+const MIN_WALK_NORMAL: f32 = 0.7;
+
+/// A walking player in Quake 3 space. The player box is approximated by a sphere as wide as
+/// the box that touches its bottom, which is enough for floors, walls and stairs.
+pub struct Player {
+    /// Center of the collision sphere.
+    pub origin: Vector3<f32>,
+    pub velocity: Vector3<f32>,
+    pub on_ground: bool,
+}
+
+impl Player {
+    /// Puts the player's eyes at `eye`.
+    pub fn new(eye: Vector3<f32>, physics: &PhysicsConfig) -> Player {
+        Player {
+            origin: eye - Player::eye_offset(physics),
+            velocity: Vector3::zero(),
+            on_ground: false,
+        }
+    }
+
+    pub fn eye(&self, physics: &PhysicsConfig) -> Vector3<f32> {
+        self.origin + Player::eye_offset(physics)
+    }
+
+    fn radius(physics: &PhysicsConfig) -> f32 {
+        physics.player_maxs[0].min(physics.player_maxs[1])
+    }
+
+    fn eye_offset(physics: &PhysicsConfig) -> Vector3<f32> {
+        let center = physics.player_mins[2] + Player::radius(physics);
+        Vector3::new(0.0, 0.0, VIEW_HEIGHT - center)
+    }
+
+    /// Moves the player for one frame like PM_WalkMove and PM_AirMove. `wish_dir` is the
+    /// horizontal direction of the movement keys, zero if none are held. In the air the player
+    /// accelerates towards `max_speed` along `wish_dir` with `air_accelerate`, which projects
+    /// onto the current velocity and makes strafe jumping work.
+    pub fn update(&mut self,
+                  map: &BspMap,
+                  physics: &PhysicsConfig,
+                  wish_dir: Vector3<f32>,
+                  jump: bool,
+                  dt: f32) {
+        let radius = Player::radius(physics);
+        if trace_sphere(map, self.origin.into(), self.origin.into(), radius).start_solid {
+            self.unstick(map, physics);
+        }
+
+        let mut ground = self.ground_normal(map, physics);
+        if jump && ground.is_some() {
+            self.velocity.z = physics.jump_velocity;
+            ground = None;
+        }
+        self.on_ground = ground.is_some();
+
+        match ground {
+            Some(normal) => {
+                self.velocity = ground_friction(self.velocity,
+                                                physics.friction,
+                                                physics.stop_speed,
+                                                dt);
+                let wish = clip_velocity(wish_dir, normal, physics.overclip);
+                if wish.length2() > 0.0 {
+                    self.velocity = accelerate(self.velocity,
+                                               wish.normalize(),
+                                               physics.max_speed,
+                                               physics.accelerate,
+                                               dt);
+                }
+                // Walking up and down slopes doesn't change the speed
+                let speed = self.velocity.length();
+                let clipped = clip_velocity(self.velocity, normal, physics.overclip);
+                self.velocity = if clipped.length2() > 0.0 {
+                    clipped.normalize() * speed
+                } else {
+                    clipped
+                };
+            }
+            None => {
+                if wish_dir.length2() > 0.0 {
+                    self.velocity = accelerate(self.velocity,
+                                               wish_dir,
+                                               physics.max_speed,
+                                               physics.air_accelerate,
+                                               dt);
+                }
+                self.velocity.z -= physics.gravity * dt;
+            }
+        }
+
+        self.step_slide(map, physics, dt);
+    }
+
+    /// Moves the player out of any solid brush it's inside of, after teleporting or spawning
+    /// in a wall. Returns false if no way out was found.
+    pub fn unstick(&mut self, map: &BspMap, physics: &PhysicsConfig) -> bool {
+        match push_out_of_solid(map, self.origin.into(), Player::radius(physics)) {
+            Some(origin) => {
+                self.origin = Vector3::from(origin);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Normal of the walkable ground right below the player, if there is any.
+    fn ground_normal(&self, map: &BspMap, physics: &PhysicsConfig) -> Option<Vector3<f32>> {
+        let below = self.origin - Vector3::new(0.0, 0.0, 0.25);
+        let trace = trace_sphere(map, self.origin.into(), below.into(), Player::radius(physics));
+        let normal = Vector3::from(trace.normal);
+        // Jumping off of a ramp shouldn't stick to it
+        if !trace.hit() || normal.z < MIN_WALK_NORMAL ||
+           (self.velocity.z > 0.0 && self.velocity.dot(normal) > 10.0) {
+            None
+        } else {
+            Some(normal)
+        }
+    }
+
+    /// Moves along the velocity, sliding along everything in the way. Returns whether anything
+    /// was hit.
+    fn slide(&mut self, map: &BspMap, physics: &PhysicsConfig, dt: f32) -> bool {
+        let radius = Player::radius(physics);
+        let mut time_left = dt;
+        let mut blocked = false;
+        for _ in 0..physics.slide_iterations {
+            let end = self.origin + self.velocity * time_left;
+            let trace = trace_sphere(map, self.origin.into(), end.into(), radius);
+            if trace.all_solid {
+                self.velocity.z = 0.0;
+                return true;
+            }
+            self.origin = Vector3::from(trace.end);
+            if !trace.hit() {
+                break;
+            }
+            blocked = true;
+            time_left *= 1.0 - trace.fraction;
+            self.velocity = clip_velocity(self.velocity,
+                                          Vector3::from(trace.normal),
+                                          physics.overclip);
+        }
+        blocked
+    }
+
+    /// Slides, and if something was in the way on the ground, tries again `step_height` higher
+    /// and keeps whichever got further, like PM_StepSlideMove.
+    fn step_slide(&mut self, map: &BspMap, physics: &PhysicsConfig, dt: f32) {
+        let start_origin = self.origin;
+        let start_velocity = self.velocity;
+        if !self.slide(map, physics, dt) || !self.on_ground {
+            return;
+        }
+        let slid_origin = self.origin;
+        let slid_velocity = self.velocity;
+
+        let radius = Player::radius(physics);
+        let step = Vector3::new(0.0, 0.0, physics.step_height);
+        let up = trace_sphere(map, start_origin.into(), (start_origin + step).into(), radius);
+        self.origin = Vector3::from(up.end);
+        self.velocity = start_velocity;
+        self.slide(map, physics, dt);
+        let down = trace_sphere(map, self.origin.into(), (self.origin - step).into(), radius);
+        self.origin = Vector3::from(down.end);
+
+        let horizontal = |v: Vector3<f32>| Vector3::new(v.x, v.y, 0.0).length2();
+        let stepped_onto_ground = down.hit() && down.normal[2] >= MIN_WALK_NORMAL;
+        if !stepped_onto_ground ||
+           horizontal(self.origin - start_origin) <= horizontal(slid_origin - start_origin) {
+            self.origin = slid_origin;
+            self.velocity = slid_velocity;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{EuclideanVector, Vector3};
+    use config::PhysicsConfig;
+    use bsp_reader::{self, BspMap};
+
+    /// The room of the default map, its walls are 16 units thick around -256 to 256 on x and y
+    /// and its floor is at -64.
+    fn default_map() -> BspMap {
+        bsp_reader::load_bytes(include_bytes!("../assets/default.bsp").to_vec()).unwrap()
+    }
+
+    fn assert_near(actual: Vector3<f32>, expected: [f32; 3]) {
+        for axis in 0..3 {
+            assert!((actual[axis] - expected[axis]).abs() < 1e-3,
+                    "{:?} isn't {:?}",
+                    actual,
+                    expected);
+        }
+    }
+
+    #[test]
+    fn accelerate_adds_acceleration_times_wish_speed_on_the_ground() {
+        let velocity = accelerate(Vector3::new(0.0, 0.0, 0.0),
+                                  Vector3::new(1.0, 0.0, 0.0),
+                                  320.0,
+                                  10.0,
+                                  0.01);
+        assert_near(velocity, [32.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn accelerate_stops_at_the_wish_speed() {
+        let wish_dir = Vector3::new(1.0, 0.0, 0.0);
+        let velocity = accelerate(Vector3::new(300.0, 0.0, 0.0), wish_dir, 320.0, 10.0, 0.01);
+        assert_near(velocity, [320.0, 0.0, 0.0]);
+        let velocity = accelerate(Vector3::new(400.0, 0.0, 0.0), wish_dir, 320.0, 10.0, 0.01);
+        assert_near(velocity, [400.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn accelerate_in_the_air_keeps_the_speed_across_the_wish_direction() {
+        // Only the speed along the wish direction is limited
+        let velocity = accelerate(Vector3::new(320.0, 0.0, 0.0),
+                                  Vector3::new(0.0, 1.0, 0.0),
+                                  320.0,
+                                  1.0,
+                                  0.01);
+        assert_near(velocity, [320.0, 3.2, 0.0]);
+    }
+
+    #[test]
+    fn strafing_in_the_air_goes_faster_than_the_max_speed() {
+        let physics = PhysicsConfig::default();
+        let mut velocity = Vector3::new(physics.max_speed, 0.0, 0.0);
+        for _ in 0..100 {
+            // Holding the wish direction almost at a right angle to the velocity, like turning
+            // the mouse while strafing
+            let forward = velocity.normalize();
+            let side = Vector3::new(-forward.y, forward.x, 0.0);
+            let wish_dir = (side * 10.0 + forward).normalize();
+            velocity = accelerate(velocity,
+                                  wish_dir,
+                                  physics.max_speed,
+                                  physics.air_accelerate,
+                                  1.0 / 125.0);
+        }
+        assert!(velocity.length() > physics.max_speed + 10.0,
+                "speed {} after strafing",
+                velocity.length());
+    }
+
+    #[test]
+    fn friction_slows_down_by_a_fraction_of_the_speed() {
+        let velocity = friction(Vector3::new(100.0, 0.0, 0.0), 6.0, 0.01);
+        assert_near(velocity, [94.0, 0.0, 0.0]);
+        assert_near(friction(Vector3::new(0.5, 0.0, 0.5), 6.0, 0.01), [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn ground_friction_brakes_slow_players_as_if_moving_at_the_stop_speed() {
+        let velocity = ground_friction(Vector3::new(50.0, 0.0, 0.0), 6.0, 100.0, 0.01);
+        assert_near(velocity, [44.0, 0.0, 0.0]);
+        let velocity = ground_friction(Vector3::new(200.0, 0.0, 0.0), 6.0, 100.0, 0.01);
+        assert_near(velocity, [188.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn ground_friction_only_counts_the_horizontal_speed() {
+        // 6 units are taken off of the 50 horizontal ones, the vertical speed doesn't add to it
+        let velocity = ground_friction(Vector3::new(50.0, 0.0, 100.0), 6.0, 100.0, 0.01);
+        assert_near(velocity, [44.0, 0.0, 88.0]);
+        let velocity = ground_friction(Vector3::new(0.5, 0.0, -300.0), 6.0, 100.0, 0.01);
+        assert_near(velocity, [0.0, 0.0, -300.0]);
+    }
+
+    #[test]
+    fn clip_velocity_removes_the_speed_into_the_plane_and_a_bit_more() {
+        let floor = Vector3::new(0.0, 0.0, 1.0);
+        let velocity = clip_velocity(Vector3::new(100.0, 0.0, -100.0), floor, 1.001);
+        assert_near(velocity, [100.0, 0.0, 0.1]);
+        // Moving away from the plane is slowed down a bit less than it's pushed out
+        let velocity = clip_velocity(Vector3::new(0.0, 0.0, 50.0), floor, 1.001);
+        assert_near(velocity, [0.0, 0.0, 50.0 - 50.0 / 1.001]);
+    }
+
+    #[test]
+    fn slide_stops_at_a_wall_and_keeps_moving_along_it() {
+        let map = default_map();
+        let physics = PhysicsConfig::default();
+        let mut player = Player {
+            origin: Vector3::new(200.0, 0.0, 0.0),
+            velocity: Vector3::new(1000.0, 500.0, 0.0),
+            on_ground: false,
+        };
+        assert!(player.slide(&map, &physics, 0.1));
+
+        // The sphere of radius 15 touches the wall at 256
+        assert!((player.origin.x - 241.0).abs() < 0.5, "stopped at {:?}", player.origin);
+        assert!(player.origin.y > 40.0, "didn't slide along the wall: {:?}", player.origin);
+        // The overclip turns a thousandth of the speed into the wall around
+        assert_near(player.velocity, [-1.0, 500.0, 0.0]);
+    }
+
+    #[test]
+    fn slide_moves_the_whole_way_through_open_space() {
+        let map = default_map();
+        let physics = PhysicsConfig::default();
+        let mut player = Player {
+            origin: Vector3::new(0.0, 0.0, 0.0),
+            velocity: Vector3::new(100.0, 0.0, 0.0),
+            on_ground: false,
+        };
+        assert!(!player.slide(&map, &physics, 0.5));
+        assert_near(player.origin, [50.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn step_slide_doesnt_climb_a_wall_taller_than_a_step() {
+        let map = default_map();
+        let physics = PhysicsConfig::default();
+        // Standing on the floor, the sphere's center is a radius above it
+        let start = Vector3::new(200.0, 0.0, -64.0 + 15.0 + 0.25);
+        let mut slid = Player {
+            origin: start,
+            velocity: Vector3::new(1000.0, 0.0, 0.0),
+            on_ground: true,
+        };
+        let mut stepped = Player { ..slid };
+        slid.slide(&map, &physics, 0.1);
+        stepped.step_slide(&map, &physics, 0.1);
+        assert_near(stepped.origin, [slid.origin.x, slid.origin.y, slid.origin.z]);
+        assert_near(stepped.velocity, [slid.velocity.x, slid.velocity.y, slid.velocity.z]);
+    }
+}
@@ -0,0 +1,164 @@
+use glium;
+use glium::Surface;
+use glium::backend::Facade;
+use glium::draw_parameters::AnySamplesPassedQuery;
+use bsp_reader::BspMap;
+use lines::LineVertex;
+use primitives;
+
+/// Boxes are grown by this much so geometry lying exactly on the leaf bounds isn't hidden by
+/// the depth buffer it wrote itself.
+const MARGIN: f32 = 8.0;
+
+/// Returns the bounds of every cluster in Quake 3 space, indexed by cluster. Clusters without
+/// any leaf are `None`.
+pub fn cluster_bounds(map: &BspMap) -> Vec<Option<([f32; 3], [f32; 3])>> {
+    let count = map.leafs.iter().map(|l| l.cluster + 1).max().unwrap_or(0).max(0) as usize;
+    let mut bounds: Vec<Option<([f32; 3], [f32; 3])>> = vec![None; count];
+
+    for leaf in &map.leafs {
+        if leaf.cluster < 0 {
+            continue;
+        }
+        let mins = [leaf.mins[0] as f32, leaf.mins[1] as f32, leaf.mins[2] as f32];
+        let maxs = [leaf.maxs[0] as f32, leaf.maxs[1] as f32, leaf.maxs[2] as f32];
+        let cluster = &mut bounds[leaf.cluster as usize];
+        *cluster = Some(match *cluster {
+            Some((a, b)) => {
+                ([a[0].min(mins[0]), a[1].min(mins[1]), a[2].min(mins[2])],
+                 [b[0].max(maxs[0]), b[1].max(maxs[1]), b[2].max(maxs[2])])
+            }
+            None => (mins, maxs),
+        });
+    }
+
+    bounds
+}
+
+/// Returns the clusters that reference each face in ascending order, empty for faces that
+/// aren't in any leaf. A face that spans several clusters has to be drawn if any of them is
+/// visible.
+pub fn face_clusters(map: &BspMap) -> Vec<Vec<i32>> {
+    let mut clusters = vec![Vec::new(); map.faces.len()];
+    for leaf in &map.leafs {
+        if leaf.cluster < 0 {
+            continue;
+        }
+        for i in 0..leaf.n_leaffaces {
+            let face = map.leaffaces[(leaf.leafface + i) as usize] as usize;
+            if let Some(face_clusters) = clusters.get_mut(face) {
+                if !face_clusters.contains(&leaf.cluster) {
+                    face_clusters.push(leaf.cluster);
+                }
+            }
+        }
+    }
+    for face_clusters in &mut clusters {
+        face_clusters.sort();
+    }
+    clusters
+}
+
+/// Hides clusters whose bounding box didn't pass the depth test. The boxes are drawn after the
+/// world with color and depth writes off and their results are read a frame later, so waiting
+/// for the GPU never stalls the frame.
+pub struct OcclusionCuller {
+    program: glium::Program,
+    boxes: glium::VertexBuffer<LineVertex>,
+    bounds: Vec<Option<([f32; 3], [f32; 3])>>,
+    queries: Vec<Option<AnySamplesPassedQuery>>,
+    visible: Vec<bool>,
+}
+
+impl OcclusionCuller {
+    pub fn new<F: Facade>(display: &F,
+                          program: glium::Program,
+                          bounds: Vec<Option<([f32; 3], [f32; 3])>>)
+                          -> OcclusionCuller {
+        let mut vertexes = Vec::with_capacity(bounds.len() * primitives::BOX_TRIANGLE_VERTEXES);
+        for b in &bounds {
+            let (mins, maxs) = b.unwrap_or(([0.0; 3], [0.0; 3]));
+            primitives::box_triangles(&mut vertexes,
+                                      [mins[0] - MARGIN, mins[1] - MARGIN, mins[2] - MARGIN],
+                                      [maxs[0] + MARGIN, maxs[1] + MARGIN, maxs[2] + MARGIN],
+                                      [1.0, 1.0, 1.0]);
+        }
+
+        OcclusionCuller {
+            program: program,
+            boxes: glium::VertexBuffer::new(display, &vertexes).unwrap(),
+            queries: bounds.iter().map(|_| None).collect(),
+            visible: vec![true; bounds.len()],
+            bounds: bounds,
+        }
+    }
+
+    /// Clusters that are unknown or haven't been tested yet count as visible.
+    pub fn is_visible(&self, cluster: i32) -> bool {
+        cluster < 0 || self.visible.get(cluster as usize).cloned().unwrap_or(true)
+    }
+
+    pub fn occluded(&self) -> usize {
+        self.visible.iter().filter(|&&v| !v).count()
+    }
+
+    /// Picks up the results of the queries that have finished since the last frame.
+    pub fn collect(&mut self) {
+        for (query, visible) in self.queries.iter_mut().zip(self.visible.iter_mut()) {
+            if query.as_ref().map_or(false, |q| q.is_ready()) {
+                *visible = query.take().unwrap().get();
+            }
+        }
+    }
+
+    /// Draws the box of every cluster without a pending query against the depth buffer of
+    /// `target`. `camera` is in Quake 3 space, clusters around it are always visible.
+    pub fn query<F: Facade, S: Surface>(&mut self,
+                                        display: &F,
+                                        target: &mut S,
+                                        mvp: [[f32; 4]; 4],
+                                        camera: [f32; 3]) {
+        let uniforms = uniform!{ mvp: mvp };
+
+        for i in 0..self.bounds.len() {
+            let (mins, maxs) = match self.bounds[i] {
+                Some(bounds) => bounds,
+                None => continue,
+            };
+            if (0..3).all(|a| camera[a] > mins[a] - MARGIN && camera[a] < maxs[a] + MARGIN) {
+                self.queries[i] = None;
+                self.visible[i] = true;
+                continue;
+            }
+            if self.queries[i].is_some() {
+                continue;
+            }
+
+            let query = match AnySamplesPassedQuery::new(display, true) {
+                Ok(query) => query,
+                Err(_) => return,
+            };
+            {
+                let params = glium::DrawParameters {
+                    depth: glium::Depth {
+                        test: glium::draw_parameters::DepthTest::IfLess,
+                        write: false,
+                        ..Default::default()
+                    },
+                    color_mask: (false, false, false, false),
+                    samples_passed_query: Some((&query).into()),
+                    ..Default::default()
+                };
+                let count = primitives::BOX_TRIANGLE_VERTEXES;
+                let vertexes = self.boxes.slice(i * count..(i + 1) * count).unwrap();
+                target.draw(vertexes,
+                            &glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+                            &self.program,
+                            &uniforms,
+                            &params)
+                      .unwrap();
+            }
+            self.queries[i] = Some(query);
+        }
+    }
+}
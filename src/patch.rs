@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use bsp_reader::{Face, Vertex};
+
+/// Vertexes of different patches closer than this many units on every axis are welded into one
+/// position. Patches that share an edge evaluate the same curve from the same control points,
+/// but in a different order, so their edge vertexes differ by float noise far below this.
+/// Positions are snapped to a grid of this size to find each other, so in rare cases two
+/// vertexes just either side of a grid line aren't welded.
+pub const WELD_TOLERANCE: f32 = 1.0 / 64.0;
+
+pub struct Patch {
+    pub vertexes: Vec<Vertex>,
+    pub indices: Vec<u32>,
+}
+
+/// Returns the width and height of the control point grid of a patch face. Both have to be odd
+/// and at least 3, since the grid is made of 3x3 quadratic patches that share their edge rows
+/// and columns, and the face has to have exactly that many vertexes.
+pub fn grid_size(face: &Face) -> Result<(usize, usize), String> {
+    let (width, height) = (face.size[0], face.size[1]);
+    if width < 3 || height < 3 || width % 2 == 0 || height % 2 == 0 {
+        return Err(format!("control grid of {}x{} isn't odd and at least 3x3", width, height));
+    }
+    if face.n_vertexes != width * height {
+        return Err(format!("{} vertexes for a control grid of {}x{}",
+                           face.n_vertexes,
+                           width,
+                           height));
+    }
+    Ok((width as usize, height as usize))
+}
+
+/// Tessellates every 3x3 patch of the control grid into `level` by `level` quads. The patches
+/// start at every second row and column, so a 5x3 grid has two patches sharing column 2. Faces
+/// that fail `grid_size` or whose vertexes are out of range give an empty patch.
+pub fn tessellate(face: &Face, vertexes: &[Vertex], level: usize) -> Patch {
+    let mut patch = Patch {
+        vertexes: Vec::new(),
+        indices: Vec::new(),
+    };
+    let (width, height) = match grid_size(face) {
+        Ok(size) => size,
+        Err(_) => return patch,
+    };
+    let start = face.vertex.max(0) as usize;
+    let controls = match vertexes.get(start..start + width * height) {
+        Some(controls) => controls,
+        None => return patch,
+    };
+
+    for py in 0..(height - 1) / 2 {
+        for px in 0..(width - 1) / 2 {
+            let mut grid = [&controls[0]; 9];
+            for row in 0..3 {
+                for col in 0..3 {
+                    grid[row * 3 + col] = &controls[(py * 2 + row) * width + px * 2 + col];
+                }
+            }
+            tessellate_quadratic(&grid, level, &mut patch);
+        }
+    }
+
+    patch
+}
+
+/// Gives all vertexes that round to the same multiple of `WELD_TOLERANCE` the position of the
+/// first one seen. One welder is used for all patches of a tessellation level, patches of
+/// different levels never meet.
+pub struct Welder {
+    positions: HashMap<[i32; 3], [f32; 3]>,
+}
+
+impl Welder {
+    pub fn new() -> Welder {
+        Welder { positions: HashMap::new() }
+    }
+
+    /// Moves the vertexes of `patch` onto the vertexes welded before them. Returns how many moved.
+    pub fn weld(&mut self, patch: &mut Patch) -> usize {
+        let mut moved = 0;
+        for vertex in &mut patch.vertexes {
+            let position = *self.positions.entry(weld_key(vertex.position)).or_insert(vertex.position);
+            if position != vertex.position {
+                vertex.position = position;
+                moved += 1;
+            }
+        }
+        moved
+    }
+}
+
+fn weld_key(position: [f32; 3]) -> [i32; 3] {
+    [(position[0] / WELD_TOLERANCE).round() as i32,
+     (position[1] / WELD_TOLERANCE).round() as i32,
+     (position[2] / WELD_TOLERANCE).round() as i32]
+}
+
+/// Returns the center of the control points of the connected patches each face belongs to, or
+/// `None` for faces that aren't valid patches. Patches are connected if control points on their
+/// borders coincide, which they do along shared edges. Choosing the tessellation level of all
+/// connected patches by the same center gives their shared edges matching vertexes.
+pub fn group_centers(faces: &[Face], vertexes: &[Vertex]) -> Vec<Option<[f32; 3]>> {
+    let mut parents: Vec<usize> = (0..faces.len()).collect();
+    fn root(parents: &mut Vec<usize>, i: usize) -> usize {
+        let mut i = i;
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+
+    let controls = |face: &Face| {
+        let start = face.vertex.max(0) as usize;
+        grid_size(face).ok().and_then(|(width, height)| {
+            vertexes.get(start..start + width * height).map(|c| (c, width, height))
+        })
+    };
+    let mut owners: HashMap<[i32; 3], usize> = HashMap::new();
+    for (i, face) in faces.iter().enumerate().filter(|&(_, f)| f.f_type == 2) {
+        let (grid, width, height) = match controls(face) {
+            Some(grid) => grid,
+            None => continue,
+        };
+        for (j, control) in grid.iter().enumerate() {
+            let (column, row) = (j % width, j / width);
+            if column != 0 && column != width - 1 && row != 0 && row != height - 1 {
+                continue;
+            }
+            let owner = *owners.entry(weld_key(control.position)).or_insert(i);
+            let (a, b) = (root(&mut parents, owner), root(&mut parents, i));
+            parents[a] = b;
+        }
+    }
+
+    let mut sums: HashMap<usize, ([f32; 3], usize)> = HashMap::new();
+    for (i, face) in faces.iter().enumerate().filter(|&(_, f)| f.f_type == 2) {
+        if let Some((grid, _, _)) = controls(face) {
+            let group = root(&mut parents, i);
+            let sum = sums.entry(group).or_insert(([0.0; 3], 0));
+            for control in grid {
+                for axis in 0..3 {
+                    (sum.0)[axis] += control.position[axis];
+                }
+            }
+            sum.1 += grid.len();
+        }
+    }
+
+    (0..faces.len())
+        .map(|i| {
+            if faces[i].f_type != 2 || controls(&faces[i]).is_none() {
+                return None;
+            }
+            let (sum, count) = sums[&root(&mut parents, i)];
+            let count = count as f32;
+            Some([sum[0] / count, sum[1] / count, sum[2] / count])
+        })
+        .collect()
+}
+
+fn tessellate_quadratic(grid: &[&Vertex; 9], level: usize, patch: &mut Patch) {
+    let base = patch.vertexes.len() as u32;
+
+    for i in 0..level + 1 {
+        let v = i as f32 / level as f32;
+        let column = [blend(&[grid[0], grid[3], grid[6]], v),
+                      blend(&[grid[1], grid[4], grid[7]], v),
+                      blend(&[grid[2], grid[5], grid[8]], v)];
+        for j in 0..level + 1 {
+            let u = j as f32 / level as f32;
+            let mut vertex = blend(&[&column[0], &column[1], &column[2]], u);
+            vertex.normal = normalize(vertex.normal);
+            patch.vertexes.push(vertex);
+        }
+    }
+
+    let row = level as u32 + 1;
+    for i in 0..level as u32 {
+        for j in 0..level as u32 {
+            let a = base + i * row + j;
+            let b = a + 1;
+            let c = a + row;
+            let d = c + 1;
+            patch.indices.extend_from_slice(&[a, c, b, b, c, d]);
+        }
+    }
+}
+
+fn blend(points: &[&Vertex; 3], t: f32) -> Vertex {
+    let weights = [(1.0 - t) * (1.0 - t), 2.0 * t * (1.0 - t), t * t];
+    let mix = |f: &Fn(&Vertex) -> f32| {
+        weights[0] * f(points[0]) + weights[1] * f(points[1]) + weights[2] * f(points[2])
+    };
+
+    Vertex {
+        position: [mix(&|p| p.position[0]), mix(&|p| p.position[1]), mix(&|p| p.position[2])],
+        texcoord: [[mix(&|p| p.texcoord[0][0]), mix(&|p| p.texcoord[0][1])],
+                   [mix(&|p| p.texcoord[1][0]), mix(&|p| p.texcoord[1][1])]],
+        normal: [mix(&|p| p.normal[0]), mix(&|p| p.normal[1]), mix(&|p| p.normal[2])],
+        color: [mix(&|p| p.color[0] as f32).round() as u8,
+                mix(&|p| p.color[1] as f32).round() as u8,
+                mix(&|p| p.color[2] as f32).round() as u8,
+                mix(&|p| p.color[3] as f32).round() as u8],
+    }
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if length > 0.0 {
+        [v[0] / length, v[1] / length, v[2] / length]
+    } else {
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch_face(width: i32, height: i32) -> Face {
+        Face {
+            texture: 0,
+            effect: -1,
+            f_type: 2,
+            vertex: 0,
+            n_vertexes: width * height,
+            meshvert: 0,
+            n_meshverts: 0,
+            lm_index: -1,
+            lm_start: [0; 2],
+            lm_size: [0; 2],
+            lm_origin: [0.0; 3],
+            lm_vecs: [[0.0; 3]; 2],
+            normal: [0.0, 0.0, 1.0],
+            size: [width, height],
+        }
+    }
+
+    /// A flat control grid with each point at its column and row.
+    fn controls(width: i32, height: i32) -> Vec<Vertex> {
+        let mut controls = Vec::new();
+        for row in 0..height {
+            for col in 0..width {
+                controls.push(Vertex {
+                    position: [col as f32, row as f32, 0.0],
+                    texcoord: [[0.0; 2]; 2],
+                    normal: [0.0, 0.0, 1.0],
+                    color: [255; 4],
+                });
+            }
+        }
+        controls
+    }
+
+    #[test]
+    fn grid_size_accepts_odd_grids_of_at_least_3() {
+        assert_eq!(grid_size(&patch_face(3, 3)), Ok((3, 3)));
+        assert_eq!(grid_size(&patch_face(5, 3)), Ok((5, 3)));
+        assert_eq!(grid_size(&patch_face(3, 7)), Ok((3, 7)));
+    }
+
+    #[test]
+    fn grid_size_rejects_even_and_small_grids() {
+        for &(width, height) in &[(2, 3), (4, 3), (3, 6), (1, 3), (3, 1), (1, 1), (0, 0)] {
+            assert!(grid_size(&patch_face(width, height)).is_err(),
+                    "{}x{}",
+                    width,
+                    height);
+        }
+    }
+
+    #[test]
+    fn grid_size_rejects_a_vertex_count_that_doesnt_match() {
+        let mut face = patch_face(3, 3);
+        face.n_vertexes = 10;
+        assert!(grid_size(&face).is_err());
+    }
+
+    #[test]
+    fn tessellate_splits_a_5x3_grid_into_two_patches_sharing_column_2() {
+        let level = 4;
+        let patch = tessellate(&patch_face(5, 3), &controls(5, 3), level);
+        let row = level + 1;
+        assert_eq!(patch.vertexes.len(), 2 * row * row);
+        assert_eq!(patch.indices.len(), 2 * level * level * 6);
+
+        // The last column of the first patch is the first column of the second
+        for i in 0..row {
+            let left = patch.vertexes[i * row + level].position;
+            let right = patch.vertexes[row * row + i * row].position;
+            assert_eq!(left, right);
+            assert_eq!(left[0], 2.0);
+        }
+    }
+
+    #[test]
+    fn tessellate_gives_nothing_for_invalid_grids() {
+        let patch = tessellate(&patch_face(4, 3), &controls(4, 3), 4);
+        assert!(patch.vertexes.is_empty() && patch.indices.is_empty());
+        // Too few vertexes for the grid
+        let patch = tessellate(&patch_face(5, 3), &controls(3, 3), 4);
+        assert!(patch.vertexes.is_empty() && patch.indices.is_empty());
+    }
+}
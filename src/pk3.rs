@@ -0,0 +1,249 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::read::DeflateDecoder;
+
+const LOCAL_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_SIGNATURE: u32 = 0x02014b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+const LOCAL_HEADER_SIZE: usize = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+/// The end of central directory record, which can be followed by a comment of up to 64 KB.
+const END_SIZE: usize = 22;
+const MAX_COMMENT: usize = 0xffff;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// A file in a pk3 archive, as listed by its central directory.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Path inside the archive with forward slashes, like `maps/q3dm1.bsp`.
+    pub name: String,
+    method: u16,
+    compressed_size: u64,
+    size: u64,
+    header_offset: u64,
+}
+
+/// A .pk3 archive, which is a zip file. Only the directory is read up front, files are read
+/// when asked for. They have to be stored or deflated, which is all the Quake 3 tools write.
+pub struct Pk3<R> {
+    reader: R,
+    entries: Vec<Entry>,
+}
+
+impl Pk3<File> {
+    pub fn open(path: &Path) -> io::Result<Pk3<File>> {
+        Pk3::new(try!(File::open(path)))
+    }
+}
+
+impl<R: Read + Seek> Pk3<R> {
+    /// Reads the central directory at the end of the archive.
+    pub fn new(mut reader: R) -> io::Result<Pk3<R>> {
+        let length = try!(reader.seek(SeekFrom::End(0)));
+        let tail_length = length.min((END_SIZE + MAX_COMMENT) as u64);
+        try!(reader.seek(SeekFrom::Start(length - tail_length)));
+        let mut tail = vec![0; tail_length as usize];
+        try!(reader.read_exact(&mut tail));
+
+        // Searched from the back, since the comment after the record could contain the signature
+        let end = match (0..(tail.len() + 1).saturating_sub(END_SIZE))
+                            .rev()
+                            .find(|&i| LittleEndian::read_u32(&tail[i..]) == END_SIGNATURE) {
+            Some(end) => end,
+            None => return Err(invalid("not a zip archive".to_owned())),
+        };
+        let record = &tail[end..];
+        let count = LittleEndian::read_u16(&record[10..]) as usize;
+        let directory_size = LittleEndian::read_u32(&record[12..]) as u64;
+        let directory_offset = LittleEndian::read_u32(&record[16..]) as u64;
+        if directory_offset + directory_size > length {
+            return Err(invalid("the central directory is past the end of the archive".to_owned()));
+        }
+
+        try!(reader.seek(SeekFrom::Start(directory_offset)));
+        let mut directory = vec![0; directory_size as usize];
+        try!(reader.read_exact(&mut directory));
+        let entries = try!(read_directory(&directory, count));
+        Ok(Pk3 {
+            reader: reader,
+            entries: entries,
+        })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// The .bsp files in the maps directory, where the game looks for them.
+    pub fn maps(&self) -> Vec<Entry> {
+        self.entries
+            .iter()
+            .filter(|e| {
+                let name = e.name.to_lowercase();
+                name.starts_with("maps/") && name.ends_with(".bsp")
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Reads and decompresses a whole file.
+    pub fn read(&mut self, entry: &Entry) -> io::Result<Vec<u8>> {
+        try!(self.reader.seek(SeekFrom::Start(entry.header_offset)));
+        let mut header = [0; LOCAL_HEADER_SIZE];
+        try!(self.reader.read_exact(&mut header));
+        if LittleEndian::read_u32(&header) != LOCAL_SIGNATURE {
+            return Err(invalid(format!("{} has no local header", entry.name)));
+        }
+        // The name and extra field can differ from the ones in the central directory
+        let skip = LittleEndian::read_u16(&header[26..]) as i64 +
+                   LittleEndian::read_u16(&header[28..]) as i64;
+        try!(self.reader.seek(SeekFrom::Current(skip)));
+
+        let compressed = self.reader.by_ref().take(entry.compressed_size);
+        let mut data = Vec::new();
+        match entry.method {
+            STORED => try!(compressed.take(entry.size).read_to_end(&mut data)),
+            DEFLATED => {
+                try!(DeflateDecoder::new(compressed).take(entry.size).read_to_end(&mut data))
+            }
+            method => {
+                return Err(invalid(format!("{} uses compression method {}, only stored and \
+                                            deflated files can be read",
+                                           entry.name,
+                                           method)))
+            }
+        };
+        if data.len() as u64 != entry.size {
+            return Err(invalid(format!("{} is truncated, {} of {} bytes",
+                                       entry.name,
+                                       data.len(),
+                                       entry.size)));
+        }
+        Ok(data)
+    }
+}
+
+fn read_directory(directory: &[u8], count: usize) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    for _ in 0..count {
+        if offset + CENTRAL_HEADER_SIZE > directory.len() ||
+           LittleEndian::read_u32(&directory[offset..]) != CENTRAL_SIGNATURE {
+            return Err(invalid(format!("the central directory ends after {} of {} files",
+                                       entries.len(),
+                                       count)));
+        }
+        let header = &directory[offset..];
+        let name_length = LittleEndian::read_u16(&header[28..]) as usize;
+        let extra_length = LittleEndian::read_u16(&header[30..]) as usize;
+        let comment_length = LittleEndian::read_u16(&header[32..]) as usize;
+        let name_end = offset + CENTRAL_HEADER_SIZE + name_length;
+        if name_end > directory.len() {
+            return Err(invalid("a file name is past the end of the central directory".to_owned()));
+        }
+        entries.push(Entry {
+            name: String::from_utf8_lossy(&directory[offset + CENTRAL_HEADER_SIZE..name_end])
+                      .into_owned(),
+            method: LittleEndian::read_u16(&header[10..]),
+            compressed_size: LittleEndian::read_u32(&header[20..]) as u64,
+            size: LittleEndian::read_u32(&header[24..]) as u64,
+            header_offset: LittleEndian::read_u32(&header[42..]) as u64,
+        });
+        offset = name_end + extra_length + comment_length;
+    }
+    Ok(entries)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    /// Builds a zip archive of `(name, method, compressed data, size)` files.
+    fn archive(files: &[(&str, u16, &[u8], usize)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+        for &(name, method, contents, size) in files {
+            let offset = data.len();
+            let mut local = [0; LOCAL_HEADER_SIZE];
+            LittleEndian::write_u32(&mut local, LOCAL_SIGNATURE);
+            LittleEndian::write_u16(&mut local[8..], method);
+            LittleEndian::write_u32(&mut local[18..], contents.len() as u32);
+            LittleEndian::write_u32(&mut local[22..], size as u32);
+            LittleEndian::write_u16(&mut local[26..], name.len() as u16);
+            data.extend_from_slice(&local);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(contents);
+
+            let mut central = [0; CENTRAL_HEADER_SIZE];
+            LittleEndian::write_u32(&mut central, CENTRAL_SIGNATURE);
+            LittleEndian::write_u16(&mut central[10..], method);
+            LittleEndian::write_u32(&mut central[20..], contents.len() as u32);
+            LittleEndian::write_u32(&mut central[24..], size as u32);
+            LittleEndian::write_u16(&mut central[28..], name.len() as u16);
+            LittleEndian::write_u32(&mut central[42..], offset as u32);
+            directory.extend_from_slice(&central);
+            directory.extend_from_slice(name.as_bytes());
+        }
+
+        let mut end = [0; END_SIZE];
+        LittleEndian::write_u32(&mut end, END_SIGNATURE);
+        LittleEndian::write_u16(&mut end[8..], files.len() as u16);
+        LittleEndian::write_u16(&mut end[10..], files.len() as u16);
+        LittleEndian::write_u32(&mut end[12..], directory.len() as u32);
+        LittleEndian::write_u32(&mut end[16..], data.len() as u32);
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(&end);
+        data
+    }
+
+    #[test]
+    fn reads_stored_and_deflated_files() {
+        // A deflate stream of one final block that stores "hello" uncompressed
+        let deflated = [0x01, 5, 0, 0xfa, 0xff, b'h', b'e', b'l', b'l', b'o'];
+        let data = archive(&[("maps/a.bsp", STORED, b"stored", 6),
+                             ("maps/b.bsp", DEFLATED, &deflated, 5)]);
+        let mut pk3 = Pk3::new(Cursor::new(data)).unwrap();
+        let entries = pk3.entries().to_vec();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(pk3.read(&entries[0]).unwrap(), b"stored".to_vec());
+        assert_eq!(pk3.read(&entries[1]).unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn maps_lists_only_bsp_files_in_the_maps_directory() {
+        let data = archive(&[("maps/q3dm1.bsp", STORED, b"", 0),
+                             ("MAPS/Q3DM2.BSP", STORED, b"", 0),
+                             ("maps/q3dm1.aas", STORED, b"", 0),
+                             ("textures/q3dm1.bsp", STORED, b"", 0)]);
+        let pk3 = Pk3::new(Cursor::new(data)).unwrap();
+        let names: Vec<String> = pk3.maps().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["maps/q3dm1.bsp".to_owned(), "MAPS/Q3DM2.BSP".to_owned()]);
+    }
+
+    #[test]
+    fn rejects_what_it_cant_read() {
+        assert!(Pk3::new(Cursor::new(b"not a zip file at all, just some text".to_vec())).is_err());
+
+        let data = archive(&[("maps/a.bsp", 12, b"bzip2", 5), ("maps/b.bsp", STORED, b"abc", 4)]);
+        let mut pk3 = Pk3::new(Cursor::new(data)).unwrap();
+        let entries = pk3.entries().to_vec();
+        assert!(pk3.read(&entries[0]).is_err());
+        assert!(pk3.read(&entries[1]).is_err());
+
+        // The directory says there are more files than it holds
+        let mut data = archive(&[("maps/a.bsp", STORED, b"", 0)]);
+        let end = data.len() - END_SIZE;
+        LittleEndian::write_u16(&mut data[end + 10..], 2);
+        assert!(Pk3::new(Cursor::new(data)).is_err());
+    }
+}
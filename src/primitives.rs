@@ -0,0 +1,128 @@
+use glium;
+use glium::backend::Facade;
+use lines::LineVertex;
+
+/// Sides of a box as corners, counter clockwise seen from outside. Bit 0 of a corner selects
+/// the max x, bit 1 the max y and bit 2 the max z. The order is -x, +x, -y, +y, -z, +z.
+const BOX_SIDES: [[usize; 4]; 6] = [[0, 4, 6, 2], [1, 3, 7, 5], [0, 1, 5, 4], [2, 6, 7, 3],
+                                    [0, 2, 3, 1], [4, 5, 7, 6]];
+/// Brightness of every side for `shaded_box_triangles`, lit from above and the front.
+const SIDE_SHADES: [f32; 6] = [0.8, 0.8, 0.6, 1.2, 0.7, 1.0];
+/// Pairs of corners joined by the edges of a box.
+const BOX_EDGES: [(usize, usize); 12] = [(0, 1), (2, 3), (4, 5), (6, 7), (0, 2), (1, 3), (4, 6),
+                                         (5, 7), (0, 4), (1, 5), (2, 6), (3, 7)];
+
+/// Vertexes per box added by `box_triangles`.
+pub const BOX_TRIANGLE_VERTEXES: usize = 36;
+
+fn corner(mins: [f32; 3], maxs: [f32; 3], i: usize) -> [f32; 3] {
+    [if i & 1 == 0 { mins[0] } else { maxs[0] },
+     if i & 2 == 0 { mins[1] } else { maxs[1] },
+     if i & 4 == 0 { mins[2] } else { maxs[2] }]
+}
+
+fn push_box(vertexes: &mut Vec<LineVertex>,
+            mins: [f32; 3],
+            maxs: [f32; 3],
+            color: [f32; 3],
+            shades: &[f32; 6]) {
+    for (side, &shade) in BOX_SIDES.iter().zip(shades.iter()) {
+        let shaded = [color[0] * shade, color[1] * shade, color[2] * shade];
+        for &i in [side[0], side[1], side[2], side[0], side[2], side[3]].iter() {
+            vertexes.push(LineVertex {
+                position: corner(mins, maxs, i),
+                color: shaded,
+            });
+        }
+    }
+}
+
+/// Adds the triangles of an axis aligned box in a single color.
+pub fn box_triangles(vertexes: &mut Vec<LineVertex>,
+                     mins: [f32; 3],
+                     maxs: [f32; 3],
+                     color: [f32; 3]) {
+    push_box(vertexes, mins, maxs, color, &[1.0; 6]);
+}
+
+/// Adds the triangles of a box with every side a little darker or lighter, so its shape reads
+/// without lighting.
+pub fn shaded_box_triangles(vertexes: &mut Vec<LineVertex>,
+                            mins: [f32; 3],
+                            maxs: [f32; 3],
+                            color: [f32; 3]) {
+    push_box(vertexes, mins, maxs, color, &SIDE_SHADES);
+}
+
+/// Adds the twelve edges of a box as line segments.
+pub fn box_lines(vertexes: &mut Vec<LineVertex>, mins: [f32; 3], maxs: [f32; 3], color: [f32; 3]) {
+    for &(a, b) in BOX_EDGES.iter() {
+        vertexes.push(LineVertex {
+            position: corner(mins, maxs, a),
+            color: color,
+        });
+        vertexes.push(LineVertex {
+            position: corner(mins, maxs, b),
+            color: color,
+        });
+    }
+}
+
+/// Adds the x, y and z axes from the origin in red, green and blue, `length` units long, and a
+/// grey grid of lines `spacing` apart in the z = 0 plane that reaches as far.
+pub fn axis_gizmo(vertexes: &mut Vec<LineVertex>, length: f32, spacing: f32) {
+    let mut push = |from: [f32; 3], to: [f32; 3], color: [f32; 3]| {
+        vertexes.push(LineVertex {
+            position: from,
+            color: color,
+        });
+        vertexes.push(LineVertex {
+            position: to,
+            color: color,
+        });
+    };
+
+    let lines = (length / spacing).floor() as i32;
+    let grey = [0.4, 0.4, 0.4];
+    for i in -lines..lines + 1 {
+        let offset = i as f32 * spacing;
+        push([offset, -length, 0.0], [offset, length, 0.0], grey);
+        push([-length, offset, 0.0], [length, offset, 0.0], grey);
+    }
+    push([0.0; 3], [length, 0.0, 0.0], [1.0, 0.0, 0.0]);
+    push([0.0; 3], [0.0, length, 0.0], [0.0, 1.0, 0.0]);
+    push([0.0; 3], [0.0, 0.0, length], [0.0, 0.0, 1.0]);
+}
+
+/// A white cube from -0.5 to 0.5 on every axis, drawn as a triangle list.
+pub fn unit_cube<F: Facade>(display: &F) -> glium::VertexBuffer<LineVertex> {
+    let mut vertexes = Vec::with_capacity(BOX_TRIANGLE_VERTEXES);
+    box_triangles(&mut vertexes, [-0.5; 3], [0.5; 3], [1.0; 3]);
+    glium::VertexBuffer::new(display, &vertexes).unwrap()
+}
+
+/// A rectangle in the z = 0 plane with its lower left corner at `position`, for drawing with an
+/// orthographic projection in pixels. Counter clockwise with y up.
+pub fn screen_quad<F: Facade>(display: &F,
+                              position: [f32; 2],
+                              size: [f32; 2],
+                              color: [f32; 3])
+                              -> (glium::VertexBuffer<LineVertex>, glium::IndexBuffer<u16>) {
+    let corners = [[position[0], position[1], 0.0],
+                   [position[0] + size[0], position[1], 0.0],
+                   [position[0] + size[0], position[1] + size[1], 0.0],
+                   [position[0], position[1] + size[1], 0.0]];
+    let vertexes: Vec<LineVertex> = corners.iter()
+                                           .map(|&c| {
+                                               LineVertex {
+                                                   position: c,
+                                                   color: color,
+                                               }
+                                           })
+                                           .collect();
+    (glium::VertexBuffer::new(display, &vertexes).unwrap(),
+     glium::IndexBuffer::new(display,
+                             glium::index::PrimitiveType::TrianglesList,
+                             &[0u16, 1, 2, 0, 2, 3])
+         .unwrap())
+}
@@ -0,0 +1,84 @@
+use glium::backend::Facade;
+use glium::framebuffer::{DepthRenderBuffer, SimpleFrameBuffer};
+use glium::texture::{DepthFormat, SrgbTexture2d};
+
+const SCALE_STEP: f32 = 0.05;
+const ADJUST_INTERVAL: f32 = 0.5;
+
+/// Offscreen render target whose size follows the window scaled by a factor that shrinks when
+/// frames take longer than the target frame time and grows again when there is headroom.
+pub struct DynamicResolution {
+    pub scale: f32,
+    min_scale: f32,
+    max_scale: f32,
+    target_frame_time: f32,
+    elapsed: f32,
+    frames: u32,
+    window_size: (u32, u32),
+    color: SrgbTexture2d,
+    depth: DepthRenderBuffer,
+}
+
+impl DynamicResolution {
+    /// `target_frame_time` is in seconds.
+    pub fn new<F: Facade>(display: &F,
+                          window_size: (u32, u32),
+                          min_scale: f32,
+                          max_scale: f32,
+                          target_frame_time: f32)
+                          -> DynamicResolution {
+        let (color, depth) = create_buffers(display, scaled(window_size, max_scale));
+        DynamicResolution {
+            scale: max_scale,
+            min_scale: min_scale,
+            max_scale: max_scale,
+            target_frame_time: target_frame_time,
+            elapsed: 0.0,
+            frames: 0,
+            window_size: window_size,
+            color: color,
+            depth: depth,
+        }
+    }
+
+    /// Records the last frame time and resizes the buffers if the scale or the window changed.
+    pub fn update<F: Facade>(&mut self, display: &F, dt: f32, window_size: (u32, u32)) {
+        self.elapsed += dt;
+        self.frames += 1;
+
+        let mut scale = self.scale;
+        if self.elapsed >= ADJUST_INTERVAL {
+            let frame_time = self.elapsed / self.frames as f32;
+            if frame_time > self.target_frame_time * 1.1 {
+                scale -= SCALE_STEP;
+            } else if frame_time < self.target_frame_time * 0.8 {
+                scale += SCALE_STEP;
+            }
+            scale = scale.max(self.min_scale).min(self.max_scale);
+            self.elapsed = 0.0;
+            self.frames = 0;
+        }
+
+        if scale != self.scale || window_size != self.window_size {
+            let (color, depth) = create_buffers(display, scaled(window_size, scale));
+            self.color = color;
+            self.depth = depth;
+            self.scale = scale;
+            self.window_size = window_size;
+        }
+    }
+
+    pub fn framebuffer<F: Facade>(&self, display: &F) -> SimpleFrameBuffer {
+        SimpleFrameBuffer::with_depth_buffer(display, &self.color, &self.depth).unwrap()
+    }
+}
+
+fn scaled(size: (u32, u32), scale: f32) -> (u32, u32) {
+    (((size.0 as f32 * scale) as u32).max(1), ((size.1 as f32 * scale) as u32).max(1))
+}
+
+fn create_buffers<F: Facade>(display: &F, size: (u32, u32)) -> (SrgbTexture2d, DepthRenderBuffer) {
+    let color = SrgbTexture2d::empty(display, size.0, size.1).unwrap();
+    let depth = DepthRenderBuffer::new(display, DepthFormat::I24, size.0, size.1).unwrap();
+    (color, depth)
+}
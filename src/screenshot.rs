@@ -0,0 +1,78 @@
+use std::io;
+use glium::CapabilitiesSource;
+use glium::backend::Facade;
+use glium::framebuffer::{DepthRenderBuffer, SimpleFrameBuffer};
+use glium::texture::{DepthFormat, RawImage2d, SrgbTexture2d};
+use image;
+
+/// Size of a screenshot, either in pixels or relative to the window.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScreenshotSize {
+    Pixels(u32, u32),
+    Scale(f32),
+}
+
+impl ScreenshotSize {
+    pub fn resolve(self, window_size: (u32, u32)) -> (u32, u32) {
+        match self {
+            ScreenshotSize::Pixels(width, height) => (width, height),
+            ScreenshotSize::Scale(scale) => {
+                ((window_size.0 as f32 * scale).round() as u32,
+                 (window_size.1 as f32 * scale).round() as u32)
+            }
+        }
+    }
+}
+
+/// Offscreen color and depth buffers of any size the GPU supports, for screenshots that don't
+/// depend on the window size.
+pub struct Screenshot {
+    size: (u32, u32),
+    color: SrgbTexture2d,
+    depth: DepthRenderBuffer,
+}
+
+impl Screenshot {
+    /// Creates the buffers, or says why a framebuffer of `size` isn't possible on this GPU.
+    pub fn new<F: Facade>(display: &F, size: (u32, u32)) -> Result<Screenshot, String> {
+        if size.0 == 0 || size.1 == 0 {
+            return Err(format!("{}x{} has no pixels", size.0, size.1));
+        }
+        // Textures can be bigger than what can be drawn to at once
+        let (max_width, max_height) = display.get_context().get_capabilities().max_viewport_dims;
+        if size.0 > max_width as u32 || size.1 > max_height as u32 {
+            return Err(format!("{}x{} is bigger than the largest viewport of {}x{}",
+                               size.0,
+                               size.1,
+                               max_width,
+                               max_height));
+        }
+
+        let color = try!(SrgbTexture2d::empty(display, size.0, size.1)
+                             .map_err(|e| format!("no {}x{} texture: {}", size.0, size.1, e)));
+        let depth = try!(DepthRenderBuffer::new(display, DepthFormat::I24, size.0, size.1)
+                             .map_err(|e| format!("no {}x{} depth buffer: {}", size.0, size.1, e)));
+        Ok(Screenshot {
+            size: size,
+            color: color,
+            depth: depth,
+        })
+    }
+
+    pub fn framebuffer<F: Facade>(&self, display: &F) -> SimpleFrameBuffer {
+        SimpleFrameBuffer::with_depth_buffer(display, &self.color, &self.depth).unwrap()
+    }
+
+    /// Reads back what was drawn into the framebuffer and saves it to `path`, as PNG unless the
+    /// extension asks for another format.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let image: RawImage2d<u8> = self.color.read();
+        // Rows are read bottom up, images are stored top down
+        let row = self.size.0 as usize * 4;
+        let mut pixels = Vec::with_capacity(image.data.len());
+        for y in (0..self.size.1 as usize).rev() {
+            pixels.extend_from_slice(&image.data[y * row..(y + 1) * row]);
+        }
+        image::save_buffer(path, &pixels, self.size.0, self.size.1, image::RGBA(8))
+    }
+}
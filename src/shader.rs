@@ -0,0 +1,46 @@
+use std::fs::File;
+use std::io::{self, Read};
+use glium;
+use glium::backend::Facade;
+
+/// Shaders built into the binary, so it runs from any working directory.
+const EMBEDDED: [(&'static str, &'static str); 4] = [("world.vert",
+                                                      include_str!("shaders/world.vert")),
+                                                     ("world.frag",
+                                                      include_str!("shaders/world.frag")),
+                                                     ("lines.vert",
+                                                      include_str!("shaders/lines.vert")),
+                                                     ("lines.frag",
+                                                      include_str!("shaders/lines.frag"))];
+
+/// Returns the source of the shader file `name`, read from `directory` if one is given or the
+/// embedded copy otherwise. Errors include the path of the shader.
+pub fn read(directory: Option<&str>, name: &str) -> io::Result<String> {
+    match directory {
+        Some(directory) => {
+            let path = format!("{}/{}", directory, name);
+            let mut source = String::new();
+            match File::open(&path).and_then(|mut f| f.read_to_string(&mut source)) {
+                Ok(_) => Ok(source),
+                Err(error) => Err(io::Error::new(error.kind(), format!("{}: {}", path, error))),
+            }
+        }
+        None => {
+            EMBEDDED.iter().find(|s| s.0 == name).map(|s| s.1.to_owned()).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no embedded shader {}", name))
+            })
+        }
+    }
+}
+
+/// Reads and compiles the shader files `vertex` and `fragment`. The error contains the
+/// compiler log if compilation failed.
+pub fn program<F: Facade>(display: &F,
+                          directory: Option<&str>,
+                          vertex: &str,
+                          fragment: &str)
+                          -> Result<glium::Program, String> {
+    let vertex = try!(read(directory, vertex).map_err(|e| e.to_string()));
+    let fragment = try!(read(directory, fragment).map_err(|e| e.to_string()));
+    glium::Program::from_source(display, &vertex, &fragment, None).map_err(|e| e.to_string())
+}
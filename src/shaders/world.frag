@@ -1,12 +1,140 @@
 #version 330
 
+in vec2 frag_diffuse_uv;
+in vec2 frag_lightmap_uv;
 in vec3 frag_normal;
+in vec3 frag_position;
 in vec4 frag_color;
+in float frag_distance;
+in float frag_fog_volume;
+flat in float frag_face_type;
 
 out vec4 color;
 
+// Matches the order of RenderMode in main.rs
+const int MODE_TEXTURED = 0;
+const int MODE_TEXTURED_LIGHTMAP = 1;
+const int MODE_VERTEX_COLOR = 2;
+const int MODE_LIGHTMAP = 3;
+const int MODE_NORMALS = 4;
+const int MODE_FLAT_WHITE = 5;
+const int MODE_DELUXEMAP = 6;
+const int MODE_LIGHTMAP_UV = 7;
+const int MODE_ENTITY_LIGHTS = 8;
+const int MODE_FACE_TYPE = 9;
+
+// Matches MAX_LIGHTS in main.rs
+const int MAX_LIGHTS = 8;
+
+// Colors of polygons, patches, meshes and billboards, matches FACE_TYPE_COLORS in main.rs
+const vec3 FACE_TYPE_COLORS[4] = vec3[4](vec3(0.6, 0.6, 0.6),
+                                         vec3(1.0, 0.5, 0.1),
+                                         vec3(0.2, 0.8, 1.0),
+                                         vec3(1.0, 0.2, 0.8));
+
+uniform int render_mode;
+uniform sampler2D diffuse_map;
+// Terrain surfaces blend from the diffuse map to this one by vertex alpha
+uniform sampler2D blend_map;
+uniform bool blend;
+// Masked textures like grates, pixels with less than half alpha are discarded like alphaFunc GE128
+uniform bool alpha_test;
+uniform sampler2D lightmap;
+// Light direction per lightmap texel in world space, packed into 0..1. There are no normal maps
+// to light with it yet, so it is only shown by its render mode.
+uniform sampler2D deluxemap;
+uniform vec3 fog_color;
+uniform float fog_density;
+uniform vec3 fog_volume_color;
+uniform float fog_volume_density;
+// Multiplies the lightmap by the vertex color, like stages with rgbGen vertex do in the game.
+// The game doubles the lightmap for overbright bits first, which isn't done here.
+uniform bool modulate_vertex_colors;
+// Light entities near the camera, the intensity in w is the distance the light reaches
+layout(std140) uniform Lights {
+    vec4 light_positions[MAX_LIGHTS];
+    vec4 light_colors[MAX_LIGHTS];
+    int light_count;
+};
+// Water surfaces are warped by time like tcMod turb, tinted and blended with what's behind them
+uniform bool water;
+uniform float time;
+uniform vec3 water_tint;
+uniform float water_opacity;
+uniform float water_warp;
+uniform float water_warp_speed;
+
+const float TAU = 6.2831853;
+
+// Lambert shading with the linear falloff of q3map2 lights and a little ambient light
+vec3 entity_light(vec3 normal) {
+    vec3 total = vec3(0.1);
+    for (int i = 0; i < light_count; i++) {
+        vec3 to_light = light_positions[i].xyz - frag_position;
+        float distance = length(to_light);
+        float falloff = max(1.0 - distance / light_positions[i].w, 0.0);
+        total += light_colors[i].rgb * max(dot(normal, to_light / distance), 0.0) * falloff;
+    }
+    return total;
+}
+
 void main() {
     float a = (dot(frag_normal, normalize(vec3(-2.0, -1.0, -3.0))) + 1) / 2.0;
     //color = mix(vec4(0.1, 0.1, 0.1, 1.0), vec4(0.7, 0.7, 0.7, 1.0), a);
-    color = frag_color;
+    vec2 diffuse_uv = frag_diffuse_uv;
+    vec3 normal = normalize(frag_normal);
+    if (water) {
+        // The phase depends on the position so the surface doesn't move as one piece
+        float phase = (frag_position.x + frag_position.z) / 1024.0 + time * water_warp_speed;
+        vec2 warp = vec2(sin(phase * TAU), cos((phase + frag_position.y / 1024.0) * TAU));
+        diffuse_uv += warp * water_warp;
+        normal = normalize(normal + vec3(warp.x, 0.0, warp.y) * water_warp);
+    }
+    vec4 diffuse = texture(diffuse_map, diffuse_uv);
+    if (blend) {
+        diffuse = mix(diffuse, texture(blend_map, diffuse_uv), frag_color.a);
+    }
+    if (alpha_test && diffuse.a < 0.5) {
+        discard;
+    }
+    vec4 light = texture(lightmap, frag_lightmap_uv);
+    if (modulate_vertex_colors) {
+        light.rgb *= frag_color.rgb;
+    }
+
+    if (render_mode == MODE_TEXTURED) {
+        color = diffuse;
+    } else if (render_mode == MODE_TEXTURED_LIGHTMAP) {
+        color = vec4(diffuse.rgb * light.rgb, diffuse.a);
+    } else if (render_mode == MODE_VERTEX_COLOR) {
+        color = frag_color;
+    } else if (render_mode == MODE_LIGHTMAP) {
+        color = vec4(light.rgb, 1.0);
+    } else if (render_mode == MODE_NORMALS) {
+        color = vec4(normal * 0.5 + 0.5, 1.0);
+    } else if (render_mode == MODE_DELUXEMAP) {
+        color = vec4(texture(deluxemap, frag_lightmap_uv).rgb, 1.0);
+    } else if (render_mode == MODE_LIGHTMAP_UV) {
+        color = vec4(frag_lightmap_uv, 0.0, 1.0);
+    } else if (render_mode == MODE_ENTITY_LIGHTS) {
+        color = vec4(diffuse.rgb * entity_light(normal), diffuse.a);
+    } else if (render_mode == MODE_FACE_TYPE) {
+        // Shaded by the fixed light direction, curved surfaces would be flat blobs otherwise
+        int face_type = clamp(int(frag_face_type + 0.5) - 1, 0, 3);
+        color = vec4(FACE_TYPE_COLORS[face_type] * (0.4 + 0.6 * a), 1.0);
+    } else {
+        color = vec4(1.0);
+    }
+
+    float fog = exp(-fog_density * frag_distance);
+    color.rgb = mix(fog_color, color.rgb, fog);
+
+    // Surfaces inside a fog brush are tinted by their distance to the camera, which is only
+    // right when the camera is in the same fog volume
+    float volume_fog = exp(-fog_volume_density * frag_distance);
+    color.rgb = mix(fog_volume_color, color.rgb, mix(1.0, volume_fog, frag_fog_volume));
+
+    if (water) {
+        color = vec4(color.rgb * water_tint, water_opacity);
+    }
 }
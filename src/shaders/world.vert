@@ -1,18 +1,37 @@
 #version 330
 
 in vec3 position;
+in mat2 texcoord;
 in vec3 normal;
 in vec4 color;
+in float fog_volume;
+in float face_type;
 
+out vec2 frag_diffuse_uv;
+out vec2 frag_lightmap_uv;
 out vec3 frag_normal;
+out vec3 frag_position;
 out vec4 frag_color;
+out float frag_distance;
+out float frag_fog_volume;
+flat out float frag_face_type;
 
 uniform mat4 model;
 uniform mat4 view;
 uniform mat4 perspective;
+uniform bool flip_lightmap_v;
 
 void main() {
     gl_Position = perspective * view * model * vec4(position, 1.0);
+    frag_diffuse_uv = texcoord[0];
+    frag_lightmap_uv = texcoord[1];
+    if (flip_lightmap_v) {
+        frag_lightmap_uv.y = 1.0 - frag_lightmap_uv.y;
+    }
     frag_normal = mat3(transpose(inverse(model))) * normal;
+    frag_position = (model * vec4(position, 1.0)).xyz;
     frag_color = color;
+    frag_distance = length((view * model * vec4(position, 1.0)).xyz);
+    frag_fog_volume = fog_volume;
+    frag_face_type = face_type;
 }
@@ -0,0 +1,74 @@
+use std::f32::consts::PI;
+use cgmath::{Matrix4, Vector3};
+use glium;
+use glium::Surface;
+use glium::backend::Facade;
+use lines::LineVertex;
+use primitives;
+
+/// Speed at which the bob reaches its full amount, the walking speed of the game.
+const FULL_BOB_SPEED: f32 = 320.0;
+/// Bob cycles per unit moved.
+const BOB_FREQUENCY: f32 = 1.0 / 160.0;
+
+/// A placeholder weapon drawn in view space in the lower right of the screen. It bobs side to
+/// side and down while the camera moves.
+pub struct Viewmodel {
+    program: glium::Program,
+    vertexes: glium::VertexBuffer<LineVertex>,
+    phase: f32,
+    speed: f32,
+}
+
+impl Viewmodel {
+    pub fn new<F: Facade>(display: &F, program: glium::Program) -> Viewmodel {
+        let mut vertexes = Vec::new();
+        // Barrel and grip, in view space with -z forward
+        primitives::shaded_box_triangles(&mut vertexes,
+                                         [4.0, -6.0, -22.0],
+                                         [7.0, -3.0, -6.0],
+                                         [0.5, 0.5, 0.55]);
+        primitives::shaded_box_triangles(&mut vertexes,
+                                         [4.5, -10.0, -10.0],
+                                         [6.5, -6.0, -7.0],
+                                         [0.35, 0.25, 0.2]);
+
+        Viewmodel {
+            program: program,
+            vertexes: glium::VertexBuffer::new(display, &vertexes).unwrap(),
+            phase: 0.0,
+            speed: 0.0,
+        }
+    }
+
+    /// Advances the bob by the distance the camera moved this frame.
+    pub fn update(&mut self, distance: f32, dt: f32) {
+        self.phase = (self.phase + distance * BOB_FREQUENCY * 2.0 * PI) % (2.0 * PI);
+        if dt > 0.0 {
+            self.speed = distance / dt;
+        }
+    }
+
+    /// Draws over everything in `target`, whose depth has to be cleared first so the world
+    /// doesn't cut into the model. `bob` is the offset in units at full speed.
+    pub fn draw<S: Surface>(&self, target: &mut S, perspective: Matrix4<f32>, bob: f32) {
+        let amount = bob * (self.speed / FULL_BOB_SPEED).min(1.0);
+        let offset = Vector3::new(self.phase.sin() * amount, -self.phase.cos().abs() * amount, 0.0);
+        let mvp: [[f32; 4]; 4] = (perspective * Matrix4::from_translation(offset)).into();
+
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLess,
+                write: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        target.draw(&self.vertexes,
+                    &glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+                    &self.program,
+                    &uniform!{ mvp: mvp },
+                    &params)
+              .unwrap();
+    }
+}
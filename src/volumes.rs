@@ -0,0 +1,162 @@
+use glium;
+use glium::Surface;
+use glium::backend::Facade;
+use bsp_reader::BspMap;
+use collision::{CONTENTS_LAVA, CONTENTS_SLIME, CONTENTS_SOLID, CONTENTS_TRIGGER, CONTENTS_WATER};
+use geometry;
+use lines::LineVertex;
+
+/// Opacity of the volumes drawn over the world.
+const OPACITY: f32 = 0.3;
+
+/// What a brush is made of, from the contents flags of its texture.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VolumeKind {
+    Water,
+    Lava,
+    Slime,
+    Solid,
+    Trigger,
+}
+
+pub const VOLUME_KINDS: [VolumeKind; 5] = [VolumeKind::Water,
+                                           VolumeKind::Lava,
+                                           VolumeKind::Slime,
+                                           VolumeKind::Solid,
+                                           VolumeKind::Trigger];
+
+impl VolumeKind {
+    /// Picks the kind for `contents`, liquids before solid and solid before trigger if several
+    /// flags are set. Returns `None` for brushes that are none of these, like clip or fog.
+    pub fn classify(contents: i32) -> Option<VolumeKind> {
+        if contents & CONTENTS_LAVA != 0 {
+            Some(VolumeKind::Lava)
+        } else if contents & CONTENTS_SLIME != 0 {
+            Some(VolumeKind::Slime)
+        } else if contents & CONTENTS_WATER != 0 {
+            Some(VolumeKind::Water)
+        } else if contents & CONTENTS_SOLID != 0 {
+            Some(VolumeKind::Solid)
+        } else if contents & CONTENTS_TRIGGER != 0 {
+            Some(VolumeKind::Trigger)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VolumeKind::Water => "water",
+            VolumeKind::Lava => "lava",
+            VolumeKind::Slime => "slime",
+            VolumeKind::Solid => "solid",
+            VolumeKind::Trigger => "trigger",
+        }
+    }
+
+    pub fn color(self) -> [f32; 3] {
+        match self {
+            VolumeKind::Water => [0.1, 0.3, 1.0],
+            VolumeKind::Lava => [1.0, 0.3, 0.0],
+            VolumeKind::Slime => [0.3, 1.0, 0.1],
+            VolumeKind::Solid => [0.6, 0.6, 0.6],
+            VolumeKind::Trigger => [1.0, 0.1, 1.0],
+        }
+    }
+}
+
+/// The brushes of all models rebuilt into triangles and colored by their kind, so the liquid and
+/// trigger volumes, which have no faces of their own, can be seen through the world.
+pub struct Volumes {
+    program: glium::Program,
+    /// Everything but solid brushes, followed by the solid ones.
+    vertexes: glium::VertexBuffer<LineVertex>,
+    /// Number of vertexes before the solid brushes start.
+    non_solid: usize,
+}
+
+impl Volumes {
+    /// Builds the volumes with `program`, which takes `LineVertex` triangles like the line
+    /// program, and prints how many brushes there are of each kind.
+    pub fn new<F: Facade>(display: &F, map: &BspMap, program: glium::Program) -> Volumes {
+        let mut counts = [0; 5];
+        let mut non_solid = Vec::new();
+        let mut solid = Vec::new();
+        for brush in &map.brushes {
+            let contents = map.textures.get(brush.texture as usize).map_or(0, |t| t.contents);
+            let kind = match VolumeKind::classify(contents) {
+                Some(kind) => kind,
+                None => continue,
+            };
+            counts[VOLUME_KINDS.iter().position(|&k| k == kind).unwrap()] += 1;
+
+            let vertexes = if kind == VolumeKind::Solid {
+                &mut solid
+            } else {
+                &mut non_solid
+            };
+            for polygon in geometry::brush_polygons(map, brush) {
+                for i in 1..polygon.len() - 1 {
+                    for &position in &[polygon[0], polygon[i], polygon[i + 1]] {
+                        vertexes.push(LineVertex {
+                            position: position,
+                            color: kind.color(),
+                        });
+                    }
+                }
+            }
+        }
+
+        let summary: Vec<String> = VOLUME_KINDS.iter()
+                                               .zip(counts.iter())
+                                               .map(|(kind, count)| {
+                                                   format!("{} {}", count, kind.name())
+                                               })
+                                               .collect();
+        println!("Brush volumes: {}", summary.join(", "));
+
+        let non_solid_count = non_solid.len();
+        non_solid.extend_from_slice(&solid);
+        Volumes {
+            program: program,
+            vertexes: glium::VertexBuffer::new(display, &non_solid).unwrap(),
+            non_solid: non_solid_count,
+        }
+    }
+
+    /// Draws the volumes translucent over what's in `target`, with the solid brushes only if
+    /// `include_solid` is set since they cover most of the world.
+    pub fn draw<S: Surface>(&self, target: &mut S, mvp: [[f32; 4]; 4], include_solid: bool) {
+        let count = if include_solid {
+            self.vertexes.len()
+        } else {
+            self.non_solid
+        };
+        if count == 0 {
+            return;
+        }
+
+        let params = glium::DrawParameters {
+            depth: glium::Depth {
+                test: glium::draw_parameters::DepthTest::IfLessOrEqual,
+                write: false,
+                ..Default::default()
+            },
+            blend: glium::Blend {
+                color: glium::BlendingFunction::Addition {
+                    source: glium::LinearBlendingFactor::ConstantAlpha,
+                    destination: glium::LinearBlendingFactor::OneMinusConstantAlpha,
+                },
+                alpha: glium::BlendingFunction::AlwaysReplace,
+                constant_value: (0.0, 0.0, 0.0, OPACITY),
+            },
+            ..Default::default()
+        };
+        target.draw(self.vertexes.slice(0..count).unwrap(),
+                    &glium::index::NoIndices(glium::index::PrimitiveType::TrianglesList),
+                    &self.program,
+                    &uniform!{ mvp: mvp },
+                    &params)
+              .unwrap();
+    }
+}